@@ -4,6 +4,7 @@ use anyhow::Context;
 use smol::channel::{Receiver, Sender};
 use smol::prelude::*;
 use smol_timeout::TimeoutExt;
+use sosistab::mux::StreamHeader;
 use std::time::Duration;
 use std::{sync::Arc, time::Instant};
 /// An "actor" that keeps a client session alive.
@@ -22,6 +23,7 @@ impl Keepalive {
         use_bridges: bool,
         stdio_vpn: bool,
         ccache: Arc<ClientCache>,
+        sosistab_cfg: sosistab::ClientConfig,
     ) -> Self {
         let (send, recv) = smol::channel::unbounded();
         let (send_stats, recv_stats) = smol::channel::unbounded();
@@ -34,6 +36,7 @@ impl Keepalive {
                 use_bridges,
                 stdio_vpn,
                 ccache,
+                sosistab_cfg,
                 recv,
                 recv_stats,
             ))),
@@ -63,6 +66,7 @@ async fn keepalive_actor(
     use_bridges: bool,
     stdio_vpn: bool,
     ccache: Arc<ClientCache>,
+    sosistab_cfg: sosistab::ClientConfig,
     recv_socks5_conn: Receiver<(String, Sender<sosistab::mux::RelConn>)>,
     recv_get_stats: Receiver<Sender<sosistab::SessionStats>>,
 ) -> anyhow::Result<()> {
@@ -73,6 +77,7 @@ async fn keepalive_actor(
             use_bridges,
             stdio_vpn,
             ccache.clone(),
+            sosistab_cfg.clone(),
             recv_socks5_conn.clone(),
             recv_get_stats.clone(),
         )
@@ -90,6 +95,7 @@ async fn keepalive_actor_once(
     use_bridges: bool,
     stdio_vpn: bool,
     ccache: Arc<ClientCache>,
+    sosistab_cfg: sosistab::ClientConfig,
     recv_socks5_conn: Receiver<(String, Sender<sosistab::mux::RelConn>)>,
     recv_get_stats: Receiver<Sender<sosistab::SessionStats>>,
 ) -> anyhow::Result<()> {
@@ -121,12 +127,13 @@ async fn keepalive_actor_once(
             .into_iter()
             .map(|desc| {
                 let send = send.clone();
+                let sosistab_cfg = sosistab_cfg.clone();
                 GEXEC.spawn(async move {
                     log::debug!("connecting through {}...", desc.endpoint);
                     drop(
                         send.send((
                             desc.endpoint,
-                            sosistab::connect(desc.endpoint, desc.sosistab_key).await,
+                            sosistab::connect(desc.endpoint, desc.sosistab_key, sosistab_cfg).await,
                         ))
                         .await,
                     )
@@ -148,12 +155,15 @@ async fn keepalive_actor_once(
             bridge_sess_async.await
         } else {
             async {
+                // every A and AAAA record gets a try
+                let exit_addrs = smol::net::resolve(format!("{}:19831", exit_info.hostname))
+                    .await
+                    .context("can't resolve hostname of exit")?;
                 Ok(infal(
-                    sosistab::connect(
-                        smol::net::resolve(format!("{}:19831", exit_info.hostname))
-                            .await
-                            .context("can't resolve hostname of exit")?[0],
+                    sosistab::connect_happy_eyeballs(
+                        exit_addrs,
                         exit_info.sosistab_key,
+                        sosistab_cfg.clone(),
                     )
                     .await,
                 )
@@ -232,7 +242,9 @@ async fn keepalive_actor_once(
                     scope
                         .spawn(async move {
                             let start = Instant::now();
-                            let remote = (&mux).open_conn(Some(conn_host)).await;
+                            let remote = (&mux)
+                                .open_conn(Some(StreamHeader::TcpConnect { host: conn_host }))
+                                .await;
                             match remote {
                                 Ok(remote) => {
                                     let sess_stats = mux.get_session().get_stats().await.ok_or_else(|| anyhow::anyhow!("session is dead (stats)"))?;
@@ -289,7 +301,7 @@ async fn authenticate_session(
     session: &sosistab::mux::Multiplex,
     token: &crate::cache::Token,
 ) -> anyhow::Result<()> {
-    let mut auth_conn = session.open_conn(None).await?;
+    let mut auth_conn = session.open_conn(Some(StreamHeader::Auth)).await?;
     log::debug!("sending auth info...");
     aioutils::write_pascalish(
         &mut auth_conn,
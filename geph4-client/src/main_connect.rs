@@ -40,6 +40,14 @@ pub struct ConnectOpt {
     #[structopt(long)]
     /// whether or not to wait for VPN commands on stdio
     stdio_vpn: bool,
+
+    #[structopt(long, default_value = "4")]
+    /// how many shards, each with its own UDP socket, to spread the session over
+    shards: u8,
+
+    #[structopt(long, default_value = "5")]
+    /// how often, in seconds, each shard hops to a fresh UDP socket. Hopping less often saves battery and NAT mappings on mobile devices.
+    hop_interval: u64,
 }
 
 pub async fn main_connect(opt: ConnectOpt) -> anyhow::Result<()> {
@@ -55,6 +63,9 @@ pub async fn main_connect(opt: ConnectOpt) -> anyhow::Result<()> {
         opt.use_bridges,
         opt.stdio_vpn,
         Arc::new(client_cache),
+        sosistab::ClientConfig::default()
+            .shards(opt.shards)
+            .reset_interval(Duration::from_secs(opt.hop_interval)),
     );
     // enter the socks5 loop
     let socks5_listener = smol::net::TcpListener::bind(opt.socks5_listen)
@@ -15,6 +15,7 @@ use rand::prelude::*;
 use smol::prelude::*;
 use smol_timeout::TimeoutExt;
 use smolscale::OnError;
+use sosistab::mux::StreamHeader;
 
 use crate::{vpn::handle_vpn_session, ALLOCATOR};
 /// the root context
@@ -109,17 +110,50 @@ pub async fn main_loop<'a>(
         let sosis_listener =
             sosistab::Listener::listen("[::0]:19831", ctx1.sosistab_sk.clone()).await;
         log::debug!("sosis_listener initialized");
-        loop {
-            let sess = sosis_listener
-                .accept_session()
-                .await
-                .ok_or_else(|| anyhow::anyhow!("can't accept from sosistab"))?;
-            let ctx1 = ctx1.clone();
-            let sp = ctx1.nursery.clone();
-            sp.spawn(OnError::Ignore, move |_| {
-                handle_session(ctx1.new_sess(sess))
-            });
-        }
+        let accept_loop = async {
+            loop {
+                let sess = sosis_listener
+                    .accept_session()
+                    .await
+                    .ok_or_else(|| anyhow::anyhow!("can't accept from sosistab"))?;
+                let ctx1 = ctx1.clone();
+                let sp = ctx1.nursery.clone();
+                sp.spawn(OnError::Ignore, move |_| {
+                    handle_session(ctx1.new_sess(sess))
+                });
+            }
+        };
+        accept_loop
+            .or(sync_token_keys(&sosis_listener, &ctx1.sosistab_sk))
+            .await
+    };
+    // future that governs the "self bridge" over TCP, for clients whose networks drop UDP
+    let ctx2 = ctx.clone();
+    let self_bridge_tcp_fut = async {
+        let backhaul = sosistab::TcpServerBackhaul::bind(
+            "[::0]:19831",
+            x25519_dalek::PublicKey::from(&ctx2.sosistab_sk),
+        )
+        .await?;
+        let sosis_listener =
+            sosistab::Listener::listen_with_backhaul(backhaul, ctx2.sosistab_sk.clone());
+        log::debug!("TCP sosis_listener initialized");
+        let accept_loop = async {
+            loop {
+                let sess = sosis_listener
+                    .accept_session()
+                    .await
+                    .ok_or_else(|| anyhow::anyhow!("can't accept from TCP sosistab"))?;
+                let ctx2 = ctx2.clone();
+                let sp = ctx2.nursery.clone();
+                sp.spawn(OnError::Ignore, move |_| {
+                    handle_session(ctx2.new_sess(sess))
+                });
+            }
+        };
+        accept_loop
+            .or(sync_token_keys(&sosis_listener, &ctx2.sosistab_sk))
+            .await
     };
     // future that uploads gauge statistics
     let stat_client = ctx.stat_client.clone();
@@ -139,11 +173,34 @@ pub async fn main_loop<'a>(
     };
     // race
     smol::future::race(control_prot_fut, self_bridge_fut)
+        .or(self_bridge_tcp_fut)
         .or(gauge_fut)
         .or(nursery.wait())
         .await
 }
 
+/// Keeps a self-bridge listener's resume-token keys derived from the long-term key and rotated daily, so sessions survive exit restarts and can move between the UDP and TCP listeners. The tokens get their own master secret, hashed from the key under a context of its own, so that the key itself only ever does key exchange.
+async fn sync_token_keys(
+    listener: &sosistab::Listener,
+    sosistab_sk: &x25519_dalek::StaticSecret,
+) -> anyhow::Result<()> {
+    let mut token_master = [0u8; 32];
+    blake3::derive_key(
+        "geph4-exit resume token master",
+        &sosistab_sk.to_bytes(),
+        &mut token_master,
+    );
+    loop {
+        let day = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / 86400;
+        listener.set_token_keys(sosistab::TokenKeys::derive(&token_master, day));
+        smol::Timer::after(Duration::from_secs(600)).await;
+    }
+}
+
 async fn handle_control<'a>(
     ctx: Arc<RootCtx>,
     mut client: smol::net::TcpStream,
@@ -238,16 +295,25 @@ async fn handle_session(ctx: SessCtx) -> anyhow::Result<()> {
         nursery,
     } = ctx;
     let sess = sosistab::mux::Multiplex::new(sess);
-    let nhandle = nursery.clone();
     let is_plus = authenticate_sess(root.binder_client.clone(), &sess)
         .timeout(Duration::from_secs(300))
         .await
         .ok_or_else(|| anyhow::anyhow!("authentication timeout"))??;
     log::info!("authenticated a new session (is_plus = {})", is_plus);
     if !is_plus {
-        sess.get_session().set_ratelimit(root.free_limit);
+        let limit = root.free_limit.saturating_mul(1024);
+        sess.get_session().set_up_ratelimit(limit, limit);
+        sess.get_session().set_down_ratelimit(limit, limit);
     }
+    serve_session(root, &sess, nursery).await
+}
 
+/// Serves an authenticated session until it ends, relaying its streams with tasks in the session's nursery.
+async fn serve_session(
+    root: Arc<RootCtx>,
+    sess: &sosistab::mux::Multiplex,
+    nhandle: smolscale::NurseryHandle,
+) -> anyhow::Result<()> {
     let (send_sess_alive, recv_sess_alive) = smol::channel::bounded(1);
     let sess_alive_loop = async {
         let alive = AtomicBool::new(false);
@@ -278,6 +344,16 @@ async fn handle_session(ctx: SessCtx) -> anyhow::Result<()> {
     let proxy_loop = async {
         loop {
             let stream = sess.accept_conn().await?;
+            let host = match stream.header() {
+                Some(StreamHeader::TcpConnect { host }) => Some(host.clone()),
+                // clients that predate stream headers send the host down the stream
+                None => None,
+                // exits only relay TCP. Dropping the stream resets it, so the client finds out right away
+                Some(header) => {
+                    log::debug!("refusing unsupported {:?} stream", header);
+                    continue;
+                }
+            };
             let root = root.clone();
             let send_sess_alive = send_sess_alive.clone();
             nhandle.spawn(OnError::Ignore, move |_| async move {
@@ -293,6 +369,7 @@ async fn handle_session(ctx: SessCtx) -> anyhow::Result<()> {
                     root.exit_hostname.clone(),
                     root.port_whitelist,
                     stream,
+                    host,
                     root.google_proxy,
                 )
                 .await
@@ -300,7 +377,7 @@ async fn handle_session(ctx: SessCtx) -> anyhow::Result<()> {
         }
     };
     let vpn_loop = handle_vpn_session(
-        &sess,
+        sess,
         root.exit_hostname.clone(),
         root.stat_client.clone(),
         root.port_whitelist,
@@ -313,6 +390,12 @@ async fn authenticate_sess(
     sess: &sosistab::mux::Multiplex,
 ) -> anyhow::Result<bool> {
     let mut stream = sess.accept_conn().await?;
+    // clients that predate stream headers send none
+    if let Some(header) = stream.header() {
+        if header != &StreamHeader::Auth {
+            anyhow::bail!("expected an authentication stream, got {:?}", header)
+        }
+    }
     log::debug!("authenticating session...");
     // wait for a message containing a blinded signature
     let (auth_tok, auth_sig, level): (Vec<u8>, mizaru::UnblindedSignature, String) =
@@ -346,11 +429,12 @@ async fn handle_proxy_stream(
     exit_hostname: String,
     port_whitelist: bool,
     mut client: sosistab::mux::RelConn,
+    host: Option<String>,
     google_proxy: Option<SocketAddr>,
 ) -> anyhow::Result<()> {
-    // read proxy request
-    let to_prox: String = match client.additional_info() {
-        Some(s) => s.to_string(),
+    // read proxy request, unless the stream header carried it
+    let to_prox: String = match host {
+        Some(host) => host,
         None => aioutils::read_pascalish(&mut client).await?,
     };
     let addr = smol::net::resolve(&to_prox)
@@ -410,3 +494,139 @@ async fn handle_proxy_stream(
     .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sosistab::{Backhaul, SimConfig, SimNetwork};
+
+    /// Stands in for the binder in tests whose sessions skip authentication.
+    struct NoBinder;
+
+    impl BinderClient for NoBinder {
+        fn request(
+            &self,
+            _: BinderRequestData,
+            _: Duration,
+        ) -> binder_transport::BinderResult<BinderResponse> {
+            Err(binder_transport::BinderError::Other("no binder in tests".into()))
+        }
+    }
+
+    fn test_root() -> Arc<RootCtx> {
+        Arc::new(RootCtx {
+            stat_client: Arc::new(statsd::Client::new("127.0.0.1:8125", "test").unwrap()),
+            exit_hostname: "test".to_string(),
+            binder_client: Arc::new(NoBinder),
+            bridge_secret: String::new(),
+            signing_sk: ed25519_dalek::Keypair::generate(&mut rand::thread_rng()),
+            sosistab_sk: x25519_dalek::StaticSecret::new(&mut rand::thread_rng()),
+            session_count: AtomicUsize::new(0),
+            conn_count: AtomicUsize::new(0),
+            free_limit: 0,
+            port_whitelist: false,
+            google_proxy: None,
+            nursery: smolscale::Nursery::new().handle(),
+        })
+    }
+
+    /// Connects a client to a simulated exit by opening a stream with the given header, since the exit only learns about a session once data flows. Returns the listener, both ends of the session, and the stream.
+    async fn sim_session(
+        root: &RootCtx,
+        header: Option<StreamHeader>,
+    ) -> (
+        sosistab::Listener,
+        sosistab::mux::Multiplex,
+        sosistab::mux::Multiplex,
+        sosistab::mux::RelConn,
+    ) {
+        let net = SimNetwork::new(SimConfig::default());
+        let server_addr: SocketAddr = "10.0.0.1:19831".parse().unwrap();
+        let listener = sosistab::Listener::listen_with_backhaul(
+            net.bind(server_addr).unwrap(),
+            root.sosistab_sk.clone(),
+        );
+        let client = sosistab::connect_with_backhaul_factory(
+            server_addr,
+            (&root.sosistab_sk).into(),
+            move || {
+                let backhaul = net.bind("10.0.0.2:0".parse().unwrap());
+                async move {
+                    let backhaul: Arc<dyn Backhaul> = Arc::new(backhaul?);
+                    Ok(backhaul)
+                }
+            },
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        let client = sosistab::mux::Multiplex::new(client);
+        // the stream only opens once the exit's end of the session is there to answer it
+        let (conn, sess) = smol::future::zip(client.open_conn(header), async {
+            sosistab::mux::Multiplex::new(listener.accept_session().await.unwrap())
+        })
+        .await;
+        (listener, client, sess, conn.unwrap())
+    }
+
+    #[test]
+    fn session_cleans_up_after_client_closes() {
+        smol::block_on(async {
+            let root = test_root();
+            // a stream that never says where to go, so its task waits on the client for as long as the session lasts
+            let (_listener, client, sess, conn) = sim_session(&root, None).await;
+            let nursery = smolscale::Nursery::new();
+            let serve = serve_session(root.clone(), &sess, nursery.handle());
+            let leave = async {
+                while root.conn_count.load(Ordering::Relaxed) == 0
+                    || root.session_count.load(Ordering::Relaxed) == 0
+                {
+                    smol::Timer::after(Duration::from_millis(10)).await;
+                }
+                drop(conn);
+                drop(client);
+                smol::future::pending::<anyhow::Result<()>>().await
+            };
+            // the client says goodbye, so nothing waits for the 300-second timeout
+            assert!(serve
+                .or(leave)
+                .timeout(Duration::from_secs(10))
+                .await
+                .expect("session outlived its client")
+                .is_err());
+            drop(sess);
+            nursery
+                .wait()
+                .timeout(Duration::from_secs(10))
+                .await
+                .expect("stream outlived its session")
+                .unwrap();
+            assert_eq!(root.conn_count.load(Ordering::Relaxed), 0);
+            assert_eq!(root.session_count.load(Ordering::Relaxed), 0);
+        })
+    }
+
+    #[test]
+    fn unsupported_streams_are_reset() {
+        smol::block_on(async {
+            let root = test_root();
+            let (_listener, _client, sess, mut conn) =
+                sim_session(&root, Some(StreamHeader::Dns)).await;
+            let nursery = smolscale::Nursery::new();
+            let serve = serve_session(root.clone(), &sess, nursery.handle());
+            let read = async {
+                let mut buf = [0u8; 1];
+                let err = conn.read(&mut buf).await.unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+                Ok(())
+            };
+            serve
+                .or(read)
+                .timeout(Duration::from_secs(10))
+                .await
+                .expect("unsupported stream left hanging")
+                .unwrap();
+            assert_eq!(root.conn_count.load(Ordering::Relaxed), 0);
+        })
+    }
+}
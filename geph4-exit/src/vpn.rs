@@ -176,6 +176,7 @@ static INCOMING_MAP: Lazy<RwLock<LruCache<Ipv4Addr, Sender<Bytes>>>> =
     Lazy::new(|| RwLock::new(LruCache::new(1000)));
 
 /// Incoming packet handler
+#[cfg(not(test))]
 static INCOMING_PKT_HANDLER: Lazy<smol::Task<()>> = Lazy::new(|| {
     smolscale::spawn(async {
         loop {
@@ -192,6 +193,11 @@ static INCOMING_PKT_HANDLER: Lazy<smol::Task<()>> = Lazy::new(|| {
     })
 });
 
+/// Stands in for the incoming packet handler in tests, which have no TUN device to read from.
+#[cfg(test)]
+static INCOMING_PKT_HANDLER: Lazy<smol::Task<()>> =
+    Lazy::new(|| smolscale::spawn(smol::future::pending()));
+
 /// The raw TUN device.
 static RAW_TUN: Lazy<TunDevice> = Lazy::new(|| {
     log::info!("initializing tun-geph");
@@ -314,4 +320,19 @@ mod tests {
         }
         dbg!(assigned);
     }
+
+    #[test]
+    fn cgnat_releases_addresses() {
+        let assigner = IpAddrAssigner::new("100.64.0.0/10".parse().unwrap());
+        let assigned = assigner.assign();
+        let addr = assigned.addr();
+        let copy = assigned.clone();
+        drop(assigned);
+        // clones share one lease
+        assert!(assigner.table.lock().contains(&addr));
+        drop(copy);
+        assert!(assigner.table.lock().is_empty());
+        // so the address is free for the next session
+        assert_eq!(assigner.assign().addr(), addr);
+    }
 }
@@ -28,6 +28,7 @@ fn main() {
             })
             .await,
             x25519_dalek::PublicKey::from(pubkey_bts),
+            Default::default(),
         )
         .await
         .unwrap();
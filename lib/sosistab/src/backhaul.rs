@@ -1,11 +1,18 @@
 use std::{
     io,
-    marker::PhantomData,
-    net::{SocketAddr, UdpSocket},
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
 };
 
 use bytes::{Bytes, BytesMut};
 use smol::Async;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod mmsg;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) use mmsg::{enable_gro, set_gso};
+mod sim;
+pub use sim::*;
+mod tcp;
+pub use tcp::*;
 
 /// A trait that represents a datagram backhaul. This presents an interface similar to that of "PacketConn" in Go, and it is used to abstract over different kinds of datagram transports.
 #[async_trait::async_trait]
@@ -25,11 +32,20 @@ pub trait Backhaul: Send + Sync {
     async fn recv_from_many(&self) -> io::Result<Vec<(Bytes, SocketAddr)>> {
         Ok(vec![self.recv_from().await?])
     }
+    /// Returns the local address, if the transport has a meaningful one.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+    /// Whether the backhaul never loses datagrams, like one tunnelled over TCP. Sessions over reliable backhauls don't bother with FEC.
+    fn is_reliable(&self) -> bool {
+        false
+    }
 }
 
 #[async_trait::async_trait]
 impl Backhaul for Async<UdpSocket> {
     async fn send_to(&self, to_send: Bytes, dest: SocketAddr) -> io::Result<()> {
+        let dest = dest_for(self.get_ref().local_addr()?, dest);
         self.send_to(&to_send, dest).await?;
         Ok(())
     }
@@ -40,108 +56,50 @@ impl Backhaul for Async<UdpSocket> {
             buf.set_len(2048);
         }
         let (n, origin) = self.recv_from(&mut buf).await?;
-        Ok((buf.freeze().slice(0..n), origin))
+        Ok((buf.freeze().slice(0..n), canonical_addr(origin)))
     }
 
-    #[cfg(target_family = "unix")]
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().local_addr().ok()
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
     async fn send_to_many(&self, to_send: &[(Bytes, SocketAddr)]) -> io::Result<()> {
-        use nix::sys::socket::SendMmsgData;
-        use nix::sys::socket::{ControlMessage, InetAddr, SockAddr};
-        use nix::sys::uio::IoVec;
         use std::os::unix::prelude::*;
-        // non-blocking
+        let local_addr = self.get_ref().local_addr()?;
+        let mut progress = 0;
         self.write_with(|sock| {
-            let fd: RawFd = sock.as_raw_fd();
-            let iov: Vec<[IoVec<&[u8]>; 1]> = to_send
-                .iter()
-                .map(|(bts, _)| [IoVec::from_slice(bts)])
-                .collect();
-            let control_msgs: Vec<ControlMessage<'static>> = vec![];
-            let smd: Vec<_> = iov
-                .iter()
-                .zip(to_send.iter())
-                .map(|(iov, (_, addr))| {
-                    let iov: &[IoVec<&[u8]>] = iov;
-                    let cmsgs: &[ControlMessage<'static>] = &control_msgs;
-                    SendMmsgData {
-                        iov,
-                        cmsgs,
-                        addr: Some(SockAddr::new_inet(InetAddr::from_std(addr))),
-                        _lt: PhantomData::default(),
-                    }
-                })
-                .collect();
-            nix::sys::socket::sendmmsg(fd, smd.iter(), nix::sys::socket::MsgFlags::empty())
-                .map_err(to_ioerror)?;
-            Ok(())
+            mmsg::send_many(sock.as_raw_fd(), local_addr, to_send, &mut progress)
         })
         .await
     }
 
-    #[cfg(target_family = "unix")]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
     async fn recv_from_many(&self) -> io::Result<Vec<(Bytes, SocketAddr)>> {
-        use nix::sys::socket::RecvMmsgData;
-        use nix::sys::uio::IoVec;
         use std::os::unix::prelude::*;
-        const MAX_LEN: usize = 16;
-        self.read_with(|sock| {
-            // get fd
-            let fd: RawFd = sock.as_raw_fd();
-            // create a byte buffer
-            let mut byte_buffer = vec![0u8; 2048 * MAX_LEN];
-            // split into slices
-            let response: Vec<(usize, Option<nix::sys::socket::SockAddr>)> = {
-                let byte_slices: Vec<&mut [u8]> = byte_buffer.chunks_exact_mut(2048).collect();
-                let mut iovs: Vec<[IoVec<&mut [u8]>; 1]> = byte_slices
-                    .into_iter()
-                    .map(|v| [IoVec::from_mut_slice(v)])
-                    .collect();
-                let mut rmds: Vec<RecvMmsgData<'_, &mut [IoVec<&mut [u8]>]>> = iovs
-                    .iter_mut()
-                    .map(|iov| {
-                        let iov: &mut [IoVec<&mut [u8]>] = iov;
-                        RecvMmsgData {
-                            iov,
-                            cmsg_buffer: None,
-                        }
-                    })
-                    .collect();
-                // now do the read
-                let response = nix::sys::socket::recvmmsg(
-                    fd,
-                    &mut rmds,
-                    nix::sys::socket::MsgFlags::empty(),
-                    None,
-                )
-                .map_err(to_ioerror)?;
-                response.into_iter().map(|v| (v.bytes, v.address)).collect()
-            };
-            assert!(response.len() <= MAX_LEN);
-            let bts = Bytes::from(byte_buffer);
-            Ok(response
-                .into_iter()
-                .enumerate()
-                .filter_map(|(i, rm)| {
-                    let bts = bts.slice(2048 * i..2048 * i + rm.0);
-                    let sockaddr = rm.1?;
-                    if let nix::sys::socket::SockAddr::Inet(inetaddr) = sockaddr {
-                        Some((bts, inetaddr.to_std()))
-                    } else {
-                        None
-                    }
-                })
-                .collect())
-        })
-        .await
+        self.read_with(|sock| mmsg::recv_many(sock.as_raw_fd()))
+            .await
+    }
+}
+
+/// Turns IPv4-mapped IPv6 addresses, which is how dual-stack sockets see IPv4 peers, back into IPv4 addresses, so that a peer has the same address whichever kind of socket sees it.
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(v6) = addr {
+        let octets = v6.ip().octets();
+        if octets[..12] == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff] {
+            let ip = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+            return SocketAddr::new(ip.into(), v6.port());
+        }
     }
+    addr
 }
 
-#[cfg(target_family = "unix")]
-fn to_ioerror(err: nix::Error) -> std::io::Error {
-    if let Some(errno) = err.as_errno() {
-        if errno == nix::errno::EWOULDBLOCK || errno == nix::errno::Errno::EAGAIN {
-            return std::io::Error::new(std::io::ErrorKind::WouldBlock, err);
+/// Puts a destination in the address family of the socket sending to it. IPv6 sockets reach IPv4 peers through IPv4-mapped addresses, which not every platform fills in by itself.
+fn dest_for(local_addr: SocketAddr, dest: SocketAddr) -> SocketAddr {
+    match (local_addr, canonical_addr(dest)) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
         }
+        (_, dest) => dest,
     }
-    std::io::Error::new(std::io::ErrorKind::ConnectionAborted, err)
 }
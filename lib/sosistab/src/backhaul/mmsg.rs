@@ -0,0 +1,370 @@
+//! Batched UDP I/O for Linux. Datagrams move through `sendmmsg` and `recvmmsg` with headers and buffers that are reused from call to call, and with UDP GSO and GRO, runs of equally long datagrams cross the kernel as single buffers.
+
+use super::{canonical_addr, dest_for};
+use bytes::{Bytes, BytesMut};
+use nix::libc;
+use once_cell::sync::OnceCell;
+use socket2::SockAddr;
+use std::{
+    cell::RefCell,
+    io, mem,
+    net::SocketAddr,
+    os::unix::io::RawFd,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// not every libc version defines these
+const SOL_UDP: libc::c_int = 17;
+const UDP_SEGMENT: libc::c_int = 103;
+const UDP_GRO: libc::c_int = 104;
+
+/// Most messages moved by one syscall. With GSO or GRO, a message can be many datagrams.
+const BATCH: usize = 16;
+/// Room for each received message, which is as big as GRO can make one.
+const RECV_SLOT: usize = 65536;
+/// Most datagrams, and bytes, that the kernel segments out of one message.
+const GSO_MAX_SEGMENTS: usize = 64;
+const GSO_MAX_BYTES: usize = 65000;
+
+/// Whether batches may use GSO, which `runtime::set_udp_gso` can turn off.
+static GSO_ENABLED: AtomicBool = AtomicBool::new(true);
+/// Whether the kernel knows GSO at all, which those before 4.18 don't. Found out from the first socket that sends a batch.
+static GSO_SUPPORTED: OnceCell<bool> = OnceCell::new();
+
+/// Room for one control message carrying an int or smaller, aligned like a `cmsghdr`.
+type CmsgBuf = [u64; 4];
+
+/// Everything a batch needs besides the datagrams themselves, kept around so that batches don't allocate.
+struct Scratch {
+    hdrs: Vec<libc::mmsghdr>,
+    iovecs: Vec<libc::iovec>,
+    addrs: Vec<SockAddr>,
+    names: Vec<libc::sockaddr_storage>,
+    cmsgs: Vec<CmsgBuf>,
+    /// Each message sent, as a range of datagrams.
+    groups: Vec<(usize, usize)>,
+    /// Where received datagrams land. Datagrams handed out share it, and it's reused once they're all dropped.
+    recv_buf: BytesMut,
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::new(Scratch {
+        hdrs: Vec::new(),
+        iovecs: Vec::new(),
+        addrs: Vec::new(),
+        names: Vec::new(),
+        cmsgs: Vec::new(),
+        groups: Vec::new(),
+        recv_buf: BytesMut::new(),
+    });
+}
+
+/// Asks the kernel to coalesce incoming datagrams. Sockets with GRO on must be read with `recv_many`, since other reads can't tell where coalesced datagrams end.
+pub(crate) fn enable_gro(fd: RawFd) {
+    let on: libc::c_int = 1;
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_UDP,
+            UDP_GRO,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        tracing::debug!("UDP GRO unavailable: {}", io::Error::last_os_error());
+    }
+}
+
+pub(crate) fn set_gso(enabled: bool) {
+    GSO_ENABLED.store(enabled, Ordering::Relaxed)
+}
+
+/// Whether sends from the socket may use GSO.
+fn gso_usable(fd: RawFd) -> bool {
+    let supported = *GSO_SUPPORTED.get_or_init(|| {
+        let mut segment_size: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                SOL_UDP,
+                UDP_SEGMENT,
+                &mut segment_size as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res != 0 {
+            tracing::debug!("UDP GSO unavailable: {}", io::Error::last_os_error());
+        }
+        res == 0
+    });
+    supported && GSO_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Sends datagrams from a socket bound to `local_addr`, starting at the `progress`th. Progress is kept across calls, so that a retry after `WouldBlock` picks up where the last call stopped.
+pub(crate) fn send_many(
+    fd: RawFd,
+    local_addr: SocketAddr,
+    to_send: &[(Bytes, SocketAddr)],
+    progress: &mut usize,
+) -> io::Result<()> {
+    SCRATCH.with(|scratch| {
+        let scratch = &mut *scratch.borrow_mut();
+        let mut gso = gso_usable(fd);
+        while *progress < to_send.len() {
+            // equally long datagrams to the same place become one message, except that the last may be shorter
+            scratch.groups.clear();
+            let mut start = *progress;
+            while start < to_send.len() && scratch.groups.len() < BATCH {
+                let (first, dest) = &to_send[start];
+                let mut end = start + 1;
+                let mut total = first.len();
+                while gso && end < to_send.len() && end - start < GSO_MAX_SEGMENTS {
+                    let (next, next_dest) = &to_send[end];
+                    if next_dest != dest
+                        || next.len() > first.len()
+                        || total + next.len() > GSO_MAX_BYTES
+                    {
+                        break;
+                    }
+                    total += next.len();
+                    end += 1;
+                    if next.len() < first.len() {
+                        break;
+                    }
+                }
+                scratch.groups.push((start, end));
+                start = end;
+            }
+            // fill in everything the headers point to before taking any pointers
+            scratch.iovecs.clear();
+            scratch.addrs.clear();
+            scratch.cmsgs.clear();
+            for &(start, end) in scratch.groups.iter() {
+                for (bts, _) in &to_send[start..end] {
+                    scratch.iovecs.push(libc::iovec {
+                        iov_base: bts.as_ptr() as *mut libc::c_void,
+                        iov_len: bts.len(),
+                    });
+                }
+                scratch
+                    .addrs
+                    .push(SockAddr::from(dest_for(local_addr, to_send[start].1)));
+                let mut cmsg_buf: CmsgBuf = [0; 4];
+                unsafe {
+                    let cmsg = cmsg_buf.as_mut_ptr() as *mut libc::cmsghdr;
+                    (*cmsg).cmsg_level = SOL_UDP;
+                    (*cmsg).cmsg_type = UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                    let segment_size = to_send[start].0.len() as u16;
+                    std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size);
+                }
+                scratch.cmsgs.push(cmsg_buf);
+            }
+            scratch.hdrs.clear();
+            let mut iov_offset = 0;
+            for (i, &(start, end)) in scratch.groups.iter().enumerate() {
+                let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+                hdr.msg_hdr.msg_name = scratch.addrs[i].as_ptr() as *mut libc::c_void;
+                hdr.msg_hdr.msg_namelen = scratch.addrs[i].len();
+                hdr.msg_hdr.msg_iov = unsafe { scratch.iovecs.as_mut_ptr().add(iov_offset) };
+                hdr.msg_hdr.msg_iovlen = (end - start) as _;
+                if end - start > 1 {
+                    hdr.msg_hdr.msg_control = scratch.cmsgs[i].as_mut_ptr() as *mut libc::c_void;
+                    hdr.msg_hdr.msg_controllen =
+                        unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as _;
+                }
+                iov_offset += end - start;
+                scratch.hdrs.push(hdr);
+            }
+            let sent = unsafe {
+                libc::sendmmsg(fd, scratch.hdrs.as_mut_ptr(), scratch.hdrs.len() as _, 0)
+            };
+            if sent < 0 {
+                let err = io::Error::last_os_error();
+                let segmented = scratch.groups.iter().any(|(start, end)| end - start > 1);
+                if segmented
+                    && (err.raw_os_error() == Some(libc::EIO)
+                        || err.raw_os_error() == Some(libc::EINVAL))
+                {
+                    // the device or route couldn't segment this batch, which may not hold for the next one
+                    tracing::debug!("UDP GSO failed ({}), sending batch unsegmented", err);
+                    gso = false;
+                    continue;
+                }
+                return Err(err);
+            }
+            if sent == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            *progress = scratch.groups[sent as usize - 1].1;
+            gso = gso_usable(fd);
+        }
+        Ok(())
+    })
+}
+
+/// Receives whatever datagrams are waiting, splitting apart any that GRO coalesced. Datagrams from anything but IP addresses are dropped.
+pub(crate) fn recv_many(fd: RawFd) -> io::Result<Vec<(Bytes, SocketAddr)>> {
+    SCRATCH.with(|scratch| {
+        let scratch = &mut *scratch.borrow_mut();
+        scratch.recv_buf.clear();
+        scratch.recv_buf.reserve(BATCH * RECV_SLOT);
+        unsafe {
+            scratch.recv_buf.set_len(BATCH * RECV_SLOT);
+        }
+        scratch.iovecs.clear();
+        scratch.names.clear();
+        scratch.cmsgs.clear();
+        for slot in scratch.recv_buf.chunks_exact_mut(RECV_SLOT) {
+            scratch.iovecs.push(libc::iovec {
+                iov_base: slot.as_mut_ptr() as *mut libc::c_void,
+                iov_len: slot.len(),
+            });
+            scratch.names.push(unsafe { mem::zeroed() });
+            scratch.cmsgs.push([0; 4]);
+        }
+        scratch.hdrs.clear();
+        for i in 0..BATCH {
+            let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+            hdr.msg_hdr.msg_name = &mut scratch.names[i] as *mut _ as *mut libc::c_void;
+            hdr.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+            hdr.msg_hdr.msg_iov = &mut scratch.iovecs[i];
+            hdr.msg_hdr.msg_iovlen = 1;
+            hdr.msg_hdr.msg_control = scratch.cmsgs[i].as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_hdr.msg_controllen = mem::size_of::<CmsgBuf>() as _;
+            scratch.hdrs.push(hdr);
+        }
+        let received = unsafe {
+            libc::recvmmsg(
+                fd,
+                scratch.hdrs.as_mut_ptr(),
+                BATCH as _,
+                0,
+                std::ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            scratch.recv_buf.clear();
+            return Err(io::Error::last_os_error());
+        }
+        let received = &scratch.hdrs[..received as usize];
+        let chunk = scratch.recv_buf.split().freeze();
+        let mut datagrams = Vec::with_capacity(received.len());
+        for (i, hdr) in received.iter().enumerate() {
+            let len = hdr.msg_len as usize;
+            if hdr.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                continue;
+            }
+            let addr = unsafe {
+                SockAddr::from_raw_parts(
+                    &scratch.names[i] as *const _ as *const libc::sockaddr,
+                    hdr.msg_hdr.msg_namelen,
+                )
+            };
+            let addr = match addr.as_std() {
+                Some(addr) => canonical_addr(addr),
+                None => continue,
+            };
+            let segment_size = gro_segment_size(&hdr.msg_hdr).unwrap_or(len).max(1);
+            let message = chunk.slice(i * RECV_SLOT..i * RECV_SLOT + len);
+            for offset in (0..len).step_by(segment_size) {
+                let end = (offset + segment_size).min(len);
+                datagrams.push((message.slice(offset..end), addr));
+            }
+        }
+        Ok(datagrams)
+    })
+}
+
+/// The size of the datagrams that GRO coalesced into a received message, if it did.
+fn gro_segment_size(msg_hdr: &libc::msghdr) -> Option<usize> {
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg_hdr);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == SOL_UDP && (*cmsg).cmsg_type == UDP_GRO {
+                let size = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                return Some(size as usize);
+            }
+            cmsg = libc::CMSG_NXTHDR(msg_hdr, cmsg);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{runtime, Backhaul};
+    use bytes::Bytes;
+
+    #[test]
+    fn batches_round_trip() {
+        smol::block_on(async {
+            let alice = runtime::new_udp_socket_bind("127.0.0.1:0").await.unwrap();
+            let bob = runtime::new_udp_socket_bind_gro("127.0.0.1:0")
+                .await
+                .unwrap();
+            let carol = runtime::new_udp_socket_bind_gro("[::0]:0").await.unwrap();
+            let bob_addr = bob.local_addr().unwrap();
+            let carol_addr = ([127, 0, 0, 1], carol.local_addr().unwrap().port()).into();
+            // runs of equal lengths, a shorter tail, and a change of destination in the middle
+            let mut to_send = Vec::new();
+            for i in 0..200u32 {
+                let len = if i % 50 == 49 { 300 } else { 1000 };
+                let mut pkt = vec![0u8; len];
+                pkt[..4].copy_from_slice(&i.to_be_bytes());
+                let dest = if (100..110).contains(&i) {
+                    carol_addr
+                } else {
+                    bob_addr
+                };
+                to_send.push((Bytes::from(pkt), dest));
+            }
+            alice.send_to_many(&to_send).await.unwrap();
+            let mut received = Vec::new();
+            while received.len() < 190 {
+                for (pkt, from) in bob.recv_from_many().await.unwrap() {
+                    assert_eq!(from, alice.local_addr().unwrap());
+                    received.push(pkt);
+                }
+            }
+            let expected: Vec<Bytes> = to_send
+                .iter()
+                .filter(|(_, dest)| *dest == bob_addr)
+                .map(|(pkt, _)| pkt.clone())
+                .collect();
+            assert_eq!(received, expected);
+            let mut received = Vec::new();
+            while received.len() < 10 {
+                for (pkt, from) in carol.recv_from_many().await.unwrap() {
+                    assert_eq!(from, alice.local_addr().unwrap());
+                    received.push(pkt);
+                }
+            }
+            let expected: Vec<Bytes> = to_send[100..110]
+                .iter()
+                .map(|(pkt, _)| pkt.clone())
+                .collect();
+            assert_eq!(received, expected);
+        })
+    }
+
+    #[test]
+    fn plain_reads_see_whole_datagrams() {
+        smol::block_on(async {
+            let alice = runtime::new_udp_socket_bind("127.0.0.1:0").await.unwrap();
+            let bob = runtime::new_udp_socket_bind("127.0.0.1:0").await.unwrap();
+            let bob_addr = bob.local_addr().unwrap();
+            let to_send: Vec<_> = (0..64u8)
+                .map(|i| (Bytes::from(vec![i; 1000]), bob_addr))
+                .collect();
+            // the batch goes out segmented, and must arrive as separate datagrams all the same
+            alice.send_to_many(&to_send).await.unwrap();
+            for (pkt, _) in to_send {
+                let (received, _) = Backhaul::recv_from(&bob).await.unwrap();
+                assert_eq!(received, pkt);
+            }
+        })
+    }
+}
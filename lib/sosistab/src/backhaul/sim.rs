@@ -0,0 +1,347 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use rand::prelude::*;
+use smol::channel::{Receiver, Sender};
+
+use crate::{chan::recv_many, runtime, Backhaul};
+
+/// How many packets a simulated socket buffers before it starts dropping, roughly like a kernel UDP buffer.
+const SOCKET_BUFFER: usize = 1024;
+
+/// Parameters of a simulated network. Probabilities are per packet.
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    /// Probability that a packet is silently dropped.
+    pub loss: f64,
+    /// Probability that a packet starts a burst of loss, during which every packet from the same sender is dropped.
+    pub burst_loss: f64,
+    /// Mean length of a loss burst, in packets.
+    pub burst_len: f64,
+    /// Probability that a packet is held back for `reorder_delay`, so that later packets overtake it.
+    pub reorder: f64,
+    /// How long a reordered packet is held back.
+    pub reorder_delay: Duration,
+    /// Probability that a packet is delivered twice.
+    pub duplicate: f64,
+    /// One-way propagation delay.
+    pub latency: Duration,
+    /// Maximum random delay added on top of `latency`.
+    pub jitter: Duration,
+    /// Bottleneck bandwidth of every sender, in bytes per second. `None` means unlimited.
+    pub bandwidth: Option<u64>,
+    /// Longest time a packet may queue at the bottleneck before it is tail-dropped.
+    pub max_queue_delay: Duration,
+    /// Seed for every random decision the network makes.
+    pub seed: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            loss: 0.0,
+            burst_loss: 0.0,
+            burst_len: 1.0,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(20),
+            duplicate: 0.0,
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            bandwidth: None,
+            max_queue_delay: Duration::from_millis(200),
+            seed: 0,
+        }
+    }
+}
+
+/// An in-memory datagram network that [SimBackhaul]s bind to. All random decisions come from one seeded RNG, so the same seed and the same sequence of sends loses, duplicates and reorders exactly the same packets.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<SimState>>,
+}
+
+struct SimState {
+    cfg: SimConfig,
+    host_cfgs: HashMap<IpAddr, SimConfig>,
+    rng: StdRng,
+    sockets: HashMap<SocketAddr, Sender<(Bytes, SocketAddr)>>,
+    busy_until: HashMap<SocketAddr, Instant>,
+    in_burst: HashMap<SocketAddr, bool>,
+    next_port: u16,
+}
+
+impl SimNetwork {
+    /// Creates a new simulated network.
+    pub fn new(cfg: SimConfig) -> Self {
+        let rng = StdRng::seed_from_u64(cfg.seed);
+        SimNetwork {
+            state: Arc::new(Mutex::new(SimState {
+                cfg,
+                host_cfgs: HashMap::new(),
+                rng,
+                sockets: HashMap::new(),
+                busy_until: HashMap::new(),
+                in_burst: HashMap::new(),
+                next_port: 10000,
+            })),
+        }
+    }
+
+    /// Changes the network conditions on the fly. The RNG is not reseeded.
+    pub fn set_config(&self, cfg: SimConfig) {
+        self.state.lock().cfg = cfg;
+    }
+
+    /// Overrides the network conditions for packets to or from the given host, as if it sat behind a link of its own. Conditions of the sending host win if both ends have their own.
+    pub fn set_host_config(&self, host: IpAddr, cfg: SimConfig) {
+        self.state.lock().host_cfgs.insert(host, cfg);
+    }
+
+    /// How many backhauls are currently bound to the network.
+    pub fn bound_count(&self) -> usize {
+        self.state.lock().sockets.len()
+    }
+
+    /// Binds a new backhaul to the given address. A zero port picks an unused one.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<SimBackhaul> {
+        let mut state = self.state.lock();
+        let mut addr = addr;
+        if addr.port() == 0 {
+            loop {
+                addr.set_port(state.next_port);
+                state.next_port = state.next_port.checked_add(1).unwrap_or(10000);
+                if !state.sockets.contains_key(&addr) {
+                    break;
+                }
+            }
+        } else if state.sockets.contains_key(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "simulated address in use",
+            ));
+        }
+        let (send, recv) = smol::channel::bounded(SOCKET_BUFFER);
+        state.sockets.insert(addr, send);
+        Ok(SimBackhaul {
+            network: self.clone(),
+            local_addr: addr,
+            recv,
+        })
+    }
+
+    fn transmit(&self, src: SocketAddr, dest: SocketAddr, pkt: Bytes) {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        let host_cfgs = &state.host_cfgs;
+        let cfg = host_cfgs
+            .get(&src.ip())
+            .or_else(|| host_cfgs.get(&dest.ip()))
+            .unwrap_or(&state.cfg);
+        let rng = &mut state.rng;
+        let now = Instant::now();
+        // draw every random decision up front, so that the sequence of draws doesn't depend on timing
+        let mut lost = rng.gen_bool(cfg.loss.clamp(0.0, 1.0));
+        let in_burst = state.in_burst.entry(src).or_default();
+        if cfg.burst_loss > 0.0 || *in_burst {
+            // a two-state Gilbert model, where the bad state loses everything
+            *in_burst = if *in_burst {
+                !rng.gen_bool((1.0 / cfg.burst_len.max(1.0)).min(1.0))
+            } else {
+                rng.gen_bool(cfg.burst_loss.clamp(0.0, 1.0))
+            };
+            lost |= *in_burst;
+        }
+        let copies = if rng.gen_bool(cfg.duplicate.clamp(0.0, 1.0)) {
+            2
+        } else {
+            1
+        };
+        let delays: Vec<Duration> = (0..copies)
+            .map(|_| {
+                let mut delay = cfg.latency + cfg.jitter.mul_f64(rng.gen::<f64>());
+                if rng.gen_bool(cfg.reorder.clamp(0.0, 1.0)) {
+                    delay += cfg.reorder_delay;
+                }
+                delay
+            })
+            .collect();
+        // the bottleneck queue
+        let mut depart = now;
+        if let Some(bandwidth) = cfg.bandwidth {
+            let busy = state.busy_until.entry(src).or_insert(now);
+            let start = (*busy).max(now);
+            if start.saturating_duration_since(now) > cfg.max_queue_delay {
+                tracing::trace!("sim: tail-dropping packet from {}", src);
+                return;
+            }
+            depart = start + Duration::from_secs_f64(pkt.len() as f64 / bandwidth.max(1) as f64);
+            *busy = depart;
+        }
+        if lost {
+            return;
+        }
+        let dest_chan = match state.sockets.get(&dest) {
+            Some(chan) => chan.clone(),
+            None => return,
+        };
+        for delay in delays {
+            deliver(dest_chan.clone(), pkt.clone(), src, depart + delay);
+        }
+    }
+}
+
+fn deliver(dest: Sender<(Bytes, SocketAddr)>, pkt: Bytes, src: SocketAddr, at: Instant) {
+    if at <= Instant::now() {
+        let _ = dest.try_send((pkt, src));
+    } else {
+        runtime::spawn(async move {
+            smol::Timer::at(at).await;
+            let _ = dest.try_send((pkt, src));
+        })
+        .detach();
+    }
+}
+
+/// A backhaul bound to a [SimNetwork]. Dropping it unbinds its address.
+pub struct SimBackhaul {
+    network: SimNetwork,
+    local_addr: SocketAddr,
+    recv: Receiver<(Bytes, SocketAddr)>,
+}
+
+impl Drop for SimBackhaul {
+    fn drop(&mut self) {
+        self.network.state.lock().sockets.remove(&self.local_addr);
+    }
+}
+
+#[async_trait::async_trait]
+impl Backhaul for SimBackhaul {
+    async fn send_to(&self, to_send: Bytes, dest: SocketAddr) -> io::Result<()> {
+        self.network.transmit(self.local_addr, dest, to_send);
+        Ok(())
+    }
+
+    async fn recv_from(&self) -> io::Result<(Bytes, SocketAddr)> {
+        self.recv
+            .recv()
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionAborted, "simulated network gone"))
+    }
+
+    async fn recv_from_many(&self) -> io::Result<Vec<(Bytes, SocketAddr)>> {
+        recv_many(&self.recv)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionAborted, "simulated network gone"))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::timeout;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// Sends numbered packets across the network and returns which ones arrived, in arrival order.
+    fn run_trial(cfg: SimConfig, count: u32) -> Vec<u32> {
+        smol::block_on(async {
+            let net = SimNetwork::new(cfg);
+            let alice = net.bind(addr("10.0.0.1:0")).unwrap();
+            let bob = net.bind(addr("10.0.0.2:1000")).unwrap();
+            for i in 0..count {
+                alice
+                    .send_to(
+                        Bytes::copy_from_slice(&i.to_be_bytes()),
+                        bob.local_addr().unwrap(),
+                    )
+                    .await
+                    .unwrap();
+            }
+            let mut received = Vec::new();
+            loop {
+                let pkt = timeout(Duration::from_millis(300), async {
+                    bob.recv_from().await.unwrap()
+                })
+                .await;
+                match pkt {
+                    Some((pkt, from)) => {
+                        assert_eq!(Some(from), alice.local_addr());
+                        let mut buf = [0u8; 4];
+                        buf.copy_from_slice(&pkt);
+                        received.push(u32::from_be_bytes(buf));
+                    }
+                    None => break received,
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn sim_is_deterministic() {
+        let cfg = SimConfig {
+            loss: 0.2,
+            duplicate: 0.1,
+            seed: 42,
+            ..Default::default()
+        };
+        let first = run_trial(cfg.clone(), 500);
+        let second = run_trial(cfg.clone(), 500);
+        assert_eq!(first, second);
+        let other = run_trial(SimConfig { seed: 43, ..cfg }, 500);
+        assert_ne!(first, other);
+        // roughly 20% loss and 10% duplication
+        assert!(first.len() > 350 && first.len() < 550);
+    }
+
+    #[test]
+    fn sim_reorders() {
+        let received = run_trial(
+            SimConfig {
+                reorder: 0.3,
+                ..Default::default()
+            },
+            200,
+        );
+        assert_eq!(received.len(), 200);
+        assert!(received.windows(2).any(|w| w[0] > w[1]));
+    }
+
+    #[test]
+    fn sim_bandwidth_cap() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig {
+                bandwidth: Some(100_000),
+                max_queue_delay: Duration::from_secs(10),
+                ..Default::default()
+            });
+            let alice = net.bind(addr("10.0.0.1:0")).unwrap();
+            let bob = net.bind(addr("10.0.0.2:0")).unwrap();
+            let start = Instant::now();
+            for _ in 0..50 {
+                alice
+                    .send_to(Bytes::from(vec![0u8; 1000]), bob.local_addr().unwrap())
+                    .await
+                    .unwrap();
+            }
+            for _ in 0..50 {
+                bob.recv_from().await.unwrap();
+            }
+            // 50 KB at 100 KB/s
+            assert!(start.elapsed() >= Duration::from_millis(450));
+        })
+    }
+}
@@ -0,0 +1,328 @@
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use c2_chacha::stream_cipher::{NewStreamCipher, SyncStreamCipher};
+use c2_chacha::ChaCha12;
+use parking_lot::RwLock;
+use rand::prelude::*;
+use smol::channel::{Receiver, Sender};
+use smol::net::{AsyncToSocketAddrs, TcpListener, TcpStream};
+use smol::prelude::*;
+
+use crate::{chan::recv_many, runtime, Backhaul};
+
+/// How long a connection may stay silent before it's torn down.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+/// How many frames may queue up in either direction of a connection before new ones are dropped.
+const QUEUE_LEN: usize = 1000;
+
+/// A backhaul that carries length-prefixed frames over an obfuscated TCP connection to a [TcpServerBackhaul]. Pass a closure creating these to `connect_with_backhaul_factory` to get one connection per shard.
+///
+/// A broken connection behaves like a dead UDP path: sends are dropped and nothing is received, until the session rebinds to a fresh backhaul.
+pub struct TcpClientBackhaul {
+    remote_addr: SocketAddr,
+    send_up: Sender<Bytes>,
+    recv_down: Receiver<Bytes>,
+    _task: smol::Task<()>,
+}
+
+impl TcpClientBackhaul {
+    /// Connects to a TCP sosistab endpoint with the given long-term public key.
+    pub async fn connect(
+        remote_addr: SocketAddr,
+        server_pk: x25519_dalek::PublicKey,
+    ) -> io::Result<Self> {
+        let mut conn = TcpStream::connect(remote_addr).await?;
+        conn.set_nodelay(true)?;
+        let nonce: [u8; 32] = rand::thread_rng().gen();
+        conn.write_all(&nonce).await?;
+        let (send_up, recv_up) = smol::channel::bounded(QUEUE_LEN);
+        let (send_down, recv_down) = smol::channel::bounded(QUEUE_LEN);
+        let _task = runtime::spawn(async move {
+            let res = pump(
+                conn,
+                obfs_cipher(&server_pk, &nonce, b"up"),
+                obfs_cipher(&server_pk, &nonce, b"dn"),
+                recv_up,
+                move |frame| {
+                    let _ = send_down.try_send(frame);
+                },
+            )
+            .await;
+            if let Err(err) = res {
+                tracing::debug!("TCP backhaul to {} died: {}", remote_addr, err)
+            }
+        });
+        Ok(TcpClientBackhaul {
+            remote_addr,
+            send_up,
+            recv_down,
+            _task,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Backhaul for TcpClientBackhaul {
+    async fn send_to(&self, to_send: Bytes, dest: SocketAddr) -> io::Result<()> {
+        if dest != self.remote_addr {
+            tracing::trace!(
+                "TCP backhaul to {} can't send to {}",
+                self.remote_addr,
+                dest
+            );
+            return Ok(());
+        }
+        let _ = self.send_up.try_send(to_send);
+        Ok(())
+    }
+
+    async fn recv_from(&self) -> io::Result<(Bytes, SocketAddr)> {
+        match self.recv_down.recv().await {
+            Ok(frame) => Ok((frame, self.remote_addr)),
+            Err(_) => smol::future::pending().await,
+        }
+    }
+
+    async fn recv_from_many(&self) -> io::Result<Vec<(Bytes, SocketAddr)>> {
+        match recv_many(&self.recv_down).await {
+            Ok(frames) => Ok(frames
+                .into_iter()
+                .map(|frame| (frame, self.remote_addr))
+                .collect()),
+            Err(_) => smol::future::pending().await,
+        }
+    }
+
+    fn is_reliable(&self) -> bool {
+        true
+    }
+}
+
+/// The listening side of the TCP backhaul. Every accepted connection shows up as a distinct peer address, so a client reconnecting looks exactly like a UDP client roaming.
+pub struct TcpServerBackhaul {
+    local_addr: SocketAddr,
+    conns: Arc<RwLock<HashMap<SocketAddr, Sender<Bytes>>>>,
+    recv_incoming: Receiver<(Bytes, SocketAddr)>,
+    _stop: Sender<()>,
+    _task: smol::Task<()>,
+}
+
+impl TcpServerBackhaul {
+    /// Listens for TCP connections on the given address. The public key must be the one the `Listener` is created with.
+    pub async fn bind(
+        addr: impl AsyncToSocketAddrs,
+        server_pk: x25519_dalek::PublicKey,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let conns = Arc::new(RwLock::new(HashMap::new()));
+        let (send_incoming, recv_incoming) = smol::channel::bounded(QUEUE_LEN);
+        let (_stop, recv_stop) = smol::channel::bounded(1);
+        let _task = runtime::spawn(accept_loop(
+            listener,
+            server_pk,
+            conns.clone(),
+            send_incoming,
+            recv_stop,
+        ));
+        Ok(TcpServerBackhaul {
+            local_addr,
+            conns,
+            recv_incoming,
+            _stop,
+            _task,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Backhaul for TcpServerBackhaul {
+    async fn send_to(&self, to_send: Bytes, dest: SocketAddr) -> io::Result<()> {
+        if let Some(conn) = self.conns.read().get(&dest) {
+            let _ = conn.try_send(to_send);
+        }
+        Ok(())
+    }
+
+    async fn recv_from(&self) -> io::Result<(Bytes, SocketAddr)> {
+        self.recv_incoming
+            .recv()
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionAborted, "TCP listener died"))
+    }
+
+    async fn recv_from_many(&self) -> io::Result<Vec<(Bytes, SocketAddr)>> {
+        recv_many(&self.recv_incoming)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionAborted, "TCP listener died"))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.local_addr)
+    }
+
+    fn is_reliable(&self) -> bool {
+        true
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    server_pk: x25519_dalek::PublicKey,
+    conns: Arc<RwLock<HashMap<SocketAddr, Sender<Bytes>>>>,
+    send_incoming: Sender<(Bytes, SocketAddr)>,
+    recv_stop: Receiver<()>,
+) {
+    loop {
+        let (mut conn, peer_addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(err) => {
+                tracing::warn!("error accepting TCP backhaul connection: {}", err);
+                smol::Timer::after(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let conns = conns.clone();
+        let send_incoming = send_incoming.clone();
+        let recv_stop = recv_stop.clone();
+        runtime::spawn(async move {
+            let serve = async {
+                conn.set_nodelay(true)?;
+                let mut nonce = [0u8; 32];
+                conn.read_exact(&mut nonce)
+                    .or(async {
+                        smol::Timer::after(Duration::from_secs(10)).await;
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "no nonce"))
+                    })
+                    .await?;
+                let (send_down, recv_down) = smol::channel::bounded(QUEUE_LEN);
+                conns.write().insert(peer_addr, send_down);
+                let _guard = scopeguard::guard((), |_| {
+                    conns.write().remove(&peer_addr);
+                });
+                pump(
+                    conn,
+                    obfs_cipher(&server_pk, &nonce, b"dn"),
+                    obfs_cipher(&server_pk, &nonce, b"up"),
+                    recv_down,
+                    |frame| {
+                        let _ = send_incoming.try_send((frame, peer_addr));
+                    },
+                )
+                .await
+            };
+            let stop = async {
+                let _ = recv_stop.recv().await;
+                Ok(())
+            };
+            if let Err(err) = serve.or(stop).await {
+                tracing::trace!("TCP backhaul connection from {} died: {}", peer_addr, err)
+            }
+        })
+        .detach();
+    }
+}
+
+/// Derives the stream cipher that obfuscates one direction of a connection. This only hides the framing from observers who don't know the server public key; the frames themselves are already encrypted.
+fn obfs_cipher(server_pk: &x25519_dalek::PublicKey, nonce: &[u8], direction: &[u8]) -> ChaCha12 {
+    let mut master = [0u8; 32];
+    blake3::derive_key("sosistab-tcp-obfs-1", server_pk.as_bytes(), &mut master);
+    let mut input = nonce.to_vec();
+    input.extend_from_slice(direction);
+    let key = blake3::keyed_hash(&master, &input);
+    ChaCha12::new_var(key.as_bytes(), &[0; 8]).expect("can't make chacha12")
+}
+
+/// Shuttles frames between a connection and a channel until either side fails.
+async fn pump(
+    conn: TcpStream,
+    mut write_cipher: ChaCha12,
+    mut read_cipher: ChaCha12,
+    outgoing: Receiver<Bytes>,
+    on_frame: impl Fn(Bytes),
+) -> io::Result<()> {
+    let mut write_conn = conn.clone();
+    let mut read_conn = conn;
+    let write_loop = async {
+        let mut buf = Vec::with_capacity(65536);
+        loop {
+            let frames = recv_many(&outgoing)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "backhaul dropped"))?;
+            buf.clear();
+            for frame in frames {
+                if frame.len() > u16::MAX as usize {
+                    continue;
+                }
+                buf.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+                buf.extend_from_slice(&frame);
+            }
+            write_cipher.apply_keystream(&mut buf);
+            write_conn.write_all(&buf).await?;
+        }
+    };
+    let read_loop = async {
+        loop {
+            let frame = read_frame(&mut read_conn, &mut read_cipher)
+                .or(async {
+                    smol::Timer::after(IDLE_TIMEOUT).await;
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "idle connection"))
+                })
+                .await?;
+            on_frame(frame);
+        }
+    };
+    write_loop.or(read_loop).await
+}
+
+async fn read_frame(conn: &mut TcpStream, cipher: &mut ChaCha12) -> io::Result<Bytes> {
+    let mut len = [0u8; 2];
+    conn.read_exact(&mut len).await?;
+    cipher.apply_keystream(&mut len);
+    let mut frame = vec![0u8; u16::from_be_bytes(len) as usize];
+    conn.read_exact(&mut frame).await?;
+    cipher.apply_keystream(&mut frame);
+    Ok(frame.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn session_over_tcp() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+            let backhaul = TcpServerBackhaul::bind("127.0.0.1:0", (&long_sk).into())
+                .await
+                .unwrap();
+            let listener = Listener::listen_with_backhaul(backhaul, long_sk.clone());
+            let client = connect_tcp(
+                listener.local_addr(),
+                (&long_sk).into(),
+                ClientConfig::default(),
+            )
+            .await
+            .unwrap();
+            for i in 0u32..100 {
+                client.send_bytes(i.to_be_bytes().to_vec().into()).await;
+                smol::Timer::after(std::time::Duration::from_millis(1)).await;
+            }
+            let server = listener.accept_session().await.unwrap();
+            let mut received = Vec::new();
+            for _ in 0u32..100 {
+                received.push(server.recv_bytes().await.unwrap());
+            }
+            received.sort();
+            let expected: Vec<Bytes> = (0u32..100)
+                .map(|i| i.to_be_bytes().to_vec().into())
+                .collect();
+            assert_eq!(received, expected);
+            // the reliable transport means no parity at all
+            let stats = server.get_stats().await.unwrap();
+            assert_eq!(stats.down_redundant, 0.0);
+        })
+    }
+}
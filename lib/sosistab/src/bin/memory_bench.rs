@@ -40,9 +40,13 @@ async fn run_client() {
             .unwrap();
     smol::Timer::after(Duration::from_secs(1)).await;
     println!("spawning {} idle connections...", CONN_COUNT);
-    let session = sosistab::connect("127.0.0.1:23456".parse().unwrap(), pubkey_bts.into())
-        .await
-        .unwrap();
+    let session = sosistab::connect(
+        "127.0.0.1:23456".parse().unwrap(),
+        pubkey_bts.into(),
+        Default::default(),
+    )
+    .await
+    .unwrap();
     let mux = Arc::new(sosistab::mux::Multiplex::new(session));
     let mut conns = Vec::new();
     for _ in 0..CONN_COUNT {
@@ -7,6 +7,9 @@ pub async fn recv_many<T>(ch: &Receiver<T>) -> Result<Vec<T>, smol::channel::Rec
     while let Ok(val) = ch.try_recv() {
         buf.push(val);
     }
-    buf.push(ch.recv().await?);
+    // only block if nothing was ready, so that what we already took isn't held hostage waiting for more
+    if buf.is_empty() {
+        buf.push(ch.recv().await?);
+    }
     Ok(buf)
 }
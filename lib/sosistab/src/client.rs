@@ -1,4 +1,8 @@
 use crate::chan::recv_many;
+use crate::crypt::AeadExt;
+use crate::multipath::PathTable;
+use crate::session::Traffic;
+use crate::shape::Shaper;
 use crate::*;
 use bytes::Bytes;
 use smol::channel::{Receiver, Sender};
@@ -9,117 +13,557 @@ use std::{
     time::{Duration, Instant},
 };
 
-/// Connects to a remote server.
+/// How long a connection attempt to one address gets before `connect_happy_eyeballs` tries the next one too, as in RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// How many frames after a shard's ClientResume are kept to send again, until the server confirms that it knows the shard.
+const UNCONFIRMED_LIMIT: usize = 64;
+/// How long a shard waits for confirmation before sending its ClientResume and the frames after it again. This doubles with every try.
+const UNCONFIRMED_RESEND: Duration = Duration::from_millis(250);
+/// How many times a shard sends its ClientResume and the frames after it again before it gives up on them.
+const UNCONFIRMED_TRIES: u32 = 5;
+/// How many times the client hellos are sent before connecting gives up. The wait for a reply starts at a second and doubles with every try, so this gives up after about a minute.
+const HANDSHAKE_TRIES: u32 = 6;
+/// The lowest loss rate FEC can aim for. Anything lower rounds down to no loss at all, which takes as much parity as the code allows.
+const MIN_TARGET_LOSS: f64 = 1.0 / 256.0;
+/// The highest loss rate FEC can aim for. Past this, it hardly sends any parity anyway.
+const MAX_TARGET_LOSS: f64 = 0.5;
+/// How often a shard may hop to a fresh socket at most.
+const MIN_RESET_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a shard may remind the server of its address at most.
+const MIN_REMIND_INTERVAL: Duration = Duration::from_millis(100);
+/// How soon a silent session may die at the earliest, so that a brief outage doesn't end it.
+const MIN_RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connects to a remote server with the given configuration.
 #[tracing::instrument]
 pub async fn connect(
     server_addr: SocketAddr,
     pubkey: x25519_dalek::PublicKey,
+    cfg: ClientConfig,
 ) -> std::io::Result<Session> {
-    connect_custom(server_addr, pubkey, || {
-        let val = "[::0]:0".parse::<SocketAddr>().unwrap();
-        Ok(val)
-    })
-    .await
+    // binding to the server's family works even where the other family is missing
+    let laddr: SocketAddr = match server_addr {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::0]:0".parse().unwrap(),
+    };
+    connect_custom(server_addr, pubkey, move || Ok(laddr), cfg).await
+}
+
+/// Connects to a remote server that has several addresses, such as every A and AAAA record of its hostname. IPv6 and IPv4 addresses take turns, starting with IPv6, and each attempt gets a head start of `ATTEMPT_DELAY` before the next one begins alongside it. Whichever connects first wins.
+#[tracing::instrument]
+pub async fn connect_happy_eyeballs(
+    server_addrs: Vec<SocketAddr>,
+    pubkey: x25519_dalek::PublicKey,
+    cfg: ClientConfig,
+) -> std::io::Result<Session> {
+    let (send_res, recv_res) = smol::channel::unbounded();
+    let mut _attempts = Vec::new();
+    let mut last_err = std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "no addresses to connect to",
+    );
+    for server_addr in interleave_families(server_addrs) {
+        let send_res = send_res.clone();
+        let cfg = cfg.clone();
+        _attempts.push(runtime::spawn(async move {
+            drop(send_res.send(connect(server_addr, pubkey, cfg).await).await)
+        }));
+        // a failed attempt doesn't hold up the next one
+        let res = async { recv_res.recv().await.ok() }
+            .or(async {
+                smol::Timer::after(ATTEMPT_DELAY).await;
+                None
+            })
+            .await;
+        match res {
+            Some(Ok(session)) => return Ok(session),
+            Some(Err(err)) => {
+                tracing::debug!("connection attempt failed: {}", err);
+                last_err = err
+            }
+            None => {}
+        }
+    }
+    drop(send_res);
+    while let Ok(res) = recv_res.recv().await {
+        match res {
+            Ok(session) => return Ok(session),
+            Err(err) => {
+                tracing::debug!("connection attempt failed: {}", err);
+                last_err = err
+            }
+        }
+    }
+    Err(last_err)
+}
+
+/// Orders addresses IPv6 first and then alternating between the families, keeping the order of each family.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6());
+    let mut v4 = v4.into_iter();
+    let mut interleaved = Vec::with_capacity(v6.len() + v4.len());
+    for addr in v6 {
+        interleaved.push(addr);
+        interleaved.extend(v4.next());
+    }
+    interleaved.extend(v4);
+    interleaved
 }
 
-/// Connects to a remote server, given a closure that generates socket addresses.
+/// Tunables of a client session. Start from the defaults and change what's needed, e.g. `ClientConfig::default().shards(2)`.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    shards: u8,
+    reset_interval: Duration,
+    remind_interval: Duration,
+    target_loss: f64,
+    recv_timeout: Duration,
+    require_hybrid: bool,
+    rekey_frames: u64,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            shards: 4,
+            reset_interval: Duration::from_secs(5),
+            remind_interval: Duration::from_secs(1),
+            target_loss: 0.01,
+            recv_timeout: Duration::from_secs(300),
+            require_hybrid: false,
+            rekey_frames: crypt::REKEY_FRAMES,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Sets how many shards, each with its own socket, the session is spread over. At least one is always used. Defaults to 4.
+    pub fn shards(mut self, shards: u8) -> Self {
+        self.shards = shards.max(1);
+        self
+    }
+
+    /// Sets how often each shard hops to a fresh socket. Hopping less often saves battery and NAT mappings, but makes the session easier to pick out. Defaults to 5 seconds, and is held to at least a second.
+    pub fn reset_interval(mut self, interval: Duration) -> Self {
+        self.reset_interval = interval.max(MIN_RESET_INTERVAL);
+        self
+    }
+
+    /// Sets how often each shard reminds the server of its address, so that the session survives NAT rebinding. Defaults to 1 second, and is held to at least 100 milliseconds.
+    pub fn remind_interval(mut self, interval: Duration) -> Self {
+        self.remind_interval = interval.max(MIN_REMIND_INTERVAL);
+        self
+    }
+
+    /// Sets the loss rate that FEC tries to bring downstream loss under. Defaults to 1%, and is held between 1/256, the finest target FEC can aim for, and 50%.
+    pub fn target_loss(mut self, target_loss: f64) -> Self {
+        self.target_loss = if target_loss.is_nan() {
+            MIN_TARGET_LOSS
+        } else {
+            target_loss.clamp(MIN_TARGET_LOSS, MAX_TARGET_LOSS)
+        };
+        self
+    }
+
+    /// Sets how long the session may go without hearing from the server before it dies. Defaults to 5 minutes, and is held to at least 5 seconds.
+    pub fn recv_timeout(mut self, timeout: Duration) -> Self {
+        self.recv_timeout = timeout.max(MIN_RECV_TIMEOUT);
+        self
+    }
+
+    /// Sets whether to insist on the post-quantum hybrid key exchange, rather than settling for a classical one with servers older than version 3. Someone on the path can then only block the session, never weaken it. Defaults to false.
+    pub fn require_hybrid(mut self, require: bool) -> Self {
+        self.require_hybrid = require;
+        self
+    }
+
+    /// Ratchets the session keys every `frames` frames rather than every [crypt::REKEY_FRAMES], so that tests can force rekeys.
+    #[cfg(test)]
+    pub(crate) fn rekey_frames(mut self, frames: u64) -> Self {
+        self.rekey_frames = frames;
+        self
+    }
+}
+
+/// Connects to a remote server with the given configuration, given a closure that generates socket addresses.
 #[tracing::instrument(skip(laddr_gen))]
 pub async fn connect_custom(
     server_addr: SocketAddr,
     pubkey: x25519_dalek::PublicKey,
     laddr_gen: impl Fn() -> std::io::Result<SocketAddr> + Send + Sync + 'static,
+    cfg: ClientConfig,
 ) -> std::io::Result<Session> {
-    let udp_socket = runtime::new_udp_socket_bind(laddr_gen()?).await?;
+    connect_paths(
+        server_addr,
+        pubkey,
+        move |_| {
+            let laddr = laddr_gen();
+            async move {
+                let socket: Arc<dyn Backhaul> =
+                    Arc::new(runtime::new_udp_socket_bind_gro(laddr?).await?);
+                Ok(socket)
+            }
+        },
+        1,
+        PROTOCOL_VERSION,
+        cfg,
+    )
+    .await
+}
+
+/// Connects to a remote server over several network paths at once, such as Wi-Fi and cellular, given a local address to bind to for each. Shards are spread across the paths, and the server sends more over whichever paths are healthiest.
+#[tracing::instrument]
+pub async fn connect_multipath(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+    laddrs: Vec<SocketAddr>,
+    cfg: ClientConfig,
+) -> std::io::Result<Session> {
+    if laddrs.is_empty() || laddrs.len() > u8::MAX as usize {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "need between 1 and 255 local addresses",
+        ));
+    }
+    let path_count = laddrs.len() as u8;
+    connect_paths(
+        server_addr,
+        pubkey,
+        move |path| {
+            let laddr = laddrs[path as usize];
+            async move {
+                let socket: Arc<dyn Backhaul> =
+                    Arc::new(runtime::new_udp_socket_bind_gro(laddr).await?);
+                Ok(socket)
+            }
+        },
+        path_count,
+        PROTOCOL_VERSION,
+        cfg,
+    )
+    .await
+}
+
+/// Connects to a remote server over obfuscated TCP connections, for networks that block UDP.
+#[tracing::instrument]
+pub async fn connect_tcp(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+    cfg: ClientConfig,
+) -> std::io::Result<Session> {
+    connect_with_backhaul_factory(
+        server_addr,
+        pubkey,
+        move || async move {
+            let backhaul: Arc<dyn Backhaul> =
+                Arc::new(TcpClientBackhaul::connect(server_addr, pubkey).await?);
+            Ok(backhaul)
+        },
+        cfg,
+    )
+    .await
+}
+
+/// Connects to a remote server over backhauls produced by the given factory. The factory is called once for the handshake, once for every shard, and again whenever a shard rebinds to a fresh backhaul.
+#[tracing::instrument(skip(backhaul_gen))]
+pub async fn connect_with_backhaul_factory<F, Fut>(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+    backhaul_gen: F,
+    cfg: ClientConfig,
+) -> std::io::Result<Session>
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = std::io::Result<Arc<dyn Backhaul>>> + Send + 'static,
+{
+    connect_paths(
+        server_addr,
+        pubkey,
+        move |_| backhaul_gen(),
+        1,
+        PROTOCOL_VERSION,
+        cfg,
+    )
+    .await
+}
+
+/// Connects to a remote server over `path_count` paths, given a factory that produces backhauls for each path. The handshake goes over the first path.
+pub(crate) async fn connect_paths<F, Fut>(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+    backhaul_gen: F,
+    path_count: u8,
+    max_version: u64,
+    cfg: ClientConfig,
+) -> std::io::Result<Session>
+where
+    F: Fn(u8) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = std::io::Result<Arc<dyn Backhaul>>> + Send + 'static,
+{
+    let backhaul = backhaul_gen(0).await?;
     let my_long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
     let my_eph_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+    let (my_kem_pk, my_kem_sk) = crypt::kem_keypair();
     // do the handshake
     let cookie = crypt::Cookie::new(pubkey);
-    let init_hello = msg::HandshakeFrame::ClientHello {
-        long_pk: (&my_long_sk).into(),
-        eph_pk: (&my_eph_sk).into(),
-        version: 1,
-    };
-    let mut buf = [0u8; 2048];
-    for timeout_factor in (0u32..).map(|x| 2u64.pow(x)) {
-        // send hello
-        let init_hello = crypt::StdAEAD::new(&cookie.generate_c2s().next().unwrap())
-            .pad_encrypt(&init_hello, 1000);
-        udp_socket.send_to(&init_hello, server_addr).await?;
-        tracing::trace!("sent client hello");
-        // wait for response
-        let res = udp_socket
-            .recv_from(&mut buf)
-            .or(async {
-                smol::Timer::after(Duration::from_secs(timeout_factor)).await;
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::TimedOut,
-                    "timed out",
-                ))
-            })
-            .await;
-        match res {
-            Ok((n, _)) => {
-                let buf = &buf[..n];
-                for possible_key in cookie.generate_s2c() {
-                    let decrypter = crypt::StdAEAD::new(&possible_key);
-                    let response: Option<msg::HandshakeFrame> = decrypter.pad_decrypt(buf);
-                    if let Some(msg::HandshakeFrame::ServerHello {
-                        long_pk,
-                        eph_pk,
-                        resume_token,
-                    }) = response
-                    {
-                        tracing::trace!("obtained response from server");
-                        if long_pk.as_bytes() != pubkey.as_bytes() {
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::ConnectionRefused,
-                                "bad pubkey",
-                            ));
+    let mut hellos = Vec::new();
+    if max_version >= 4 {
+        hellos.push(msg::HandshakeFrame::ClientHelloV4 {
+            long_pk: (&my_long_sk).into(),
+            eph_pk: (&my_eph_sk).into(),
+            kem_pk: my_kem_pk.clone(),
+            timestamp: 0,
+            version: max_version,
+            features: features::SUPPORTED,
+        });
+    }
+    if max_version >= 3 {
+        hellos.push(msg::HandshakeFrame::ClientHelloV3 {
+            long_pk: (&my_long_sk).into(),
+            eph_pk: (&my_eph_sk).into(),
+            kem_pk: my_kem_pk,
+            version: max_version.min(3),
+            features: features::SUPPORTED,
+            max_version,
+        });
+    }
+    if max_version >= 2 && !cfg.require_hybrid {
+        hellos.push(msg::HandshakeFrame::ClientHelloV2 {
+            long_pk: (&my_long_sk).into(),
+            eph_pk: (&my_eph_sk).into(),
+            version: max_version.min(2),
+            features: features::SUPPORTED,
+            max_version,
+        });
+    }
+    if !cfg.require_hybrid {
+        hellos.push(msg::HandshakeFrame::ClientHello {
+            long_pk: (&my_long_sk).into(),
+            eph_pk: (&my_eph_sk).into(),
+            version: 1,
+            max_version,
+            features: features::SUPPORTED,
+        });
+    }
+    if hellos.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "the hybrid handshake needs protocol version 3",
+        ));
+    }
+    for attempt in 0..HANDSHAKE_TRIES {
+        let timeout_factor = 2u64.pow(attempt);
+        // the newest hello goes first; should it go unanswered, older ones follow in case the server predates it, but their replies only count if they show that it does
+        let hello_count = if attempt == 0 { 1 } else { hellos.len() };
+        for init_hello in &mut hellos[..hello_count] {
+            if let msg::HandshakeFrame::ClientHelloV4 { timestamp, .. } = init_hello {
+                *timestamp = msg::hello_timestamp();
+            }
+            let init_hello = crypt::StdAEAD::new(&cookie.generate_c2s().next().unwrap())
+                .pad_encrypt(&init_hello, 1000);
+            backhaul.send_to(init_hello, server_addr).await?;
+        }
+        tracing::trace!("sent {} client hellos", hello_count);
+        // wait for a response, which replies that get ignored don't cut short
+        let mut timeout = smol::Timer::after(Duration::from_secs(timeout_factor));
+        loop {
+            let res = backhaul
+                .recv_from_many()
+                .or(async {
+                    (&mut timeout).await;
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "timed out",
+                    ))
+                })
+                .await;
+            match res {
+                Ok(batch) => {
+                    for (buf, _) in batch {
+                        for possible_key in cookie.generate_s2c() {
+                            let decrypter = crypt::StdAEAD::new(&possible_key);
+                            let response: Option<msg::HandshakeFrame> = decrypter.pad_decrypt(&buf);
+                            let (
+                                long_pk,
+                                eph_pk,
+                                kem_ct,
+                                resume_token,
+                                version,
+                                features,
+                                server_max_version,
+                            ) = match response {
+                                Some(msg::HandshakeFrame::ServerHello {
+                                    long_pk,
+                                    eph_pk,
+                                    resume_token,
+                                    max_version,
+                                }) => (
+                                    long_pk,
+                                    eph_pk,
+                                    None,
+                                    resume_token,
+                                    1,
+                                    features::LEGACY,
+                                    max_version,
+                                ),
+                                Some(msg::HandshakeFrame::ServerHelloV2 {
+                                    long_pk,
+                                    eph_pk,
+                                    resume_token,
+                                    version,
+                                    features,
+                                    max_version,
+                                }) => (
+                                    long_pk,
+                                    eph_pk,
+                                    None,
+                                    resume_token,
+                                    version,
+                                    features,
+                                    max_version,
+                                ),
+                                Some(msg::HandshakeFrame::ServerHelloV3 {
+                                    long_pk,
+                                    eph_pk,
+                                    kem_ct,
+                                    resume_token,
+                                    version,
+                                    features,
+                                    max_version,
+                                }) => (
+                                    long_pk,
+                                    eph_pk,
+                                    Some(kem_ct),
+                                    resume_token,
+                                    version,
+                                    features,
+                                    max_version,
+                                ),
+                                _ => continue,
+                            };
+                            // a reply to an older hello from a server that could have answered a newer one means the newer one went missing, whether by accident or not
+                            if version < max_version.min(server_max_version)
+                                || (cfg.require_hybrid && kem_ct.is_none())
+                            {
+                                tracing::debug!(
+                                    "ignoring version {} reply from a version {} server",
+                                    version,
+                                    server_max_version
+                                );
+                                continue;
+                            }
+                            if version > max_version
+                                || features & !features::SUPPORTED != 0
+                                || !features::is_usable(features)
+                            {
+                                return Err(std::io::Error::new(
+                                    std::io::ErrorKind::ConnectionRefused,
+                                    "server picked unsupported protocol version or features",
+                                ));
+                            }
+                            tracing::trace!("obtained response from server");
+                            if long_pk.as_bytes() != pubkey.as_bytes() {
+                                return Err(std::io::Error::new(
+                                    std::io::ErrorKind::ConnectionRefused,
+                                    "bad pubkey",
+                                ));
+                            }
+                            let mut shared_sec =
+                                crypt::triple_ecdh(&my_long_sk, &my_eph_sk, &long_pk, &eph_pk);
+                            if let Some(kem_ct) = kem_ct {
+                                let kem_secret = crypt::kem_decapsulate(&kem_ct, &my_kem_sk)
+                                    .ok_or_else(|| {
+                                        std::io::Error::new(
+                                            std::io::ErrorKind::ConnectionRefused,
+                                            "bad KEM ciphertext",
+                                        )
+                                    })?;
+                                shared_sec = crypt::hybrid_secret(shared_sec, &kem_secret);
+                            }
+                            // older servers don't bind the offers, and say so by leaving out their highest version
+                            if server_max_version != 0 {
+                                shared_sec = crypt::bind_offers(
+                                    shared_sec,
+                                    max_version,
+                                    features::SUPPORTED,
+                                    server_max_version,
+                                );
+                            }
+                            return init_session(
+                                cookie,
+                                resume_token,
+                                shared_sec,
+                                server_addr,
+                                backhaul.is_reliable(),
+                                version,
+                                features,
+                                Arc::new(backhaul_gen),
+                                path_count,
+                                cfg,
+                            )
+                            .await;
                         }
-                        let shared_sec =
-                            crypt::triple_ecdh(&my_long_sk, &my_eph_sk, &long_pk, &eph_pk);
-                        return init_session(
-                            cookie,
-                            resume_token,
-                            shared_sec,
-                            server_addr,
-                            Arc::new(laddr_gen),
-                        )
-                        .await;
                     }
                 }
-            }
-            Err(err) => {
-                if err.kind() == std::io::ErrorKind::TimedOut {
-                    tracing::trace!(
-                        "timed out to {} with {}s timeout; trying again",
-                        server_addr,
-                        timeout_factor
-                    );
-                    continue;
+                Err(err) => {
+                    if err.kind() == std::io::ErrorKind::TimedOut {
+                        tracing::trace!(
+                            "timed out to {} with {}s timeout; trying again",
+                            server_addr,
+                            timeout_factor
+                        );
+                        break;
+                    }
+                    return Err(err);
                 }
-                return Err(err);
             }
         }
     }
-    unimplemented!()
+    Err(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "server never answered the handshake",
+    ))
 }
 
-const SHARDS: u8 = 4;
-const RESET_MILLIS: u128 = 5000;
-const REMIND_MILLIS: u128 = 1000;
-
-#[tracing::instrument(skip(laddr_gen))]
-async fn init_session(
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(backhaul_gen))]
+async fn init_session<F, Fut>(
     cookie: crypt::Cookie,
     resume_token: Bytes,
     shared_sec: blake3::Hash,
     remote_addr: SocketAddr,
-    laddr_gen: Arc<impl Fn() -> std::io::Result<SocketAddr> + Send + Sync + 'static>,
-) -> std::io::Result<Session> {
+    reliable: bool,
+    version: u64,
+    features: u64,
+    backhaul_gen: Arc<F>,
+    path_count: u8,
+    cfg: ClientConfig,
+) -> std::io::Result<Session>
+where
+    F: Fn(u8) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = std::io::Result<Arc<dyn Backhaul>>> + Send + 'static,
+{
+    // every shard shares the same ciphers, so that each direction has exactly one ratchet
+    let up_key = blake3::keyed_hash(crypt::UP_KEY, shared_sec.as_bytes());
+    let dn_key = blake3::keyed_hash(crypt::DN_KEY, shared_sec.as_bytes());
+    let up_crypter = crypt::session_aead(features, up_key.as_bytes(), cfg.rekey_frames);
+    let dn_crypter = crypt::session_aead(features, dn_key.as_bytes(), cfg.rekey_frames);
+    let resume_ack_key = if features & features::EXT_RESUME_ACK != 0 {
+        Some(blake3::keyed_hash(
+            crypt::RESUME_ACK_KEY,
+            shared_sec.as_bytes(),
+        ))
+    } else {
+        None
+    };
+    let shaper = Arc::new(Shaper::default());
+    let traffic = Arc::new(Traffic::default());
+    let paths = Arc::new(PathTable::default());
+    let multipath = features & features::EXT_MULTIPATH != 0;
     let (send_frame_out, recv_frame_out) = smol::channel::bounded::<msg::DataFrame>(1000);
     let (send_frame_in, recv_frame_in) = smol::channel::bounded::<msg::DataFrame>(1000);
-    let backhaul_tasks: Vec<_> = (0..SHARDS)
+    // every path gets at least one shard
+    let backhaul_tasks: Vec<_> = (0..cfg.shards.max(path_count))
         .map(|i| {
             runtime::spawn(client_backhaul_once(
                 cookie.clone(),
@@ -128,73 +572,130 @@ async fn init_session(
                 recv_frame_out.clone(),
                 i,
                 remote_addr,
-                shared_sec,
-                laddr_gen.clone(),
+                up_crypter.clone(),
+                dn_crypter.clone(),
+                resume_ack_key,
+                shaper.clone(),
+                traffic.clone(),
+                if multipath { Some(paths.clone()) } else { None },
+                backhaul_gen.clone(),
+                i % path_count,
+                cfg.clone(),
             ))
         })
         .collect();
     let mut session = Session::new(SessionConfig {
-        target_loss: 0.01,
+        target_loss: cfg.target_loss,
         send_frame: send_frame_out,
         recv_frame: recv_frame_in,
-        recv_timeout: Duration::from_secs(300),
+        recv_timeout: cfg.recv_timeout,
+        reliable,
+        version,
+        features,
+        is_client: true,
+        shaper,
+        traffic,
+        paths,
+        handing_over: Default::default(),
     });
+    // the shards stop once the session stops giving them frames, which is after its close frame
     session.on_drop(move || {
-        drop(backhaul_tasks);
+        for task in backhaul_tasks {
+            session::linger(task);
+        }
     });
     Ok(session)
 }
 
 #[allow(clippy::all)]
-#[tracing::instrument(skip(laddr_gen))]
-async fn client_backhaul_once(
+#[tracing::instrument(skip(backhaul_gen))]
+async fn client_backhaul_once<F, Fut>(
     cookie: crypt::Cookie,
     resume_token: Bytes,
     send_frame_in: Sender<msg::DataFrame>,
     recv_frame_out: Receiver<msg::DataFrame>,
     shard_id: u8,
     remote_addr: SocketAddr,
-    shared_sec: blake3::Hash,
-    laddr_gen: Arc<impl Fn() -> std::io::Result<SocketAddr> + Send + Sync + 'static>,
-) -> Option<()> {
-    let up_key = blake3::keyed_hash(crypt::UP_KEY, shared_sec.as_bytes());
-    let dn_key = blake3::keyed_hash(crypt::DN_KEY, shared_sec.as_bytes());
-    let dn_crypter = Arc::new(crypt::StdAEAD::new(dn_key.as_bytes()));
-    let up_crypter = Arc::new(crypt::StdAEAD::new(up_key.as_bytes()));
-
+    up_crypter: Arc<dyn crypt::Aead>,
+    dn_crypter: Arc<dyn crypt::Aead>,
+    resume_ack_key: Option<blake3::Hash>,
+    shaper: Arc<Shaper>,
+    traffic: Arc<Traffic>,
+    paths: Option<Arc<PathTable>>,
+    backhaul_gen: Arc<F>,
+    path: u8,
+    cfg: ClientConfig,
+) -> Option<()>
+where
+    F: Fn(u8) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = std::io::Result<Arc<dyn Backhaul>>> + Send + 'static,
+{
     let mut last_remind = Instant::now();
     let mut last_reset = Instant::now();
     let mut updated = false;
-    let mut socket: Arc<dyn Backhaul> =
-        Arc::new(runtime::new_udp_socket_bind(laddr_gen().ok()?).await.ok()?);
+    let mut socket = backhaul_gen(path).await.ok()?;
     // let mut _old_cleanup: Option<smol::Task<Option<()>>> = None;
+    // the ClientResume can be lost, or overtaken by the frames after it, which the server then drops. So until the server acknowledges that one of those frames arrived, or sends anything else on the socket, they're sent again. Servers that never acknowledge get nothing sent again, lest one-way traffic be sent over and over.
+    let resume_ack = resume_ack_key.map(|key| crypt::StdAEAD::new(key.as_bytes()));
+    let mut confirmed = resume_ack.is_none();
+    let mut unconfirmed: Vec<Bytes> = Vec::new();
+    let mut resends = 0;
+    let mut next_resend = Instant::now();
+    let client_resume = || {
+        crypt::StdAEAD::new(&cookie.generate_c2s().next().unwrap()).pad_encrypt(
+            msg::HandshakeFrame::ClientResume {
+                resume_token: resume_token.clone(),
+                shard_id,
+                up_epoch: up_crypter.epoch(),
+                dn_epoch: dn_crypter.epoch(),
+            },
+            1000,
+        )
+    };
 
     #[derive(Debug)]
     enum Evt {
-        Incoming(Vec<msg::DataFrame>),
+        /// Frames that arrived, and whether the server acknowledged our ClientResume.
+        Incoming(Vec<msg::DataFrame>, bool),
         Outgoing(Vec<Bytes>),
+        Resend,
     };
 
     loop {
         let down = {
             let dn_crypter = dn_crypter.clone();
             let socket = &socket;
+            let traffic = &traffic;
+            let paths = &paths;
+            let resume_ack = &resume_ack;
             async move {
                 let mut incoming = Vec::with_capacity(64);
+                let mut acked = false;
                 for (buf, addr) in socket.recv_from_many().await.ok()? {
-                    if let Some(plain) = dn_crypter.pad_decrypt::<msg::DataFrame>(&buf) {
+                    if let Some(plain) = dn_crypter
+                        .decrypt(&buf)
+                        .and_then(|plain| msg::DataFrame::from_plain(&plain))
+                    {
                         tracing::trace!(
                             "shard {} decrypted UDP message with len {}",
                             shard_id,
                             buf.len()
                         );
+                        traffic.received_datagram(buf.len());
+                        if let (Some(paths), Some(header)) = (paths, &plain.path) {
+                            paths.received(shard_id, header);
+                        }
                         incoming.push(plain);
+                    } else if let Some(msg::HandshakeFrame::ResumeAck { shard_id: acked_id }) =
+                        resume_ack.as_ref().and_then(|aead| aead.pad_decrypt(&buf))
+                    {
+                        acked |= acked_id == shard_id;
                     } else {
                         tracing::warn!("anomalous UDP packet of len {} from {}", buf.len(), addr);
                         smol::future::pending().await
                     }
                 }
-                Some(Evt::Incoming(incoming))
+                Some(Evt::Incoming(incoming, acked))
             }
         };
         let up_crypter = up_crypter.clone();
@@ -202,13 +703,29 @@ async fn client_backhaul_once(
             let dff = recv_many(&recv_frame_out).await.ok()?;
             let encrypted = dff
                 .into_iter()
-                .map(|df| up_crypter.pad_encrypt(df, 1000))
+                .map(|mut df| {
+                    if let Some(paths) = &paths {
+                        df.path = Some(paths.stamp(shard_id));
+                    }
+                    shaper.pad_encrypt(up_crypter.as_ref(), &df)
+                })
                 .collect();
             Some(Evt::Outgoing(encrypted))
         };
+        let resend = async {
+            if confirmed || unconfirmed.is_empty() || resends >= UNCONFIRMED_TRIES {
+                smol::future::pending::<()>().await;
+            }
+            smol::Timer::at(next_resend).await;
+            Some(Evt::Resend)
+        };
 
-        match smol::future::race(down, up).await {
-            Some(Evt::Incoming(df)) => {
+        match smol::future::race(smol::future::race(down, up), resend).await {
+            Some(Evt::Incoming(df, acked)) => {
+                if acked || !df.is_empty() {
+                    confirmed = true;
+                    unconfirmed = Vec::new();
+                }
                 for df in df {
                     send_frame_in.send(df).await.ok()?;
                 }
@@ -216,32 +733,39 @@ async fn client_backhaul_once(
             Some(Evt::Outgoing(bts)) => {
                 let bts: Vec<Bytes> = bts;
                 let now = Instant::now();
-                if now.saturating_duration_since(last_remind).as_millis() > REMIND_MILLIS
-                    || !updated
-                {
+                if now.saturating_duration_since(last_remind) > cfg.remind_interval || !updated {
                     last_remind = now;
                     updated = true;
-                    let g_encrypt = crypt::StdAEAD::new(&cookie.generate_c2s().next().unwrap());
-                    if now.saturating_duration_since(last_reset).as_millis() > RESET_MILLIS {
+                    if now.saturating_duration_since(last_reset) > cfg.reset_interval {
                         last_reset = now;
-                        // also replace the UDP socket!
+                        // also replace the backhaul!
                         let old_socket = socket.clone();
                         let dn_crypter = dn_crypter.clone();
                         let send_frame_in = send_frame_in.clone();
-                        // spawn a task to clean up the UDP socket
+                        let traffic = traffic.clone();
+                        let paths = paths.clone();
+                        // spawn a task to drain and clean up the old backhaul
                         let tata: smol::Task<Option<()>> = runtime::spawn(
                             async move {
                                 loop {
-                                    let (buf, _) = old_socket.recv_from().await.ok()?;
-                                    if let Some(plain) =
-                                        dn_crypter.pad_decrypt::<msg::DataFrame>(&buf)
-                                    {
-                                        tracing::trace!(
-                                            "shard {} decrypted UDP message with len {}",
-                                            shard_id,
-                                            buf.len()
-                                        );
-                                        drop(send_frame_in.send(plain).await)
+                                    for (buf, _) in old_socket.recv_from_many().await.ok()? {
+                                        if let Some(plain) = dn_crypter
+                                            .decrypt(&buf)
+                                            .and_then(|plain| msg::DataFrame::from_plain(&plain))
+                                        {
+                                            tracing::trace!(
+                                                "shard {} decrypted UDP message with len {}",
+                                                shard_id,
+                                                buf.len()
+                                            );
+                                            traffic.received_datagram(buf.len());
+                                            if let (Some(paths), Some(header)) =
+                                                (&paths, &plain.path)
+                                            {
+                                                paths.received(shard_id, header);
+                                            }
+                                            drop(send_frame_in.send(plain).await)
+                                        }
                                     }
                                 }
                             }
@@ -252,34 +776,310 @@ async fn client_backhaul_once(
                         );
                         tata.detach();
                         socket = loop {
-                            match runtime::new_udp_socket_bind(laddr_gen().ok()?).await {
-                                Ok(sock) => break Arc::new(sock),
+                            match backhaul_gen(path).await {
+                                Ok(sock) => break sock,
                                 Err(err) => {
                                     tracing::warn!("error rebinding: {}", err);
                                     smol::Timer::after(Duration::from_secs(1)).await;
                                 }
                             }
                         };
+                        confirmed = resume_ack.is_none();
+                        unconfirmed.clear();
+                        resends = 0;
                     }
-                    drop(
-                        socket
-                            .send_to(
-                                g_encrypt.pad_encrypt(
-                                    msg::HandshakeFrame::ClientResume {
-                                        resume_token: resume_token.clone(),
-                                        shard_id,
-                                    },
-                                    1000,
-                                ),
-                                remote_addr,
-                            )
-                            .await,
-                    );
+                    drop(socket.send_to(client_resume(), remote_addr).await);
+                }
+                if !confirmed && unconfirmed.len() < UNCONFIRMED_LIMIT {
+                    if unconfirmed.is_empty() {
+                        next_resend = now + UNCONFIRMED_RESEND;
+                    }
+                    let room = UNCONFIRMED_LIMIT - unconfirmed.len();
+                    unconfirmed.extend(bts.iter().take(room).cloned());
+                }
+                for bts in bts.iter() {
+                    traffic.sent_datagram(bts.len());
                 }
                 let to_send: Vec<_> = bts.into_iter().map(|v| (v, remote_addr)).collect();
                 drop(socket.send_to_many(&to_send).await);
             }
+            Some(Evt::Resend) => {
+                resends += 1;
+                next_resend = Instant::now() + UNCONFIRMED_RESEND * 2u32.pow(resends);
+                tracing::debug!("shard {} unconfirmed, resending ClientResume", shard_id);
+                drop(socket.send_to(client_resume(), remote_addr).await);
+                for bts in unconfirmed.iter() {
+                    traffic.sent_datagram(bts.len());
+                }
+                let to_send: Vec<_> = unconfirmed
+                    .iter()
+                    .map(|v| (v.clone(), remote_addr))
+                    .collect();
+                drop(socket.send_to_many(&to_send).await);
+            }
             None => return None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn session_negotiates_version() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let (_listener, client, server) = sim_sessions(&net, Default::default()).await;
+            assert_eq!(client.version(), PROTOCOL_VERSION);
+            assert_eq!(server.version(), PROTOCOL_VERSION);
+            assert_eq!(
+                client.features(),
+                features::FEC_RAPTORQ
+                    | features::CIPHER_CHACHA20_POLY1305
+                    | features::MUX_V3
+                    | features::EXT_REKEY
+                    | features::EXT_NACK
+                    | features::EXT_MULTIPATH
+                    | features::EXT_CLOSE
+                    | features::EXT_RESUME_ACK
+                    | features::EXT_BURST_LEN
+            );
+            assert_eq!(server.features(), client.features());
+        })
+    }
+
+    #[test]
+    fn hybrid_and_classical_interoperate() {
+        smol::block_on(async {
+            // (client version, server version, negotiated version)
+            for &(client_version, server_version, expected) in &[
+                (4, 4, 4),
+                (4, 3, 3),
+                (3, 4, 3),
+                (3, 2, 2),
+                (2, 3, 2),
+                (4, 1, 1),
+            ] {
+                let net = SimNetwork::new(SimConfig::default());
+                let (_listener, client, server) = sim_sessions_versioned(
+                    &net,
+                    Default::default(),
+                    client_version,
+                    server_version,
+                )
+                .await;
+                assert_eq!(client.version(), expected);
+                assert_eq!(server.version(), expected);
+                // both sides must have derived the same keys
+                server.send_bytes(Bytes::from_static(b"world")).await;
+                assert_eq!(
+                    client.recv_bytes().await.unwrap(),
+                    Bytes::from_static(b"world")
+                );
+            }
+        })
+    }
+
+    /// Drops every hybrid hello that goes through it, like someone on the path who wants a classical handshake.
+    struct HybridHelloDropper {
+        inner: Arc<dyn Backhaul>,
+        cookie: crypt::Cookie,
+    }
+
+    #[async_trait::async_trait]
+    impl Backhaul for HybridHelloDropper {
+        async fn send_to(&self, to_send: Bytes, dest: SocketAddr) -> std::io::Result<()> {
+            let hybrid = self.cookie.generate_c2s().any(|key| {
+                matches!(
+                    crypt::StdAEAD::new(&key).pad_decrypt(&to_send),
+                    Some(msg::HandshakeFrame::ClientHelloV3 { .. })
+                        | Some(msg::HandshakeFrame::ClientHelloV4 { .. })
+                )
+            });
+            if hybrid {
+                return Ok(());
+            }
+            self.inner.send_to(to_send, dest).await
+        }
+
+        async fn recv_from(&self) -> std::io::Result<(Bytes, SocketAddr)> {
+            self.inner.recv_from().await
+        }
+
+        async fn recv_from_many(&self) -> std::io::Result<Vec<(Bytes, SocketAddr)>> {
+            self.inner.recv_from_many().await
+        }
+    }
+
+    #[test]
+    fn dropped_hybrid_hellos_dont_downgrade() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+            let pubkey: x25519_dalek::PublicKey = (&long_sk).into();
+            let _listener =
+                Listener::listen_with_backhaul(net.bind(server_addr()).unwrap(), long_sk);
+            let backhauls = sim_backhauls(&net, Default::default());
+            let connect = connect_paths(
+                server_addr(),
+                pubkey,
+                move |path| {
+                    let backhaul = backhauls(path);
+                    async move {
+                        let backhaul: Arc<dyn Backhaul> = Arc::new(HybridHelloDropper {
+                            inner: backhaul.await?,
+                            cookie: crypt::Cookie::new(pubkey),
+                        });
+                        Ok(backhaul)
+                    }
+                },
+                1,
+                PROTOCOL_VERSION,
+                ClientConfig::default(),
+            );
+            // the server answers the classical hellos that do get through, but those replies say it speaks a newer version
+            assert!(timeout(Duration::from_secs(5), connect).await.is_none());
+        })
+    }
+
+    #[test]
+    fn hybrid_can_be_required() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+            let _listener = Listener::listen_with_max_version(
+                net.bind(server_addr()).unwrap(),
+                long_sk.clone(),
+                2,
+            );
+            let connect = connect_paths(
+                server_addr(),
+                (&long_sk).into(),
+                sim_backhauls(&net, Default::default()),
+                1,
+                PROTOCOL_VERSION,
+                ClientConfig::default().require_hybrid(true),
+            );
+            assert!(timeout(Duration::from_secs(4), connect).await.is_none());
+        })
+    }
+
+    #[test]
+    fn acknowledged_resumes_send_nothing_again() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig {
+                latency: Duration::from_millis(20),
+                ..Default::default()
+            });
+            let (_listener, client, _server) = sim_sessions(&net, Default::default()).await;
+            // the server never sends anything, so only its acknowledgements stop the shards from sending these again
+            for _ in 0..200 {
+                client.send_bytes(Bytes::from(vec![0u8; 1000])).await;
+            }
+            smol::Timer::after(Duration::from_secs(1)).await;
+            let stats = client.get_stats().await.unwrap();
+            assert_eq!(stats.up_wire_packets, stats.up_packets);
+        })
+    }
+
+    #[test]
+    fn session_survives_rebinding() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let binds = Arc::new(AtomicUsize::new(0));
+            let (_listener, client, server) = sim_sessions(&net, binds.clone()).await;
+            let _echo = echo(server);
+            let recent =
+                ping_pong(&client, Duration::from_millis(10), Duration::from_secs(7)).await;
+            // one handshake backhaul, one per shard, and then at least one rebind
+            assert!(binds.load(Ordering::Relaxed) > 5);
+            assert!(recent > 50);
+        })
+    }
+
+    /// Opens a session over real UDP sockets from `connect` to a listener bound to `listen_addr`, and checks that a burst of packets makes it back and forth.
+    async fn loopback_session(listen_addr: &str, connect: impl Fn(SocketAddr) -> Vec<SocketAddr>) {
+        let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+        let listener = Listener::listen(listen_addr, long_sk.clone()).await;
+        let client = connect_happy_eyeballs(
+            connect(listener.local_addr()),
+            (&long_sk).into(),
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        client.send_bytes(Bytes::from_static(b"hello")).await;
+        let server = listener.accept_session().await.unwrap();
+        let _echo = echo(server);
+        let recent = ping_pong(&client, Duration::from_millis(2), Duration::from_secs(1)).await;
+        assert!(recent > 200);
+    }
+
+    #[test]
+    fn session_over_loopback() {
+        smol::block_on(async {
+            let port = |ip: &str| {
+                let ip: std::net::IpAddr = ip.parse().unwrap();
+                move |local: SocketAddr| vec![SocketAddr::new(ip, local.port())]
+            };
+            loopback_session("127.0.0.1:0", port("127.0.0.1")).await;
+            loopback_session("[::1]:0", port("::1")).await;
+            // dual-stack listeners see IPv4 clients at mapped addresses
+            loopback_session("[::0]:0", port("127.0.0.1")).await;
+            loopback_session("[::0]:0", port("::ffff:127.0.0.1")).await;
+            // an unreachable address goes first, as if the AAAA record were broken
+            loopback_session("127.0.0.1:0", |local| {
+                vec![
+                    SocketAddr::new("100::1".parse().unwrap(), local.port()),
+                    local,
+                ]
+            })
+            .await;
+        })
+    }
+
+    /// Pings over a client session with the given configuration for a few seconds. Returns how many backhauls the client bound, and how many pongs came back in the last second.
+    async fn count_binds(cfg: ClientConfig) -> (usize, usize) {
+        let net = SimNetwork::new(SimConfig::default());
+        let binds = Arc::new(AtomicUsize::new(0));
+        let (_listener, client, server) =
+            sim_sessions_with(&net, binds.clone(), PROTOCOL_VERSION, PROTOCOL_VERSION, cfg).await;
+        let _echo = echo(server);
+        let recent = ping_pong(&client, Duration::from_millis(10), Duration::from_secs(3)).await;
+        (binds.load(Ordering::Relaxed), recent)
+    }
+
+    #[test]
+    fn client_config_sets_shards() {
+        smol::block_on(async {
+            let (binds, recent) = count_binds(
+                ClientConfig::default()
+                    .shards(2)
+                    .reset_interval(Duration::from_secs(60)),
+            )
+            .await;
+            // one handshake backhaul and one per shard, which never rebind
+            assert_eq!(binds, 3);
+            assert!(recent > 50);
+        })
+    }
+
+    #[test]
+    fn client_config_bounds_intervals() {
+        smol::block_on(async {
+            let (binds, recent) = count_binds(
+                ClientConfig::default()
+                    .shards(1)
+                    .reset_interval(Duration::from_secs(0))
+                    .remind_interval(Duration::from_secs(0)),
+            )
+            .await;
+            // the shard hops about once a second, rather than with every frame
+            assert!(binds <= 6, "bound {} backhauls", binds);
+            assert!(recent > 50);
+        })
+    }
+}
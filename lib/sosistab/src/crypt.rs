@@ -1,13 +1,300 @@
+use crate::{TrafficShape as _, UniformShape};
 use bytes::{Bytes, BytesMut};
 use c2_chacha::stream_cipher::{NewStreamCipher, SyncStreamCipher};
 use c2_chacha::ChaCha12;
+use chacha20poly1305::aead::{Aead as _, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use parking_lot::RwLock;
+use pqcrypto_kyber::kyber768;
+use pqcrypto_traits::kem::{Ciphertext as _, PublicKey as _, SharedSecret as _};
 use rand::prelude::*;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::time::SystemTime;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 pub const UP_KEY: &[u8; 32] = b"upload--------------------------";
 pub const DN_KEY: &[u8; 32] = b"download------------------------";
+pub const RESUME_ACK_KEY: &[u8; 32] = b"resume-ack----------------------";
+
+/// An authenticated cipher suite. Handshakes always use [StdAEAD]; sessions use whichever suite the handshake settled on.
+pub trait Aead: std::fmt::Debug + Send + Sync + 'static {
+    /// Encrypts a message, given a nonce.
+    fn encrypt(&self, msg: &[u8], nonce: u128) -> Bytes;
+
+    /// Decrypts a message. Returns None if there's an error. Intentionally does not discriminate between different errors to limit possible side channels.
+    fn decrypt(&self, msg: &[u8]) -> Option<Bytes>;
+
+    /// Which key epoch the cipher is at. Ciphers that never rekey stay at zero.
+    fn epoch(&self) -> u64 {
+        0
+    }
+}
+
+/// Padding on top of any [Aead].
+pub trait AeadExt: Aead {
+    /// Pad and encrypt.
+    #[tracing::instrument(skip(self, msg))]
+    fn pad_encrypt(&self, msg: impl Serialize, target_len: usize) -> Bytes {
+        let shape = UniformShape {
+            max_len: target_len,
+        };
+        self.pad_encrypt_with(msg, |len| shape.padded_len(len))
+    }
+
+    /// Pad to whatever length `pick_len` chooses given the unpadded length, and encrypt.
+    fn pad_encrypt_with(
+        &self,
+        msg: impl Serialize,
+        pick_len: impl FnOnce(usize) -> usize,
+    ) -> Bytes {
+        let mut plain = Vec::with_capacity(1500);
+        bincode::serialize_into(&mut plain, &msg).unwrap();
+        let plainlen = plain.len();
+        let target_len = pick_len(plainlen).max(plainlen);
+        plain.resize(target_len, 0);
+        let encrypted = self.encrypt(&plain, rand::thread_rng().gen());
+        tracing::trace!("PAD and ENCRYPT {} => {}", plainlen, encrypted.len());
+        encrypted
+    }
+
+    /// Decrypt and depad.
+    #[tracing::instrument(skip(self))]
+    fn pad_decrypt<T: DeserializeOwned>(&self, ctext: &[u8]) -> Option<T> {
+        let plain = self.decrypt(ctext)?;
+        bincode::deserialize_from(plain.as_ref()).ok()
+    }
+}
+
+impl<A: Aead + ?Sized> AeadExt for A {}
+
+/// Creates the session cipher that a negotiated feature set calls for. Ratcheting ciphers move to a new key every `rekey_frames` frames, normally [REKEY_FRAMES].
+pub fn session_aead(features: u64, key: &[u8], rekey_frames: u64) -> Arc<dyn Aead> {
+    if features & crate::features::EXT_REKEY != 0 {
+        Arc::new(RatchetAEAD::new(features, key, rekey_frames))
+    } else {
+        suite_aead(features, key)
+    }
+}
+
+/// Like [session_aead], but picks up a session whose ratchet is already at `epoch`, as when a listener that has never seen the session takes it over. Returns None if the epoch is past [MAX_RESUME_EPOCH].
+pub fn resumed_session_aead(
+    features: u64,
+    key: &[u8],
+    epoch: u64,
+    rekey_frames: u64,
+) -> Option<Arc<dyn Aead>> {
+    if features & crate::features::EXT_REKEY != 0 {
+        Some(Arc::new(RatchetAEAD::at_epoch(
+            features,
+            key,
+            epoch,
+            rekey_frames,
+        )?))
+    } else {
+        Some(suite_aead(features, key))
+    }
+}
+
+fn suite_aead(features: u64, key: &[u8]) -> Arc<dyn Aead> {
+    if features & crate::features::CIPHER_CHACHA20_POLY1305 != 0 {
+        Arc::new(ChaChaPolyAEAD::new(key))
+    } else {
+        Arc::new(StdAEAD::new(key))
+    }
+}
+
+/// Frames sent under one key before the sender ratchets, unless the session says otherwise.
+pub const REKEY_FRAMES: u64 = 1 << 16;
+/// Time after which the sender ratchets, provided it has sent at least [REKEY_MIN_FRAMES] under the current key.
+const REKEY_INTERVAL: Duration = Duration::from_secs(120);
+/// Frames the sender must send under a key before ratcheting on time alone, so that the receiver almost certainly sees the new key before the one after it.
+const REKEY_MIN_FRAMES: u64 = 100;
+/// How long the receiver keeps the previous key around for stragglers.
+const REKEY_GRACE: Duration = Duration::from_secs(30);
+/// How many epochs past its own the receiver follows a frame's epoch. Every epoch skipped costs a hash, so this bounds the work a forged frame can cause, while still covering outages of hours at the time-based rekey rate.
+const MAX_EPOCH_SKIP: u64 = 256;
+/// The furthest epoch a resumed ratchet starts at. Getting there costs a hash per epoch, and it's months of rekeying at the time-based rate.
+pub const MAX_RESUME_EPOCH: u64 = 1 << 16;
+
+/// Wraps a session cipher so that the key ratchets forward through BLAKE3 every so often. Old keys are forgotten once the grace window passes, so compromising the current key doesn't expose past traffic.
+///
+/// Every frame ends with the 32-bit epoch of its key, masked with a hash of the ciphertext so that it looks as random as the rest. The receiver decrypts each frame under that epoch's key only: the current one, the previous one while it's within the grace window, or a later one up to [MAX_EPOCH_SKIP] epochs ahead, which it then follows. Each direction must have exactly one sending instance.
+pub struct RatchetAEAD {
+    features: u64,
+    mask_key: [u8; 32],
+    rekey_frames: u64,
+    rekey_interval: Duration,
+    grace: Duration,
+    state: RwLock<RatchetState>,
+}
+
+struct RatchetState {
+    epoch: u64,
+    secret: [u8; 32],
+    current: Arc<dyn Aead>,
+    previous: Option<(Arc<dyn Aead>, Instant)>,
+    epoch_start: Instant,
+    sent: u64,
+}
+
+impl std::fmt::Debug for RatchetAEAD {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RatchetAEAD(epoch {})", self.state.read().epoch)
+    }
+}
+
+impl RatchetAEAD {
+    /// Starts a ratchet from the session key, which sends `rekey_frames` frames under each key.
+    pub fn new(features: u64, key: &[u8], rekey_frames: u64) -> Self {
+        Self::at_epoch(features, key, 0, rekey_frames).expect("epoch 0 is always reachable")
+    }
+
+    /// Starts a ratchet from the session key, already moved forward to `epoch`. Returns None if the epoch is past [MAX_RESUME_EPOCH].
+    pub fn at_epoch(features: u64, key: &[u8], epoch: u64, rekey_frames: u64) -> Option<Self> {
+        if epoch > MAX_RESUME_EPOCH {
+            return None;
+        }
+        let mut secret = *blake3::keyed_hash(b"ratchet-------------------------", key).as_bytes();
+        for _ in 0..epoch {
+            secret = ratchet(&secret);
+        }
+        Some(RatchetAEAD {
+            features,
+            mask_key: *blake3::keyed_hash(b"ratchet-epoch-mask--------------", key).as_bytes(),
+            rekey_frames,
+            rekey_interval: REKEY_INTERVAL,
+            grace: REKEY_GRACE,
+            state: RwLock::new(RatchetState {
+                epoch,
+                secret,
+                current: suite_aead(features, &secret),
+                previous: None,
+                epoch_start: Instant::now(),
+                sent: 0,
+            }),
+        })
+    }
+
+    /// Moves the ratchet forward to `epoch`, whose secret is `secret`. The key of the epoch before it stays around for the grace window.
+    fn advance(
+        &self,
+        state: &mut RatchetState,
+        epoch: u64,
+        secret: [u8; 32],
+        previous: Arc<dyn Aead>,
+    ) {
+        let now = Instant::now();
+        // overwriting the secret is what makes the ratchet forward-secret
+        state.secret = secret;
+        state.current = suite_aead(self.features, &secret);
+        state.previous = Some((previous, now + self.grace));
+        state.epoch = epoch;
+        state.epoch_start = now;
+        state.sent = 0;
+        tracing::debug!("ratcheted to epoch {}", epoch);
+    }
+
+    /// The mask for the epoch trailing a ciphertext, which depends on the ciphertext's random-looking tail.
+    fn epoch_mask(&self, ctext: &[u8]) -> u32 {
+        let tail = &ctext[ctext.len().saturating_sub(16)..];
+        let mask = blake3::keyed_hash(&self.mask_key, tail);
+        u32::from_le_bytes([
+            mask.as_bytes()[0],
+            mask.as_bytes()[1],
+            mask.as_bytes()[2],
+            mask.as_bytes()[3],
+        ])
+    }
+}
+
+impl Aead for RatchetAEAD {
+    fn encrypt(&self, msg: &[u8], nonce: u128) -> Bytes {
+        let mut state = self.state.write();
+        if state.sent >= self.rekey_frames
+            || (state.sent >= REKEY_MIN_FRAMES
+                && state.epoch_start.elapsed() >= self.rekey_interval)
+        {
+            let secret = ratchet(&state.secret);
+            let previous = state.current.clone();
+            let epoch = state.epoch + 1;
+            self.advance(&mut state, epoch, secret, previous);
+        }
+        if matches!(&state.previous, Some((_, expiry)) if Instant::now() >= *expiry) {
+            state.previous = None;
+        }
+        state.sent += 1;
+        let ctext = state.current.encrypt(msg, nonce);
+        let hint = (state.epoch as u32) ^ self.epoch_mask(&ctext);
+        let mut out = BytesMut::with_capacity(ctext.len() + 4);
+        out.extend_from_slice(&ctext);
+        out.extend_from_slice(&hint.to_le_bytes());
+        out.freeze()
+    }
+
+    fn decrypt(&self, msg: &[u8]) -> Option<Bytes> {
+        if msg.len() < 4 {
+            return None;
+        }
+        let (ctext, hint) = msg.split_at(msg.len() - 4);
+        let hint = u32::from_le_bytes([hint[0], hint[1], hint[2], hint[3]]);
+        let wire_epoch = hint ^ self.epoch_mask(ctext);
+        {
+            let state = self.state.read();
+            let current = state.epoch as u32;
+            if wire_epoch == current {
+                let plain = state.current.decrypt(ctext);
+                let stale =
+                    matches!(&state.previous, Some((_, expiry)) if Instant::now() >= *expiry);
+                drop(state);
+                if stale {
+                    self.state.write().previous = None;
+                }
+                return plain;
+            }
+            if wire_epoch == current.wrapping_sub(1) {
+                return match &state.previous {
+                    Some((previous, expiry)) if Instant::now() < *expiry => previous.decrypt(ctext),
+                    _ => None,
+                };
+            }
+        }
+        let mut state = self.state.write();
+        // someone else may have followed the sender forward in the meantime
+        let skip = wire_epoch.wrapping_sub(state.epoch as u32) as u64;
+        if skip == 0 {
+            return state.current.decrypt(ctext);
+        }
+        if skip > MAX_EPOCH_SKIP {
+            return None;
+        }
+        let mut previous_secret = state.secret;
+        for _ in 1..skip {
+            previous_secret = ratchet(&previous_secret);
+        }
+        let secret = ratchet(&previous_secret);
+        let plain = suite_aead(self.features, &secret).decrypt(ctext)?;
+        let previous = if skip == 1 {
+            state.current.clone()
+        } else {
+            suite_aead(self.features, &previous_secret)
+        };
+        let epoch = state.epoch + skip;
+        self.advance(&mut state, epoch, secret, previous);
+        Some(plain)
+    }
+
+    fn epoch(&self) -> u64 {
+        self.state.read().epoch
+    }
+}
+
+fn ratchet(secret: &[u8; 32]) -> [u8; 32] {
+    *blake3::keyed_hash(secret, b"sosistab-rekey").as_bytes()
+}
+
 /// A structure for encrypting or decrypting Chacha12/Blake3-64.
 #[derive(Debug)]
 pub struct StdAEAD {
@@ -26,10 +313,11 @@ impl StdAEAD {
             blake3_key: blake3_key.as_bytes().to_owned(),
         }
     }
+}
 
-    /// Encrypts a message, given a nonce.
+impl Aead for StdAEAD {
     #[tracing::instrument]
-    pub fn encrypt(&self, msg: &[u8], nonce: u128) -> Bytes {
+    fn encrypt(&self, msg: &[u8], nonce: u128) -> Bytes {
         // overwrite first 128 bits of key
         let mut chacha_key = self.chacha_key;
         let mut blake3_key = self.blake3_key;
@@ -49,9 +337,8 @@ impl StdAEAD {
         out_space.freeze()
     }
 
-    /// Decrypts a message. Returns None if there's an error. Intentionally does not discriminate between different errors to limit possible side channels.
     #[tracing::instrument]
-    pub fn decrypt(&self, msg: &[u8]) -> Option<Bytes> {
+    fn decrypt(&self, msg: &[u8]) -> Option<Bytes> {
         if msg.len() < 24 {
             return None;
         }
@@ -76,28 +363,61 @@ impl StdAEAD {
         chacha.apply_keystream(&mut out_space);
         Some(out_space.freeze())
     }
+}
 
-    /// Pad and encrypt.
-    #[tracing::instrument(skip(msg))]
-    pub fn pad_encrypt(&self, msg: impl Serialize, target_len: usize) -> Bytes {
-        let mut target_len = rand::thread_rng().gen_range(0, target_len + 1);
-        let mut plain = Vec::with_capacity(1500);
-        bincode::serialize_into(&mut plain, &msg).unwrap();
-        let plainlen = plain.len();
-        if plain.len() > target_len {
-            target_len = plain.len() + rand::thread_rng().gen_range(0, 16);
+/// Standard ChaCha20-Poly1305, with a random 96-bit nonce appended to every message.
+pub struct ChaChaPolyAEAD {
+    cipher: ChaCha20Poly1305,
+}
+
+impl std::fmt::Debug for ChaChaPolyAEAD {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ChaChaPolyAEAD")
+    }
+}
+
+impl ChaChaPolyAEAD {
+    /// New ChaCha20-Poly1305 aead given a key.
+    pub fn new(key: &[u8]) -> Self {
+        Self::with_raw_key(&chachapoly_key(key))
+    }
+
+    /// New ChaCha20-Poly1305 aead that uses the given key as is.
+    fn with_raw_key(key: &[u8; 32]) -> Self {
+        ChaChaPolyAEAD {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
         }
-        plain.extend_from_slice(&vec![0; target_len - plain.len()]);
-        let encrypted = self.encrypt(&plain, rand::thread_rng().gen());
-        tracing::trace!("PAD and ENCRYPT {} => {}", plainlen, encrypted.len());
-        encrypted
     }
+}
 
-    /// Decrypt and depad.
+/// Derives the ChaCha20-Poly1305 key from a session key.
+fn chachapoly_key(key: &[u8]) -> [u8; 32] {
+    *blake3::keyed_hash(b"chacha20poly1305----------------", key).as_bytes()
+}
+
+impl Aead for ChaChaPolyAEAD {
     #[tracing::instrument]
-    pub fn pad_decrypt<T: DeserializeOwned>(&self, ctext: &[u8]) -> Option<T> {
-        let plain = self.decrypt(ctext)?;
-        bincode::deserialize_from(plain.as_ref()).ok()
+    fn encrypt(&self, msg: &[u8], nonce: u128) -> Bytes {
+        let nonce = &nonce.to_le_bytes()[..12];
+        let mut out_space = self
+            .cipher
+            .encrypt(Nonce::from_slice(nonce), msg)
+            .expect("can't encrypt with chacha20poly1305");
+        out_space.extend_from_slice(nonce);
+        out_space.into()
+    }
+
+    #[tracing::instrument]
+    fn decrypt(&self, msg: &[u8]) -> Option<Bytes> {
+        if msg.len() < 28 {
+            return None;
+        }
+        let (ciphertext, nonce) = msg.split_at(msg.len() - 12);
+        let plain = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()?;
+        Some(plain.into())
     }
 }
 
@@ -200,6 +520,51 @@ fn curr_epoch() -> u64 {
         / 60
 }
 
+/// Generates an ephemeral KEM keypair for a hybrid handshake, returning the encoded encapsulation key.
+pub fn kem_keypair() -> (Bytes, kyber768::SecretKey) {
+    let (pk, sk) = kyber768::keypair();
+    (Bytes::copy_from_slice(pk.as_bytes()), sk)
+}
+
+/// Encapsulates a fresh secret to an encoded encapsulation key, returning the encoded ciphertext and the secret. Returns None if the key is malformed.
+pub fn kem_encapsulate(kem_pk: &[u8]) -> Option<(Bytes, Vec<u8>)> {
+    let kem_pk = kyber768::PublicKey::from_bytes(kem_pk).ok()?;
+    let (secret, ct) = kyber768::encapsulate(&kem_pk);
+    Some((
+        Bytes::copy_from_slice(ct.as_bytes()),
+        secret.as_bytes().to_vec(),
+    ))
+}
+
+/// Recovers the secret from an encoded ciphertext. Returns None if the ciphertext is malformed.
+pub fn kem_decapsulate(kem_ct: &[u8], kem_sk: &kyber768::SecretKey) -> Option<Vec<u8>> {
+    let kem_ct = kyber768::Ciphertext::from_bytes(kem_ct).ok()?;
+    Some(kyber768::decapsulate(&kem_ct, kem_sk).as_bytes().to_vec())
+}
+
+/// Binds what both sides of a handshake said they speak into the session secret. Someone who tampers with those offers to push the session down to an older version then leaves the two ends with different keys, instead of with a weaker session.
+pub fn bind_offers(
+    secret: blake3::Hash,
+    client_max_version: u64,
+    client_features: u64,
+    server_max_version: u64,
+) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_derive_key("sosistab-1 offer-bound session secret");
+    hasher.update(secret.as_bytes());
+    hasher.update(&client_max_version.to_le_bytes());
+    hasher.update(&client_features.to_le_bytes());
+    hasher.update(&server_max_version.to_le_bytes());
+    hasher.finalize()
+}
+
+/// Combines the classical and post-quantum secrets of a hybrid handshake. The result is safe as long as either one is.
+pub fn hybrid_secret(classical: blake3::Hash, kem_secret: &[u8]) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_derive_key("sosistab-1 hybrid session secret");
+    hasher.update(classical.as_bytes());
+    hasher.update(kem_secret);
+    hasher.finalize()
+}
+
 #[tracing::instrument(skip(my_long_sk, my_eph_sk))]
 pub fn triple_ecdh(
     my_long_sk: &x25519_dalek::StaticSecret,
@@ -224,3 +589,187 @@ pub fn triple_ecdh(
     };
     blake3::hash(&to_hash)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chacha20poly1305::aead::Payload;
+    use std::convert::TryInto;
+
+    const KAT_KEY: &[u8] = b"sosistab known-answer key";
+    const KAT_NONCE: u128 = 0x0011_2233_4455_6677_8899_aabb_ccdd_eeff;
+    const KAT_PLAIN: &[u8] = b"attack at dawn";
+
+    /// Checks a suite against a fixed ciphertext, and that it rejects any tampering.
+    fn check_kat(aead: &dyn Aead, expected: &str) {
+        let ctext = aead.encrypt(KAT_PLAIN, KAT_NONCE);
+        assert_eq!(hex::encode(&ctext), expected);
+        assert_eq!(aead.decrypt(&ctext).unwrap().as_ref(), KAT_PLAIN);
+        check_tampering(aead, &ctext);
+    }
+
+    /// Checks that a suite rejects every flipped bit and every truncation of a ciphertext.
+    fn check_tampering(aead: &dyn Aead, ctext: &[u8]) {
+        for i in 0..ctext.len() {
+            let mut tampered = ctext.to_vec();
+            tampered[i] ^= 1;
+            assert!(aead.decrypt(&tampered).is_none());
+        }
+        assert!(aead.decrypt(&ctext[..ctext.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn stdaead_kat() {
+        // with all-zero keys and nonce, the body is the bare ChaCha12 keystream: test case 1 of draft-strombergson-chacha-test-vectors
+        let aead = StdAEAD {
+            chacha_key: [0; 32],
+            blake3_key: [0; 32],
+        };
+        let ctext = aead.encrypt(&[0; 64], 0);
+        assert_eq!(
+            hex::encode(&ctext[..64]),
+            "9bf49a6a0755f953811fce125f2683d50429c3bb49e074147e0089a52eae155f0564f879d27ae3c02ce82834acfa8c793a629f2ca0de6919610be82f411326be"
+        );
+        assert_eq!(aead.decrypt(&ctext).unwrap().as_ref(), &[0; 64][..]);
+        // with a nonce that completes the MAC key to the one in the official BLAKE3 test vectors, an empty message is tagged with the start of the keyed_hash vector for empty input
+        let key = *b"whats the Elvish word for friend";
+        let aead = StdAEAD {
+            chacha_key: [0; 32],
+            blake3_key: key,
+        };
+        let ctext = aead.encrypt(b"", u128::from_le_bytes(key[..16].try_into().unwrap()));
+        assert_eq!(
+            hex::encode(&ctext),
+            format!("{}92b2b75604ed3c76", hex::encode(&key[..16]))
+        );
+        assert_eq!(aead.decrypt(&ctext).unwrap().as_ref(), b"");
+        // both keys are derived from the session key
+        let aead = StdAEAD::new(KAT_KEY);
+        assert_eq!(
+            aead.chacha_key,
+            *blake3::keyed_hash(b"enc-----------------------------", KAT_KEY).as_bytes()
+        );
+        assert_eq!(
+            aead.blake3_key,
+            *blake3::keyed_hash(b"mac-----------------------------", KAT_KEY).as_bytes()
+        );
+        let ctext = aead.encrypt(KAT_PLAIN, KAT_NONCE);
+        assert_eq!(aead.decrypt(&ctext).unwrap().as_ref(), KAT_PLAIN);
+        check_tampering(&aead, &ctext);
+    }
+
+    #[test]
+    fn chachapoly_kat() {
+        // RFC 8439, section 2.8.2
+        let key: Vec<u8> = (0x80..0xa0).collect();
+        let aead = ChaChaPolyAEAD::with_raw_key(key.as_slice().try_into().unwrap());
+        let nonce = hex::decode("070000004041424344454647").unwrap();
+        let plain: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+        let payload = Payload {
+            msg: plain,
+            aad: &hex::decode("50515253c0c1c2c3c4c5c6c7").unwrap(),
+        };
+        let ctext = aead
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .unwrap();
+        assert_eq!(
+            hex::encode(&ctext),
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d63dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b3692ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc3ff4def08e4b7a9de576d26586cec64b6116\
+             1ae10b594f09e26a7e902ecbd0600691"
+        );
+        // the wire format is the same cipher with no associated data and the nonce appended
+        let ctext = aead.encrypt(plain, 0x4746_4544_4342_4140_0000_0007);
+        assert_eq!(&ctext[ctext.len() - 12..], nonce.as_slice());
+        assert_eq!(
+            aead.cipher
+                .decrypt(Nonce::from_slice(&nonce), &ctext[..ctext.len() - 12])
+                .unwrap(),
+            plain
+        );
+        assert_eq!(aead.decrypt(&ctext).unwrap().as_ref(), plain);
+    }
+
+    #[test]
+    fn chachapoly_key_derivation() {
+        // the keyed_hash vector for empty input from the official BLAKE3 test vectors
+        assert_eq!(
+            blake3::keyed_hash(b"whats the Elvish word for friend", b"")
+                .to_hex()
+                .as_str(),
+            "92b2b75604ed3c761f9d6f62392c8a9227ad0ea3f09573e783f1498a4ed60d26"
+        );
+        assert_eq!(
+            chachapoly_key(KAT_KEY),
+            *blake3::keyed_hash(b"chacha20poly1305----------------", KAT_KEY).as_bytes()
+        );
+        // sessions get the derived key, not the session key
+        let ctext = ChaChaPolyAEAD::new(KAT_KEY).encrypt(KAT_PLAIN, KAT_NONCE);
+        assert!(ChaChaPolyAEAD::with_raw_key(&chachapoly_key(KAT_KEY))
+            .decrypt(&ctext)
+            .is_some());
+        // pins the whole session wire format, so that changes to it don't slip through
+        check_kat(
+            &ChaChaPolyAEAD::new(KAT_KEY),
+            "04a01f7c9b2feb84775b607aa5aa41dbcff3d0e939df8fd39a6e0a254588ffeeddccbbaa998877665544",
+        );
+    }
+
+    #[test]
+    fn ratchet_follows_sender() {
+        let features = crate::features::SUPPORTED;
+        let sender = RatchetAEAD::new(features, KAT_KEY, 10);
+        let mut receiver = RatchetAEAD::new(features, KAT_KEY, REKEY_FRAMES);
+        receiver.grace = Duration::from_millis(100);
+        let ctexts: Vec<Bytes> = (0..35u128).map(|i| sender.encrypt(KAT_PLAIN, i)).collect();
+        assert_eq!(sender.state.read().epoch, 3);
+        for ctext in &ctexts {
+            assert_eq!(receiver.decrypt(ctext).unwrap().as_ref(), KAT_PLAIN);
+        }
+        assert_eq!(receiver.state.read().epoch, 3);
+        // stragglers from the previous key are fine for a while, but older keys are already gone
+        assert!(receiver.decrypt(&ctexts[29]).is_some());
+        assert!(receiver.decrypt(&ctexts[19]).is_none());
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(receiver.decrypt(&ctexts[29]).is_none());
+        assert!(receiver.decrypt(&ctexts[34]).is_some());
+    }
+
+    #[test]
+    fn ratchet_skips_ahead() {
+        let features = crate::features::SUPPORTED;
+        let sender = RatchetAEAD::new(features, KAT_KEY, 1);
+        let receiver = RatchetAEAD::new(features, KAT_KEY, REKEY_FRAMES);
+        let ctexts: Vec<Bytes> = (0..400u128).map(|i| sender.encrypt(KAT_PLAIN, i)).collect();
+        // the epoch is masked, so frames of one epoch don't share a tail
+        let fresh = RatchetAEAD::new(features, KAT_KEY, REKEY_FRAMES);
+        let (first, second) = (fresh.encrypt(KAT_PLAIN, 1), fresh.encrypt(KAT_PLAIN, 2));
+        assert_ne!(first[first.len() - 4..], second[second.len() - 4..]);
+        // a frame with its epoch tampered with is tried under no key at all
+        let mut tampered = ctexts[100].to_vec();
+        let len = tampered.len();
+        tampered[len - 1] ^= 1;
+        assert!(receiver.decrypt(&tampered).is_none());
+        assert_eq!(receiver.state.read().epoch, 0);
+        // everything in between went missing
+        assert_eq!(receiver.decrypt(&ctexts[100]).unwrap().as_ref(), KAT_PLAIN);
+        assert_eq!(receiver.state.read().epoch, 100);
+        assert!(receiver.decrypt(&ctexts[99]).is_some());
+        // but not without limit
+        assert!(receiver
+            .decrypt(&ctexts[100 + MAX_EPOCH_SKIP as usize + 1])
+            .is_none());
+        assert!(receiver
+            .decrypt(&ctexts[100 + MAX_EPOCH_SKIP as usize])
+            .is_some());
+    }
+
+    #[test]
+    fn session_aead_follows_features() {
+        let std = session_aead(crate::features::LEGACY, KAT_KEY, REKEY_FRAMES);
+        let chachapoly = session_aead(crate::features::SUPPORTED, KAT_KEY, REKEY_FRAMES);
+        let ctext = chachapoly.pad_encrypt(42u64, 100);
+        assert_eq!(chachapoly.pad_decrypt::<u64>(&ctext), Some(42));
+        assert!(std.pad_decrypt::<u64>(&ctext).is_none());
+    }
+}
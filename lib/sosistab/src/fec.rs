@@ -1,34 +1,147 @@
 use bytes::{Bytes, BytesMut};
 use once_cell::sync::Lazy;
 use probability::distribution::Distribution;
+use raptorq::{
+    extended_source_block_symbols, EncodingPacket, ObjectTransmissionInformation, PayloadId,
+    SourceBlockDecoder, SourceBlockEncoder,
+};
 use reed_solomon_erasure::galois_8;
 use std::convert::TryInto;
 use std::{collections::HashMap, sync::Arc};
+
+/// A systematic erasure code over runs of equal-length shards: the data shards go out as they are, followed by parity shards computed from them.
+pub trait ErasureCode: std::fmt::Debug + Send + Sync + 'static {
+    /// Most data shards worth putting into one run.
+    fn max_run_len(&self) -> usize;
+
+    /// Fills in the parity shards, which follow the first `data_shards` shards.
+    fn encode(&self, data_shards: usize, shards: &mut [&mut [u8]]);
+
+    /// Fills in the missing data shards from the shards marked present. Returns false if those aren't enough.
+    fn reconstruct(&self, data_shards: usize, shards: &mut [(&mut [u8], bool)]) -> bool;
+}
+
+/// Creates the erasure code that a negotiated feature set calls for.
+pub fn erasure_code(features: u64) -> Arc<dyn ErasureCode> {
+    if features & crate::features::FEC_RAPTORQ != 0 {
+        Arc::new(RaptorQ)
+    } else {
+        Arc::new(ReedSolomon)
+    }
+}
+
+/// Reed-Solomon over GF(2^8). Any shards of a run, as many as it has data shards, recover it.
+#[derive(Debug)]
+pub struct ReedSolomon;
+
+impl ErasureCode for ReedSolomon {
+    fn max_run_len(&self) -> usize {
+        64
+    }
+
+    fn encode(&self, data_shards: usize, shards: &mut [&mut [u8]]) {
+        rs_codec(data_shards, shards.len() - data_shards)
+            .encode(shards)
+            .expect("can't encode")
+    }
+
+    fn reconstruct(&self, data_shards: usize, shards: &mut [(&mut [u8], bool)]) -> bool {
+        if shards.len() > 128 {
+            return false;
+        }
+        rs_codec(data_shards, shards.len() - data_shards)
+            .reconstruct(shards)
+            .is_ok()
+    }
+}
+
+/// RaptorQ (RFC 6330), where parity shards are repair symbols. Unlike Reed-Solomon, encoding stays cheap for long runs, but now and then a run needs a shard or two more than it has data shards.
+#[derive(Debug)]
+pub struct RaptorQ;
+
+impl RaptorQ {
+    fn config(symbol_size: usize) -> ObjectTransmissionInformation {
+        let symbol_size = symbol_size.try_into().expect("shard too long for RaptorQ");
+        ObjectTransmissionInformation::new(0, symbol_size, 0, 1, 1)
+    }
+}
+
+impl ErasureCode for RaptorQ {
+    fn max_run_len(&self) -> usize {
+        128
+    }
+
+    fn encode(&self, data_shards: usize, shards: &mut [&mut [u8]]) {
+        let symbol_size = shards[0].len();
+        let encoder = SourceBlockEncoder::new2(
+            0,
+            &Self::config(symbol_size),
+            &shards[..data_shards].concat(),
+        );
+        let repair = encoder.repair_packets(0, (shards.len() - data_shards) as u32);
+        for (shard, packet) in shards[data_shards..].iter_mut().zip(repair) {
+            shard.copy_from_slice(packet.data());
+        }
+    }
+
+    fn reconstruct(&self, data_shards: usize, shards: &mut [(&mut [u8], bool)]) -> bool {
+        let symbol_size = shards[0].0.len();
+        let mut decoder = SourceBlockDecoder::new2(
+            0,
+            &Self::config(symbol_size),
+            (symbol_size * data_shards) as u64,
+        );
+        // repair symbols are numbered after the padding that extends the source block
+        let first_repair = extended_source_block_symbols(data_shards as u32);
+        let packets = shards
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, present))| *present)
+            .map(|(idx, (shard, _))| {
+                let esi = if idx < data_shards {
+                    idx as u32
+                } else {
+                    first_repair + (idx - data_shards) as u32
+                };
+                EncodingPacket::new(PayloadId::new(0, esi), shard.to_vec())
+            });
+        let block = match decoder.decode(packets) {
+            Some(block) => block,
+            None => return false,
+        };
+        for ((shard, present), symbol) in shards.iter_mut().zip(block.chunks(symbol_size)) {
+            if !*present {
+                shard.copy_from_slice(symbol);
+            }
+        }
+        true
+    }
+}
+
 /// A forward error correction encoder. Retains internal state for memoization, memory pooling etc.
 #[derive(Debug)]
 pub struct FrameEncoder {
-    // table mapping current loss in pct + run length => overhead
-    rate_table: HashMap<(u8, usize), usize>,
+    // table mapping current loss in pct + burst length + run length => overhead
+    rate_table: HashMap<(u8, u8, usize), usize>,
     // target loss rate
     target_loss: u8,
-    // encoder pool
-    rs_encoders: HashMap<(usize, usize), galois_8::ReedSolomon>,
+    code: Arc<dyn ErasureCode>,
 }
 
 impl FrameEncoder {
-    /// Creates a new Encoder at the given loss level.
+    /// Creates a new Encoder for the given erasure code at the given loss level.
     #[tracing::instrument]
-    pub fn new(target_loss: u8) -> Self {
+    pub fn new(code: Arc<dyn ErasureCode>, target_loss: u8) -> Self {
         FrameEncoder {
             rate_table: HashMap::new(),
             target_loss,
-            rs_encoders: HashMap::new(),
+            code,
         }
     }
 
-    /// Encodes a slice of packets into more packets.
+    /// Encodes a slice of packets into more packets. The burst length is the mean length of loss bursts in sixteenths of a packet, or zero if unknown, in which case losses are taken to be independent.
     #[tracing::instrument]
-    pub fn encode(&mut self, measured_loss: u8, pkts: &[Bytes]) -> Vec<Bytes> {
+    pub fn encode(&mut self, measured_loss: u8, burst_len: u8, pkts: &[Bytes]) -> Vec<Bytes> {
         // max length
         let max_length = pkts.iter().map(|v| v.len()).max().unwrap();
         // first we precode the packets
@@ -36,7 +149,7 @@ impl FrameEncoder {
             pkts.iter().map(|p| pre_encode(p, max_length + 2)).collect();
         // then we get an encoder for this size
         let data_shards = pkts.len();
-        let parity_shards = self.repair_len(measured_loss, pkts.len());
+        let parity_shards = self.repair_len(measured_loss, burst_len, pkts.len());
         // then we encode
         // prepare the space for in-place mutation
         let mut parity_shard_space = vec![vec![0u8; max_length + 2]; parity_shards];
@@ -51,15 +164,7 @@ impl FrameEncoder {
             parity_shards
         );
         if parity_shards > 0 {
-            let encoder = self
-                .rs_encoders
-                .entry((data_shards, parity_shards))
-                .or_insert_with(|| {
-                    galois_8::ReedSolomon::new(data_shards, parity_shards)
-                        .expect("didn't successfully construct RS encoder")
-                });
-            // do the encoding
-            encoder.encode(&mut padded_pkts).expect("can't encode");
+            self.code.encode(data_shards, &mut padded_pkts);
         }
         // return
         let mut toret = Vec::with_capacity(data_shards + parity_shards);
@@ -68,12 +173,20 @@ impl FrameEncoder {
     }
 
     /// Calculates the number of repair blocks needed to properly reconstruct a run of packets.
-    fn repair_len(&mut self, measured_loss: u8, run_len: usize) -> usize {
+    fn repair_len(&mut self, measured_loss: u8, burst_len: u8, run_len: usize) -> usize {
         let target_loss = self.target_loss;
         (*self
             .rate_table
-            .entry((measured_loss, run_len))
+            .entry((measured_loss, burst_len, run_len))
             .or_insert_with(|| {
+                if burst_len > 0 && measured_loss > 0 {
+                    return burst_repair_len(
+                        measured_loss as f64 / 256.0,
+                        burst_len as f64 / 16.0,
+                        target_loss as f64 / 256.0,
+                        run_len,
+                    );
+                }
                 for additional_len in 0.. {
                     let distro = probability::distribution::Binomial::with_failure(
                         run_len + additional_len,
@@ -90,6 +203,36 @@ impl FrameEncoder {
     }
 }
 
+/// Calculates the number of repair blocks needed under a Gilbert model of loss, where the link alternates between a good state that loses nothing and a bad state that loses everything. The stationary loss rate and the mean burst length pin down its two transition probabilities.
+fn burst_repair_len(loss: f64, burst_len: f64, target_loss: f64, run_len: usize) -> usize {
+    let loss = loss.clamp(1e-6, 1.0 - 1e-6);
+    let bad_to_good = 1.0 / burst_len.max(1.0);
+    let good_to_bad = (loss * bad_to_good / (1.0 - loss)).min(1.0);
+    // dist[state][lost] is the probability of being in that state after losing that many of the packets so far
+    let mut dist = vec![vec![1.0 - loss], vec![0.0, loss]];
+    dist[0].push(0.0);
+    for sent in 1..=255 {
+        if sent >= run_len {
+            let repair_len = sent - run_len;
+            let failure: f64 = dist
+                .iter()
+                .map(|lost| lost.iter().skip(repair_len + 1).sum::<f64>())
+                .sum();
+            if failure <= target_loss {
+                return repair_len;
+            }
+        }
+        let mut good = vec![0.0; sent + 2];
+        let mut bad = vec![0.0; sent + 2];
+        for lost in 0..=sent {
+            good[lost] += dist[0][lost] * (1.0 - good_to_bad) + dist[1][lost] * bad_to_good;
+            bad[lost + 1] += dist[0][lost] * good_to_bad + dist[1][lost] * (1.0 - bad_to_good);
+        }
+        dist = vec![good, bad];
+    }
+    255 - run_len
+}
+
 /// A single-use FEC decoder.
 #[derive(Debug)]
 pub struct FrameDecoder {
@@ -98,7 +241,7 @@ pub struct FrameDecoder {
     space: Vec<Vec<u8>>,
     present: Vec<bool>,
     present_count: usize,
-    rs_decoder: Option<Arc<galois_8::ReedSolomon>>,
+    code: Arc<dyn ErasureCode>,
     done: bool,
 }
 
@@ -116,7 +259,7 @@ static DECODER_CACHE: Lazy<[[Option<Arc<galois_8::ReedSolomon>>; 32]; 32]> = Laz
         .unwrap()
 });
 
-fn new_rs_decoder(data_shards: usize, parity_shards: usize) -> Arc<galois_8::ReedSolomon> {
+fn rs_codec(data_shards: usize, parity_shards: usize) -> Arc<galois_8::ReedSolomon> {
     if data_shards > 32 || parity_shards > 32 {
         return Arc::new(galois_8::ReedSolomon::new(data_shards, parity_shards).unwrap());
     }
@@ -128,7 +271,7 @@ fn new_rs_decoder(data_shards: usize, parity_shards: usize) -> Arc<galois_8::Ree
 
 impl FrameDecoder {
     #[tracing::instrument]
-    pub fn new(data_shards: usize, parity_shards: usize) -> Self {
+    pub fn new(code: Arc<dyn ErasureCode>, data_shards: usize, parity_shards: usize) -> Self {
         tracing::trace!("decoding with {}/{}", data_shards, parity_shards);
         FrameDecoder {
             data_shards,
@@ -136,11 +279,7 @@ impl FrameDecoder {
             present_count: 0,
             space: vec![],
             present: vec![false; data_shards + parity_shards],
-            rs_decoder: if parity_shards > 0 && data_shards + parity_shards <= 128 {
-                Some(new_rs_decoder(data_shards, parity_shards))
-            } else {
-                None
-            },
+            code,
             done: false,
         }
     }
@@ -161,10 +300,27 @@ impl FrameDecoder {
         self.data_shards - self.good_pkts()
     }
 
+    /// Indices of the data shards that have neither arrived nor been reconstructed.
+    pub fn missing_data_shards(&self) -> Vec<u8> {
+        if self.done && self.parity_shards > 0 {
+            return Vec::new();
+        }
+        (0..self.data_shards)
+            .filter(|idx| !self.present[*idx])
+            .map(|idx| idx as u8)
+            .collect()
+    }
+
     #[tracing::instrument]
     pub fn decode(&mut self, pkt: &[u8], pkt_idx: usize) -> Option<Vec<Bytes>> {
+        // ignore duplicates, like retransmitted shards that crossed paths with a recovery
+        if pkt_idx >= self.present.len() || self.present[pkt_idx] {
+            return None;
+        }
         // if we don't have parity shards, don't touch anything
         if self.parity_shards == 0 {
+            self.present[pkt_idx] = true;
+            self.present_count += 1;
             self.done = true;
             return Some(vec![post_decode(Bytes::copy_from_slice(pkt))?]);
         }
@@ -203,7 +359,9 @@ impl FrameDecoder {
             self.data_shards,
             self.parity_shards
         );
-        self.rs_decoder.as_ref()?.reconstruct(&mut ref_vec).ok()?;
+        if !self.code.reconstruct(self.data_shards, &mut ref_vec) {
+            return None;
+        }
         self.done = true;
         let res = self
             .space
@@ -252,7 +410,158 @@ fn post_decode(raw: Bytes) -> Option<Bytes> {
 //         let lala = vec![Bytes::from([0u8; 1024].as_ref()); 10];
 //         let mut encoder = FrameEncoder::new(1);
 //         b.iter(|| {
-//             encoder.encode(0, &lala);
+//             encoder.encode(0, 0, &lala);
 //         })
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::INTERLEAVE_BURST_LEN;
+    use crate::testing::*;
+    use crate::{SimConfig, SimNetwork};
+    use rand::prelude::*;
+    use std::time::Duration;
+
+    /// A Gilbert-Elliott link that loses 5% of packets in bursts of 4 on average, drawing its losses from a fixed seed.
+    struct GilbertLink {
+        rng: StdRng,
+        bad: bool,
+    }
+
+    impl GilbertLink {
+        const LOSS: f64 = 0.05;
+        const BURST_LEN: f64 = 4.0;
+
+        fn new() -> Self {
+            GilbertLink {
+                rng: StdRng::seed_from_u64(0),
+                bad: false,
+            }
+        }
+
+        /// Whether the link loses the next packet.
+        fn lose(&mut self) -> bool {
+            let bad_to_good = 1.0 / Self::BURST_LEN;
+            let good_to_bad = Self::LOSS * bad_to_good / (1.0 - Self::LOSS);
+            self.bad = self.rng.gen_bool(if self.bad {
+                1.0 - bad_to_good
+            } else {
+                good_to_bad
+            });
+            self.bad
+        }
+    }
+
+    /// Sends 20000 runs of 16 packets over a `GilbertLink`, each with `parity` parity shards that follow its data or, when interleaved, the next run's data. Returns the fraction of data packets that couldn't be recovered.
+    fn gilbert_trial(parity: usize, interleave: bool) -> f64 {
+        const RUNS: usize = 20000;
+        const RUN_LEN: usize = 16;
+        let mut link = GilbertLink::new();
+        let mut lost = vec![(0, 0); RUNS];
+        for run in 0..RUNS {
+            for _ in 0..RUN_LEN {
+                lost[run].0 += link.lose() as usize;
+            }
+            let parity_run = if interleave {
+                match run.checked_sub(1) {
+                    Some(run) => run,
+                    None => continue,
+                }
+            } else {
+                run
+            };
+            for _ in 0..parity {
+                lost[parity_run].1 += link.lose() as usize;
+            }
+        }
+        let unrecovered: usize = lost
+            .iter()
+            .filter(|(data, parity_lost)| data + parity_lost > parity)
+            .map(|(data, _)| data)
+            .sum();
+        unrecovered as f64 / (RUNS * RUN_LEN) as f64
+    }
+
+    #[test]
+    fn codes_recover_lost_shards() {
+        let codes: [Arc<dyn ErasureCode>; 2] = [Arc::new(ReedSolomon), Arc::new(RaptorQ)];
+        for code in codes.iter() {
+            let pkts: Vec<Bytes> = (0..20).map(|i| vec![i; 100 + i as usize].into()).collect();
+            let shards = FrameEncoder::new(code.clone(), 1).encode(64, 0, &pkts);
+            let parity = shards.len() - pkts.len();
+            // RaptorQ sometimes needs a shard more than Reed-Solomon would
+            let lost = parity - 1;
+            let mut decoder = FrameDecoder::new(code.clone(), pkts.len(), parity);
+            let mut decoded: Vec<Bytes> = shards
+                .iter()
+                .enumerate()
+                .skip(lost)
+                .filter_map(|(idx, shard)| decoder.decode(shard, idx))
+                .flatten()
+                .collect();
+            decoded.sort();
+            assert_eq!(decoded, pkts);
+        }
+    }
+
+    #[test]
+    fn burst_model_protects_bursty_links() {
+        let loss = (GilbertLink::LOSS * 256.0) as u8;
+        let burst_len = (GilbertLink::BURST_LEN * 16.0) as u8;
+        // sessions interleave parity on links this bursty
+        assert!(burst_len >= INTERLEAVE_BURST_LEN);
+        // aiming for 2/256 loss, parity sized for independent losses falls far short
+        let mut encoder = FrameEncoder::new(Arc::new(ReedSolomon), 2);
+        assert!(gilbert_trial(encoder.repair_len(loss, 0, 16), false) > 0.02);
+        // with as much parity as the burst model calls for in both cases, interleaving it recovers more than sending it right after its run
+        let parity = encoder.repair_len(loss, burst_len, 16);
+        let independent = gilbert_trial(parity, false);
+        let bursty = gilbert_trial(parity, true);
+        assert!(
+            bursty < independent * 0.75,
+            "{} lost with interleaved parity, {} without",
+            bursty,
+            independent
+        );
+        assert!(bursty < 2.0 / 256.0);
+    }
+
+    #[test]
+    fn fec_recovers_loss() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig {
+                loss: 0.1,
+                latency: Duration::from_millis(20),
+                seed: 1,
+                ..Default::default()
+            });
+            let (_listener, client, server) = sim_sessions(&net, Default::default()).await;
+            let _echo = echo(server);
+            ping_pong(&client, Duration::from_millis(2), Duration::from_secs(6)).await;
+            let stats = client.get_stats().await.unwrap();
+            assert!(stats.down_loss > 0.05);
+            assert!(stats.down_recovered_loss < stats.down_loss);
+        })
+    }
+
+    #[test]
+    fn fec_recovers_burst_loss() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig {
+                burst_loss: 0.02,
+                burst_len: 4.0,
+                latency: Duration::from_millis(20),
+                seed: 1,
+                ..Default::default()
+            });
+            let (_listener, client, server) = sim_sessions(&net, Default::default()).await;
+            let _echo = echo(server);
+            ping_pong(&client, Duration::from_millis(2), Duration::from_secs(6)).await;
+            let stats = client.get_stats().await.unwrap();
+            assert!(stats.down_burst_len > 2.0);
+            assert!(stats.down_recovered_loss < stats.down_loss / 2.0);
+        })
+    }
+}
@@ -1,18 +1,26 @@
 mod client;
 mod crypt;
-mod fec;
+pub mod fec;
 mod listener;
 use std::time::{Duration, Instant};
 mod chan;
 pub use client::*;
 pub use listener::*;
 mod msg;
+pub use msg::{features, PROTOCOL_VERSION};
 pub mod runtime;
 mod session;
 pub use session::*;
 mod backhaul;
 pub mod mux;
 pub use backhaul::*;
+mod shape;
+pub use shape::*;
+mod multipath;
+pub use multipath::*;
+
+#[cfg(test)]
+mod testing;
 
 #[cfg(test)]
 mod tests {
@@ -1,7 +1,10 @@
 use crate::*;
 use crate::{
     chan::recv_many,
-    session::{Session, SessionConfig},
+    crypt::{Aead, AeadExt},
+    multipath::PathTable,
+    session::{Session, SessionConfig, Traffic},
+    shape::Shaper,
 };
 use bytes::Bytes;
 use indexmap::IndexMap;
@@ -12,15 +15,80 @@ use serde::{Deserialize, Serialize};
 use smol::channel::{Receiver, Sender};
 use smol::net::AsyncToSocketAddrs;
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::{net::SocketAddr, time::Instant};
 
+/// How far a ClientHelloV4's timestamp may be from a hardened listener's clock.
+const HELLO_MAX_SKEW_SECS: u64 = 60;
+/// How many hellos a hardened listener answers from one IP address per `HELLO_LIMIT_INTERVAL`.
+const HELLO_LIMIT: u32 = 10;
+const HELLO_LIMIT_INTERVAL: Duration = Duration::from_secs(10);
+/// How many IP addresses a hardened listener counts hellos from per `HELLO_LIMIT_INTERVAL`. Hellos from any more go unanswered until the interval is over, so that a flood from spoofed or IPv6 addresses can't grow the counts without bound.
+const HELLO_LIMIT_SOURCES: usize = 10_000;
+/// What a ResumeAck is padded to. Unlike hellos, these answer every resumed shard, so they're kept short.
+const RESUME_ACK_LEN: usize = 100;
+
 pub struct Listener {
     accepted: Receiver<Session>,
     local_addr: SocketAddr,
+    token_keys: Arc<RwLock<TokenKeys>>,
+    hardened: Arc<AtomicBool>,
+    handing_over: Arc<AtomicBool>,
     _task: smol::Task<Option<()>>,
 }
 
+/// The keys that resume tokens are sealed under. Listeners with the same long-term secret key and the same token keys accept each other's resume tokens, so sessions survive a restart or land on any of several listener processes behind one address.
+#[derive(Clone)]
+pub struct TokenKeys {
+    /// Key that new tokens are sealed under.
+    pub current: [u8; 32],
+    /// Key from before the last rotation. Tokens sealed under it are still accepted.
+    pub previous: Option<[u8; 32]>,
+}
+
+impl std::fmt::Debug for TokenKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TokenKeys")
+    }
+}
+
+impl TokenKeys {
+    /// Random keys that no other listener shares. This is what listeners start with.
+    pub fn random() -> Self {
+        TokenKeys {
+            current: rand::thread_rng().gen(),
+            previous: None,
+        }
+    }
+
+    /// Derives the keys for a rotation epoch from a master secret. Listeners sharing the secret that call this with the same epoch (for example, the current day) agree on keys and rotate together without talking to each other.
+    pub fn derive(master: &[u8], epoch: u64) -> Self {
+        let derive_one = |epoch: u64| {
+            let mut key = [0u8; 32];
+            blake3::derive_key(
+                &format!("sosistab-1 resume token key {}", epoch),
+                master,
+                &mut key,
+            );
+            key
+        };
+        TokenKeys {
+            current: derive_one(epoch),
+            previous: epoch.checked_sub(1).map(derive_one),
+        }
+    }
+
+    /// Rotates in a new current key, demoting the current one to previous.
+    pub fn rotate(&self, current: [u8; 32]) -> Self {
+        TokenKeys {
+            current,
+            previous: Some(self.current),
+        }
+    }
+}
+
 impl Listener {
     /// Accepts a session. This function must be repeatedly called for the entire Listener to make any progress.
     #[tracing::instrument(skip(self))]
@@ -33,26 +101,90 @@ impl Listener {
         long_sk: x25519_dalek::StaticSecret,
     ) -> Self {
         // let addr = async_net::resolve(addr).await;
-        let socket = runtime::new_udp_socket_bind(addr).await.unwrap();
-        let local_addr = socket.get_ref().local_addr().unwrap();
+        let socket = runtime::new_udp_socket_bind_gro(addr).await.unwrap();
+        Self::listen_with_backhaul(socket, long_sk)
+    }
+
+    /// Creates a new listener that accepts sessions over an arbitrary backhaul rather than a UDP socket.
+    pub fn listen_with_backhaul(
+        backhaul: impl Backhaul + 'static,
+        long_sk: x25519_dalek::StaticSecret,
+    ) -> Self {
+        Self::listen_with_max_version(backhaul, long_sk, PROTOCOL_VERSION)
+    }
+
+    /// Like `listen_with_backhaul`, but never speaks above the given protocol version, so tests can stand in for older servers.
+    pub(crate) fn listen_with_max_version(
+        backhaul: impl Backhaul + 'static,
+        long_sk: x25519_dalek::StaticSecret,
+        max_version: u64,
+    ) -> Self {
+        Self::start(backhaul, long_sk, max_version, crypt::REKEY_FRAMES)
+    }
+
+    /// Like `listen_with_backhaul`, but ratchets session keys every `rekey_frames` frames, so tests can force rekeys.
+    #[cfg(test)]
+    pub(crate) fn listen_with_rekey_frames(
+        backhaul: impl Backhaul + 'static,
+        long_sk: x25519_dalek::StaticSecret,
+        rekey_frames: u64,
+    ) -> Self {
+        Self::start(backhaul, long_sk, PROTOCOL_VERSION, rekey_frames)
+    }
+
+    fn start(
+        backhaul: impl Backhaul + 'static,
+        long_sk: x25519_dalek::StaticSecret,
+        max_version: u64,
+        rekey_frames: u64,
+    ) -> Self {
+        let local_addr = backhaul
+            .local_addr()
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
         let cookie = crypt::Cookie::new((&long_sk).into());
         let (send, recv) = smol::channel::unbounded();
+        let token_keys = Arc::new(RwLock::new(TokenKeys::random()));
+        let hardened = Arc::new(AtomicBool::new(false));
+        let handing_over = Arc::new(AtomicBool::new(false));
         let task = runtime::spawn(
             ListenerActor {
-                socket: Arc::new(socket),
+                socket: Arc::new(backhaul),
                 cookie,
                 long_sk,
+                max_version,
+                rekey_frames,
+                token_keys: token_keys.clone(),
+                hardened: hardened.clone(),
+                handing_over: handing_over.clone(),
             }
             .run(send),
         );
         Listener {
             accepted: recv,
             local_addr,
+            token_keys,
+            hardened,
+            handing_over,
             _task: task,
         }
     }
 
-    /// Gets the local address.
+    /// Replaces the keys that resume tokens are sealed under. Sessions whose tokens are under neither new key can no longer resume, though existing bindings keep working.
+    pub fn set_token_keys(&self, keys: TokenKeys) {
+        *self.token_keys.write() = keys;
+    }
+
+    /// Turns hardened mode on or off. A hardened listener only answers fresh, timestamped hellos (so clients older than protocol version 4 can't connect), answers only a few hellos per IP address at a time, and logs malformed packets quietly. Anything else gets silence, so active probes can't tell the listener from a closed port.
+    pub fn set_hardened(&self, hardened: bool) {
+        self.hardened.store(hardened, Ordering::Relaxed);
+    }
+
+    /// Gets ready for another listener with the same long-term secret key and token keys to take over this one's sessions, as when restarting. From then on, sessions from this listener don't tell their clients they're closing when dropped, so the clients resume with whichever listener comes next.
+    pub fn hand_over(&self) {
+        self.handing_over.store(true, Ordering::Relaxed);
+    }
+
+    /// Gets the local address. Backhauls without a meaningful local address report `0.0.0.0:0`.
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
@@ -87,12 +219,54 @@ impl RecentFilter {
     }
 }
 
+// per-source limit on answered hellos
+struct HelloLimiter {
+    counts: HashMap<IpAddr, u32>,
+    curr_time: Instant,
+}
+
+impl HelloLimiter {
+    fn new() -> Self {
+        HelloLimiter {
+            counts: HashMap::new(),
+            curr_time: Instant::now(),
+        }
+    }
+
+    fn check(&mut self, ip: IpAddr) -> bool {
+        if self.curr_time.elapsed() > HELLO_LIMIT_INTERVAL {
+            self.counts.clear();
+            self.curr_time = Instant::now();
+        }
+        if self.counts.len() >= HELLO_LIMIT_SOURCES && !self.counts.contains_key(&ip) {
+            return false;
+        }
+        let count = self.counts.entry(ip).or_default();
+        *count += 1;
+        *count <= HELLO_LIMIT
+    }
+}
+
+/// Logs something that well-behaved clients never send. Hardened listeners expect to be probed, so they keep such logs quiet.
+fn log_anomaly(hardened: bool, args: std::fmt::Arguments) {
+    if hardened {
+        tracing::trace!("{}", args)
+    } else {
+        tracing::warn!("{}", args)
+    }
+}
+
 type ShardedAddrs = IndexMap<u8, SocketAddr>;
 
 struct ListenerActor {
     socket: Arc<dyn Backhaul>,
     cookie: crypt::Cookie,
     long_sk: x25519_dalek::StaticSecret,
+    max_version: u64,
+    rekey_frames: u64,
+    token_keys: Arc<RwLock<TokenKeys>>,
+    hardened: Arc<AtomicBool>,
+    handing_over: Arc<AtomicBool>,
 }
 impl ListenerActor {
     #[allow(clippy::mutable_key_type)]
@@ -100,29 +274,26 @@ impl ListenerActor {
     async fn run(self, accepted: Sender<Session>) -> Option<()> {
         // replay filter for globally-encrypted stuff
         let mut curr_filter = RecentFilter::new();
+        // rate limiter for hellos in hardened mode
+        let mut hello_limiter = HelloLimiter::new();
         // session table
         let mut session_table = SessionTable::default();
         // channel for dropping sessions
         let (send_dead, recv_dead) = smol::channel::unbounded();
 
-        let token_key = {
-            let mut buf = [0u8; 32];
-            rand::thread_rng().fill_bytes(&mut buf);
-            buf
-        };
-
         let socket = self.socket;
+        let reliable = socket.is_reliable();
 
         // two possible events
         enum Evt {
-            NewRecv((Bytes, SocketAddr)),
+            NewRecv(Vec<(Bytes, SocketAddr)>),
             DeadSess(Bytes),
         }
 
         loop {
             smol::future::yield_now().await;
             let event = smol::future::race(
-                async { Some(Evt::NewRecv(socket.recv_from().await.ok()?)) },
+                async { Some(Evt::NewRecv(socket.recv_from_many().await.ok()?)) },
                 async { Some(Evt::DeadSess(recv_dead.recv().await.ok()?)) },
             );
             match event.await? {
@@ -130,181 +301,458 @@ impl ListenerActor {
                     tracing::trace!("removing existing session!");
                     session_table.delete(resume_token).await;
                 }
-                Evt::NewRecv((buffer, addr)) => {
-                    // first we attempt to map this to an existing session
-                    if let Some((sess, sess_crypt)) = session_table.lookup(addr) {
-                        // try feeding it into the session
-                        if let Some(dframe) = sess_crypt.pad_decrypt::<msg::DataFrame>(&buffer) {
-                            drop(sess.send(dframe).await);
+                Evt::NewRecv(batch) => {
+                    for (buffer, addr) in batch {
+                        // first we attempt to map this to an existing session
+                        if let Some(((sess, sess_crypt, traffic, paths, _), shard_id)) =
+                            session_table.lookup(addr)
+                        {
+                            // try feeding it into the session
+                            if let Some(dframe) = sess_crypt
+                                .decrypt(&buffer)
+                                .and_then(|plain| msg::DataFrame::from_plain(&plain))
+                            {
+                                traffic.received_datagram(buffer.len());
+                                if let Some(header) = &dframe.path {
+                                    paths.received(shard_id, header);
+                                }
+                                let close = dframe.close;
+                                drop(sess.send(dframe).await);
+                                if let Some(ack) = session_table.take_ack(addr) {
+                                    socket.send_to(ack, addr).await.ok()?;
+                                }
+                                if close {
+                                    // nothing more will come from the client, so there's no need to wait for the session to be dropped
+                                    tracing::debug!("{} closed its session", addr);
+                                    session_table.delete_addr(addr).await;
+                                }
+                                continue;
+                            } else {
+                                tracing::trace!("{} NOT associated with existing session", addr);
+                            }
+                        }
+                        let hardened = self.hardened.load(Ordering::Relaxed);
+                        if !curr_filter.check(&buffer) {
+                            log_anomaly(
+                                hardened,
+                                format_args!("discarding replay attempt with len {}", buffer.len()),
+                            );
                             continue;
-                        } else {
-                            tracing::trace!("{} NOT associated with existing session", addr);
                         }
-                    }
-                    if !curr_filter.check(&buffer) {
-                        tracing::warn!("discarding replay attempt with len {}", buffer.len());
-                        continue;
-                    }
-                    // we know it's not part of an existing session then. we decrypt it under the current key
-                    let s2c_key = self.cookie.generate_s2c().next().unwrap();
-                    for possible_key in self.cookie.generate_c2s() {
-                        let crypter = crypt::StdAEAD::new(&possible_key);
-                        if let Some(handshake) = crypter.pad_decrypt::<msg::HandshakeFrame>(&buffer)
-                        {
-                            match handshake {
-                                ClientHello {
-                                    long_pk,
-                                    eph_pk,
-                                    version,
-                                } => {
-                                    if version != 1 {
-                                        tracing::warn!(
-                                            "got packet with incorrect version {}",
-                                            version
+                        // we know it's not part of an existing session then. we decrypt it under the current key
+                        let s2c_key = self.cookie.generate_s2c().next().unwrap();
+                        for possible_key in self.cookie.generate_c2s() {
+                            let crypter = crypt::StdAEAD::new(&possible_key);
+                            if let Some(handshake) =
+                                crypter.pad_decrypt::<msg::HandshakeFrame>(&buffer)
+                            {
+                                if hardened && !matches!(handshake, ClientResume { .. }) {
+                                    if !matches!(handshake, ClientHelloV4 { .. }) {
+                                        log_anomaly(
+                                            hardened,
+                                            format_args!("untimestamped hello from {}", addr),
                                         );
                                         break;
                                     }
-                                    // generate session key
-                                    let my_eph_sk =
-                                        x25519_dalek::StaticSecret::new(&mut rand::rngs::OsRng {});
-                                    let token = TokenInfo {
-                                        sess_key: crypt::triple_ecdh(
+                                    if !hello_limiter.check(addr.ip()) {
+                                        log_anomaly(
+                                            hardened,
+                                            format_args!("too many hellos from {}", addr.ip()),
+                                        );
+                                        break;
+                                    }
+                                }
+                                // a timestamped hello is handled like the ClientHelloV3 it extends. Hardened listeners first check it for freshness, so that a captured hello can't be replayed; other listeners skip that, so that clients with badly set clocks can still connect
+                                let (handshake, timestamped) = match handshake {
+                                    ClientHelloV4 {
+                                        long_pk,
+                                        eph_pk,
+                                        kem_pk,
+                                        timestamp,
+                                        version,
+                                        features,
+                                    } => {
+                                        let now = msg::hello_timestamp();
+                                        let skew = now.max(timestamp) - now.min(timestamp);
+                                        if hardened && skew > HELLO_MAX_SKEW_SECS {
+                                            log_anomaly(
+                                                hardened,
+                                                format_args!(
+                                                    "hello from {} is {}s off the clock",
+                                                    addr, skew
+                                                ),
+                                            );
+                                            break;
+                                        }
+                                        let hello = ClientHelloV3 {
+                                            long_pk,
+                                            eph_pk,
+                                            kem_pk,
+                                            version,
+                                            features,
+                                            max_version: version,
+                                        };
+                                        (hello, true)
+                                    }
+                                    handshake => (handshake, false),
+                                };
+                                match handshake {
+                                    ClientHello {
+                                        long_pk,
+                                        eph_pk,
+                                        version,
+                                        max_version,
+                                        features: offered_features,
+                                    } => {
+                                        if version != 1 {
+                                            log_anomaly(
+                                                hardened,
+                                                format_args!(
+                                                    "got packet with incorrect version {}",
+                                                    version
+                                                ),
+                                            );
+                                            break;
+                                        }
+                                        let reply = server_hello(
                                             &self.long_sk,
-                                            &my_eph_sk,
                                             &long_pk,
                                             &eph_pk,
-                                        )
-                                        .as_bytes()
-                                        .to_vec()
-                                        .into(),
-                                        init_time_ms: std::time::SystemTime::now()
-                                            .duration_since(std::time::UNIX_EPOCH)
-                                            .unwrap()
-                                            .as_millis()
-                                            as u64,
+                                            None,
+                                            (max_version, offered_features),
+                                            self.max_version,
+                                            1,
+                                            features::LEGACY,
+                                            &self.token_keys.read().current,
+                                        );
+                                        let reply =
+                                            crypt::StdAEAD::new(&s2c_key).pad_encrypt(&reply, 1000);
+                                        socket.send_to(reply, addr).await.ok()?;
+                                        tracing::trace!("replied to ClientHello from {}", addr);
                                     }
-                                    .encrypt(&token_key);
-                                    let reply = msg::HandshakeFrame::ServerHello {
-                                        long_pk: (&self.long_sk).into(),
-                                        eph_pk: (&my_eph_sk).into(),
-                                        resume_token: token,
-                                    };
-                                    let reply =
-                                        crypt::StdAEAD::new(&s2c_key).pad_encrypt(&reply, 1000);
-                                    socket.send_to(reply, addr).await.ok()?;
-                                    tracing::trace!("replied to ClientHello from {}", addr);
-                                }
-                                ClientResume {
-                                    resume_token,
-                                    shard_id,
-                                } => {
-                                    tracing::trace!("Got ClientResume-{} from {}!", shard_id, addr);
-                                    // first check whether we know about the resume token
-                                    if !session_table
-                                        .rebind(addr, shard_id, resume_token.clone())
-                                        .await
-                                    {
-                                        tracing::trace!("ClientResume from {} is new!", addr);
-                                        let tokinfo = TokenInfo::decrypt(&token_key, &resume_token);
-                                        if let Some(tokinfo) = tokinfo {
-                                            let up_key = blake3::keyed_hash(
-                                                crypt::UP_KEY,
-                                                &tokinfo.sess_key,
+                                    ClientHelloV2 {
+                                        long_pk,
+                                        eph_pk,
+                                        version,
+                                        features,
+                                        max_version,
+                                    } => {
+                                        let offered = (max_version, features);
+                                        // hybrid key exchange needs a ClientHelloV3, so this tops out at version 2
+                                        let version = version.min(self.max_version).min(2);
+                                        let features =
+                                            features::select(features & features::SUPPORTED);
+                                        if version < 2 || !features::is_usable(features) {
+                                            log_anomaly(
+                                                hardened,
+                                                format_args!(
+                                                "can't agree with {} on version {} features {:x}",
+                                                addr, version, features
+                                            ),
                                             );
-                                            let dn_key = blake3::keyed_hash(
-                                                crypt::DN_KEY,
-                                                &tokinfo.sess_key,
+                                            break;
+                                        }
+                                        let reply = server_hello(
+                                            &self.long_sk,
+                                            &long_pk,
+                                            &eph_pk,
+                                            None,
+                                            offered,
+                                            self.max_version,
+                                            version,
+                                            features,
+                                            &self.token_keys.read().current,
+                                        );
+                                        let reply =
+                                            crypt::StdAEAD::new(&s2c_key).pad_encrypt(&reply, 1000);
+                                        socket.send_to(reply, addr).await.ok()?;
+                                        tracing::trace!(
+                                            "replied to ClientHelloV2 from {} with version {}",
+                                            addr,
+                                            version
+                                        );
+                                    }
+                                    ClientHelloV3 {
+                                        long_pk,
+                                        eph_pk,
+                                        kem_pk,
+                                        version,
+                                        features,
+                                        max_version,
+                                    } => {
+                                        let offered = (max_version, features);
+                                        // only timestamped hellos go past version 3
+                                        let (version, min_version) = if timestamped {
+                                            (version.min(self.max_version), 4)
+                                        } else {
+                                            (version.min(self.max_version).min(3), 3)
+                                        };
+                                        let features =
+                                            features::select(features & features::SUPPORTED);
+                                        if version < min_version || !features::is_usable(features) {
+                                            log_anomaly(
+                                                hardened,
+                                                format_args!(
+                                                "can't agree with {} on version {} features {:x}",
+                                                addr, version, features
+                                            ),
                                             );
-                                            let up_aead = crypt::StdAEAD::new(up_key.as_bytes());
-                                            let dn_aead = crypt::StdAEAD::new(dn_key.as_bytes());
-                                            let socket = socket.clone();
-                                            let (session_input, session_input_recv) =
-                                                smol::channel::bounded(100);
-                                            // create session
-                                            let (session_output_send, session_output_recv) =
-                                                smol::channel::bounded::<msg::DataFrame>(1000);
-                                            let mut locked_addrs = IndexMap::new();
-                                            locked_addrs.insert(shard_id, addr);
-                                            // send for poll
-                                            let locked_addrs = Arc::new(RwLock::new(locked_addrs));
-                                            let output_poller = {
-                                                let locked_addrs = locked_addrs.clone();
-                                                runtime::spawn(async move {
-                                                    let mut ctr = 0u8;
-                                                    loop {
-                                                        match recv_many(&session_output_recv).await
-                                                        {
-                                                            Ok(dff) => {
-                                                                let remote_addr = loop {
-                                                                    let addrs = locked_addrs.read();
-                                                                    assert!(!addrs.is_empty());
-                                                                    ctr = ctr.wrapping_add(1);
-                                                                    if let Some((_, remote_addr)) =
-                                                                        addrs.get_index(
+                                            break;
+                                        }
+                                        let kem = match crypt::kem_encapsulate(&kem_pk) {
+                                            Some(kem) => kem,
+                                            None => {
+                                                log_anomaly(
+                                                    hardened,
+                                                    format_args!("malformed KEM key from {}", addr),
+                                                );
+                                                break;
+                                            }
+                                        };
+                                        let reply = server_hello(
+                                            &self.long_sk,
+                                            &long_pk,
+                                            &eph_pk,
+                                            Some(kem),
+                                            offered,
+                                            self.max_version,
+                                            version,
+                                            features,
+                                            &self.token_keys.read().current,
+                                        );
+                                        let reply =
+                                            crypt::StdAEAD::new(&s2c_key).pad_encrypt(&reply, 1000);
+                                        socket.send_to(reply, addr).await.ok()?;
+                                        tracing::trace!(
+                                            "replied to ClientHelloV3 from {} with version {}",
+                                            addr,
+                                            version
+                                        );
+                                    }
+                                    ClientResume {
+                                        resume_token,
+                                        shard_id,
+                                        up_epoch,
+                                        dn_epoch,
+                                    } => {
+                                        tracing::trace!(
+                                            "Got ClientResume-{} from {}!",
+                                            shard_id,
+                                            addr
+                                        );
+                                        // first check whether we know about the resume token
+                                        if !session_table
+                                            .rebind(addr, shard_id, resume_token.clone())
+                                            .await
+                                        {
+                                            tracing::trace!("ClientResume from {} is new!", addr);
+                                            let tokinfo = TokenInfo::decrypt(
+                                                &self.token_keys.read(),
+                                                &resume_token,
+                                            );
+                                            if let Some(tokinfo) = tokinfo {
+                                                let up_key = blake3::keyed_hash(
+                                                    crypt::UP_KEY,
+                                                    &tokinfo.sess_key,
+                                                );
+                                                let dn_key = blake3::keyed_hash(
+                                                    crypt::DN_KEY,
+                                                    &tokinfo.sess_key,
+                                                );
+                                                // the session may have been rekeying elsewhere for a long time, so pick up both ratchets where the client says they are
+                                                let aeads = crypt::resumed_session_aead(
+                                                    tokinfo.features,
+                                                    up_key.as_bytes(),
+                                                    up_epoch,
+                                                    self.rekey_frames,
+                                                )
+                                                .zip(crypt::resumed_session_aead(
+                                                    tokinfo.features,
+                                                    dn_key.as_bytes(),
+                                                    dn_epoch,
+                                                    self.rekey_frames,
+                                                ));
+                                                let (up_aead, dn_aead) = match aeads {
+                                                    Some(aeads) => aeads,
+                                                    None => {
+                                                        log_anomaly(
+                                                            hardened,
+                                                            format_args!(
+                                                                "ClientResume from {} is at an implausible epoch",
+                                                                addr
+                                                            ),
+                                                        );
+                                                        continue;
+                                                    }
+                                                };
+                                                let socket = socket.clone();
+                                                let (session_input, session_input_recv) =
+                                                    smol::channel::bounded(100);
+                                                // create session
+                                                let (session_output_send, session_output_recv) =
+                                                    smol::channel::bounded::<msg::DataFrame>(1000);
+                                                let mut locked_addrs = IndexMap::new();
+                                                locked_addrs.insert(shard_id, addr);
+                                                // send for poll
+                                                let locked_addrs =
+                                                    Arc::new(RwLock::new(locked_addrs));
+                                                let shaper = Arc::new(Shaper::default());
+                                                let traffic = Arc::new(Traffic::default());
+                                                let paths = Arc::new(PathTable::default());
+                                                let multipath =
+                                                    tokinfo.features & features::EXT_MULTIPATH != 0;
+                                                let output_poller = {
+                                                    let locked_addrs = locked_addrs.clone();
+                                                    let shaper = shaper.clone();
+                                                    let traffic = traffic.clone();
+                                                    let paths = paths.clone();
+                                                    runtime::spawn(async move {
+                                                        let mut ctr = 0u8;
+                                                        loop {
+                                                            match recv_many(&session_output_recv)
+                                                                .await
+                                                            {
+                                                                Ok(dff) => {
+                                                                    // favour healthy paths if we can tell them apart, and otherwise take turns
+                                                                    let (shard_id, remote_addr) = loop {
+                                                                        let addrs =
+                                                                            locked_addrs.read();
+                                                                        assert!(!addrs.is_empty());
+                                                                        if multipath {
+                                                                            let shard_ids: Vec<u8> =
+                                                                                addrs
+                                                                                    .keys()
+                                                                                    .copied()
+                                                                                    .collect();
+                                                                            if let Some(shard_id) =
+                                                                                paths.pick(
+                                                                                    &shard_ids,
+                                                                                )
+                                                                            {
+                                                                                break (
+                                                                                    shard_id,
+                                                                                    addrs
+                                                                                        [&shard_id],
+                                                                                );
+                                                                            }
+                                                                        }
+                                                                        ctr = ctr.wrapping_add(1);
+                                                                        if let Some((
+                                                                            shard_id,
+                                                                            remote_addr,
+                                                                        )) = addrs.get_index(
                                                                             (ctr % (addrs.len()
                                                                                 as u8))
                                                                                 as usize,
-                                                                        )
-                                                                    {
-                                                                        break *remote_addr;
-                                                                    }
-                                                                };
-                                                                let encrypted: Vec<_> = dff
-                                                                    .into_iter()
-                                                                    .map(|df| {
-                                                                        (
-                                                                            dn_aead.pad_encrypt(
-                                                                                &df, 1000,
+                                                                        ) {
+                                                                            break (
+                                                                                *shard_id,
+                                                                                *remote_addr,
+                                                                            );
+                                                                        }
+                                                                    };
+                                                                    let encrypted: Vec<_> =
+                                                                        dff.into_iter()
+                                                                            .map(|mut df| {
+                                                                                if multipath {
+                                                                                    df.path =
+                                                                                Some(paths.stamp(
+                                                                                    shard_id,
+                                                                                ));
+                                                                                }
+                                                                                (
+                                                                            shaper.pad_encrypt(
+                                                                                dn_aead.as_ref(),
+                                                                                &df,
                                                                             ),
                                                                             remote_addr,
                                                                         )
-                                                                    })
-                                                                    .collect();
-                                                                drop(
-                                                                    socket
-                                                                        .send_to_many(&encrypted)
-                                                                        .await,
-                                                                );
-                                                            }
-                                                            Err(_) => {
-                                                                smol::future::pending::<()>().await
+                                                                            })
+                                                                            .collect();
+                                                                    for (bts, _) in encrypted.iter()
+                                                                    {
+                                                                        traffic.sent_datagram(
+                                                                            bts.len(),
+                                                                        );
+                                                                    }
+                                                                    drop(
+                                                                        socket
+                                                                            .send_to_many(
+                                                                                &encrypted,
+                                                                            )
+                                                                            .await,
+                                                                    );
+                                                                }
+                                                                Err(_) => return,
                                                             }
                                                         }
-                                                    }
-                                                })
-                                            };
-                                            let mut session = Session::new(SessionConfig {
-                                                target_loss: 0.05,
-                                                send_frame: session_output_send,
-                                                recv_frame: session_input_recv,
-                                                recv_timeout: Duration::from_secs(3600),
-                                            });
-                                            let send_dead_clo = send_dead.clone();
-                                            let resume_token_clo = resume_token.clone();
-                                            session.on_drop(move || {
-                                                drop(output_poller);
-                                                drop(send_dead_clo.try_send(resume_token_clo))
-                                            });
-                                            // spawn a task that writes to the socket.
-                                            session_table.new_sess(
-                                                resume_token.clone(),
-                                                session_input,
-                                                up_aead,
-                                                locked_addrs,
-                                            );
-                                            session_table
-                                                .rebind(addr, shard_id, resume_token)
-                                                .await;
-                                            drop(accepted.send(session).await);
-                                        } else {
-                                            tracing::warn!(
-                                                "ClientResume from {} can't be decrypted",
-                                                addr
-                                            );
+                                                    })
+                                                };
+                                                let mut session = Session::new(SessionConfig {
+                                                    target_loss: 0.05,
+                                                    send_frame: session_output_send,
+                                                    recv_frame: session_input_recv,
+                                                    recv_timeout: Duration::from_secs(3600),
+                                                    reliable,
+                                                    version: tokinfo.version,
+                                                    features: tokinfo.features,
+                                                    is_client: false,
+                                                    shaper,
+                                                    traffic: traffic.clone(),
+                                                    paths: paths.clone(),
+                                                    handing_over: self.handing_over.clone(),
+                                                });
+                                                let send_dead_clo = send_dead.clone();
+                                                let resume_token_clo = resume_token.clone();
+                                                session.on_drop(move || {
+                                                    session::linger(output_poller);
+                                                    drop(send_dead_clo.try_send(resume_token_clo))
+                                                });
+                                                // spawn a task that writes to the socket.
+                                                session_table.new_sess(
+                                                    resume_token.clone(),
+                                                    session_input,
+                                                    up_aead,
+                                                    traffic,
+                                                    paths,
+                                                    locked_addrs,
+                                                );
+                                                session_table
+                                                    .rebind(addr, shard_id, resume_token.clone())
+                                                    .await;
+                                                drop(accepted.send(session).await);
+                                            } else {
+                                                log_anomaly(
+                                                    hardened,
+                                                    format_args!(
+                                                        "ClientResume from {} can't be decrypted",
+                                                        addr
+                                                    ),
+                                                );
+                                            }
+                                        }
+                                        // tell the shard that it can stop sending the ClientResume and the frames after it again, once one of those frames gets here. Frames lost before it are then behind a frame that arrived, where NACKs find them.
+                                        let tokinfo = TokenInfo::decrypt(
+                                            &self.token_keys.read(),
+                                            &resume_token,
+                                        );
+                                        if let Some(tokinfo) = tokinfo {
+                                            if tokinfo.features & features::EXT_RESUME_ACK != 0 {
+                                                let ack_key = blake3::keyed_hash(
+                                                    crypt::RESUME_ACK_KEY,
+                                                    &tokinfo.sess_key,
+                                                );
+                                                let ack = crypt::StdAEAD::new(ack_key.as_bytes())
+                                                    .pad_encrypt(
+                                                        msg::HandshakeFrame::ResumeAck { shard_id },
+                                                        RESUME_ACK_LEN,
+                                                    );
+                                                session_table.ack_on_data(addr, ack);
+                                            }
                                         }
                                     }
+                                    _ => continue,
                                 }
-                                _ => continue,
                             }
                         }
                     }
@@ -314,18 +762,81 @@ impl ListenerActor {
     }
 }
 
+/// Builds the reply to a client hello, minting a fresh resume token that remembers the negotiated parameters. A KEM ciphertext and secret make this a hybrid handshake. The highest version and the features the client offered are bound into the session key along with our own highest version, unless the client is too old to have offered them.
+#[allow(clippy::too_many_arguments)]
+fn server_hello(
+    long_sk: &x25519_dalek::StaticSecret,
+    their_long_pk: &x25519_dalek::PublicKey,
+    their_eph_pk: &x25519_dalek::PublicKey,
+    kem: Option<(Bytes, Vec<u8>)>,
+    (their_max_version, their_features): (u64, u64),
+    max_version: u64,
+    version: u64,
+    features: u64,
+    token_key: &[u8],
+) -> msg::HandshakeFrame {
+    // generate session key
+    let my_eph_sk = x25519_dalek::StaticSecret::new(rand::rngs::OsRng {});
+    let mut sess_key = crypt::triple_ecdh(long_sk, &my_eph_sk, their_long_pk, their_eph_pk);
+    let kem_ct = kem.map(|(kem_ct, kem_secret)| {
+        sess_key = crypt::hybrid_secret(sess_key, &kem_secret);
+        kem_ct
+    });
+    if their_max_version != 0 {
+        sess_key = crypt::bind_offers(sess_key, their_max_version, their_features, max_version);
+    }
+    let resume_token = TokenInfo {
+        sess_key: sess_key.as_bytes().to_vec().into(),
+        init_time_ms: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64,
+        version,
+        features,
+    }
+    .encrypt(token_key);
+    match (version, kem_ct) {
+        (_, Some(kem_ct)) => msg::HandshakeFrame::ServerHelloV3 {
+            long_pk: long_sk.into(),
+            eph_pk: (&my_eph_sk).into(),
+            kem_ct,
+            resume_token,
+            version,
+            features,
+            max_version,
+        },
+        (1, None) => msg::HandshakeFrame::ServerHello {
+            long_pk: long_sk.into(),
+            eph_pk: (&my_eph_sk).into(),
+            resume_token,
+            max_version,
+        },
+        (_, None) => msg::HandshakeFrame::ServerHelloV2 {
+            long_pk: long_sk.into(),
+            eph_pk: (&my_eph_sk).into(),
+            resume_token,
+            version,
+            features,
+            max_version,
+        },
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TokenInfo {
     sess_key: Bytes,
     init_time_ms: u64,
+    version: u64,
+    features: u64,
 }
 
 impl TokenInfo {
     #[tracing::instrument]
-    fn decrypt(key: &[u8], encrypted: &[u8]) -> Option<Self> {
-        // first we decrypt
-        let crypter = crypt::StdAEAD::new(key);
-        let plain = crypter.decrypt(encrypted)?;
+    fn decrypt(keys: &TokenKeys, encrypted: &[u8]) -> Option<Self> {
+        // first we decrypt, under either key
+        let plain = std::iter::once(keys.current)
+            .chain(keys.previous)
+            .find_map(|key| crypt::StdAEAD::new(&key).decrypt(encrypted))?;
         bincode::deserialize(&plain).ok()
     }
 
@@ -342,26 +853,32 @@ impl TokenInfo {
 
 type SessEntry = (
     Sender<msg::DataFrame>,
-    crypt::StdAEAD,
+    Arc<dyn crypt::Aead>,
+    Arc<Traffic>,
+    Arc<PathTable>,
     Arc<RwLock<ShardedAddrs>>,
 );
 
 #[derive(Default)]
 struct SessionTable {
     token_to_sess: HashMap<Bytes, SessEntry>,
-    addr_to_token: HashMap<SocketAddr, Bytes>,
+    /// Maps addresses to the token of their session and which shard they are.
+    addr_to_token: HashMap<SocketAddr, (Bytes, u8)>,
+    /// ResumeAcks owed to addresses that resumed, sent once data from them arrives.
+    pending_acks: HashMap<SocketAddr, Bytes>,
 }
 
 impl SessionTable {
     #[tracing::instrument(skip(self))]
     async fn rebind(&mut self, addr: SocketAddr, shard_id: u8, token: Bytes) -> bool {
-        if let Some((_, _, addrs)) = self.token_to_sess.get(&token) {
+        if let Some((_, _, _, _, addrs)) = self.token_to_sess.get(&token) {
             let old = addrs.write().insert(shard_id, addr);
             tracing::trace!("binding {}=>{}", shard_id, addr);
             if let Some(old) = old {
                 self.addr_to_token.remove(&old);
+                self.pending_acks.remove(&old);
             }
-            self.addr_to_token.insert(addr, token);
+            self.addr_to_token.insert(addr, (token, shard_id));
             true
         } else {
             false
@@ -370,18 +887,35 @@ impl SessionTable {
 
     #[tracing::instrument(skip(self))]
     async fn delete(&mut self, token: Bytes) {
-        if let Some((_, _, lock_addrs)) = self.token_to_sess.remove(&token) {
+        if let Some((_, _, _, _, lock_addrs)) = self.token_to_sess.remove(&token) {
             for (_, addr) in lock_addrs.read().iter() {
                 self.addr_to_token.remove(addr);
+                self.pending_acks.remove(addr);
             }
         }
     }
 
     #[tracing::instrument(skip(self))]
-    fn lookup(&self, addr: SocketAddr) -> Option<(&Sender<msg::DataFrame>, &crypt::StdAEAD)> {
-        let token = self.addr_to_token.get(&addr)?;
-        let (s, a, _) = self.token_to_sess.get(token)?;
-        Some((s, a))
+    async fn delete_addr(&mut self, addr: SocketAddr) {
+        if let Some((token, _)) = self.addr_to_token.get(&addr).cloned() {
+            self.delete(token).await;
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn lookup(&self, addr: SocketAddr) -> Option<(&SessEntry, u8)> {
+        let (token, shard_id) = self.addr_to_token.get(&addr)?;
+        Some((self.token_to_sess.get(token)?, *shard_id))
+    }
+
+    #[tracing::instrument(skip(self, ack))]
+    fn ack_on_data(&mut self, addr: SocketAddr, ack: Bytes) {
+        self.pending_acks.insert(addr, ack);
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn take_ack(&mut self, addr: SocketAddr) -> Option<Bytes> {
+        self.pending_acks.remove(&addr)
     }
 
     #[tracing::instrument(skip(self))]
@@ -389,10 +923,211 @@ impl SessionTable {
         &mut self,
         token: Bytes,
         sender: Sender<msg::DataFrame>,
-        aead: crypt::StdAEAD,
+        aead: Arc<dyn crypt::Aead>,
+        traffic: Arc<Traffic>,
+        paths: Arc<PathTable>,
         locked_addrs: Arc<RwLock<ShardedAddrs>>,
     ) {
         self.token_to_sess
-            .insert(token, (sender, aead, locked_addrs));
+            .insert(token, (sender, aead, traffic, paths, locked_addrs));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// Encrypts a hybrid hello to the server behind the cookie, timestamped unless the timestamp is None.
+    fn hello(cookie: &crypt::Cookie, timestamp: Option<u64>) -> Bytes {
+        let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+        let eph_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+        let (kem_pk, _) = crypt::kem_keypair();
+        let frame = match timestamp {
+            Some(timestamp) => ClientHelloV4 {
+                long_pk: (&long_sk).into(),
+                eph_pk: (&eph_sk).into(),
+                kem_pk,
+                timestamp,
+                version: PROTOCOL_VERSION,
+                features: features::SUPPORTED,
+            },
+            None => ClientHelloV3 {
+                long_pk: (&long_sk).into(),
+                eph_pk: (&eph_sk).into(),
+                kem_pk,
+                version: 3,
+                features: features::SUPPORTED,
+                max_version: 3,
+            },
+        };
+        crypt::StdAEAD::new(&cookie.generate_c2s().next().unwrap()).pad_encrypt(&frame, 1000)
+    }
+
+    /// Sends every packet to the server, then counts the replies that come back within a second.
+    async fn replies(probe: &SimBackhaul, server_addr: SocketAddr, pkts: Vec<Bytes>) -> usize {
+        for pkt in pkts {
+            probe.send_to(pkt, server_addr).await.unwrap();
+        }
+        let mut count = 0;
+        timeout(Duration::from_secs(1), async {
+            loop {
+                probe.recv_from().await.unwrap();
+                count += 1;
+            }
+        })
+        .await;
+        count
+    }
+
+    #[test]
+    fn hardened_listener_ignores_probes() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+            let server_addr = server_addr();
+            let listener =
+                Listener::listen_with_backhaul(net.bind(server_addr).unwrap(), long_sk.clone());
+            listener.set_hardened(true);
+            let cookie = crypt::Cookie::new((&long_sk).into());
+            let probe = net.bind("10.0.0.2:1000".parse().unwrap()).unwrap();
+            // a genuine hello gets through, and is then captured by the prober
+            let captured = hello(&cookie, Some(msg::hello_timestamp()));
+            assert_eq!(
+                replies(&probe, server_addr, vec![captured.clone()]).await,
+                1
+            );
+            let mut probes = vec![captured.clone()];
+            for &len in &[0, 1, 16, captured.len() / 2, captured.len() - 1] {
+                probes.push(captured.slice(..len));
+            }
+            for idx in (0..captured.len()).step_by(7) {
+                let mut mutated = captured.to_vec();
+                mutated[idx] ^= 1 << (idx % 8);
+                probes.push(mutated.into());
+            }
+            let mut extended = captured.to_vec();
+            extended.push(0);
+            probes.push(extended.into());
+            probes.push(hello(&cookie, Some(msg::hello_timestamp() - 600)));
+            probes.push(hello(&cookie, Some(msg::hello_timestamp() + 600)));
+            probes.push(hello(&cookie, None));
+            assert_eq!(replies(&probe, server_addr, probes).await, 0);
+            // a flood of genuine hellos from one address is cut off
+            let flooder = net.bind("10.0.0.3:1000".parse().unwrap()).unwrap();
+            let flood = (0..HELLO_LIMIT * 2)
+                .map(|_| hello(&cookie, Some(msg::hello_timestamp())))
+                .collect();
+            assert_eq!(
+                replies(&flooder, server_addr, flood).await,
+                HELLO_LIMIT as usize
+            );
+        })
+    }
+
+    #[test]
+    fn hello_limiter_counts_few_sources() {
+        let mut limiter = HelloLimiter::new();
+        for i in 0..HELLO_LIMIT_SOURCES as u128 {
+            assert!(limiter.check(IpAddr::from(i.to_be_bytes())));
+        }
+        // once full, only addresses already counted get through
+        assert!(!limiter.check("10.0.0.1".parse().unwrap()));
+        assert!(limiter.check(IpAddr::from(0u128.to_be_bytes())));
+        assert_eq!(limiter.counts.len(), HELLO_LIMIT_SOURCES);
+    }
+
+    #[test]
+    fn open_listener_forgives_skewed_clocks() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+            let server_addr = server_addr();
+            let _listener =
+                Listener::listen_with_backhaul(net.bind(server_addr).unwrap(), long_sk.clone());
+            let cookie = crypt::Cookie::new((&long_sk).into());
+            let probe = net.bind("10.0.0.2:1000".parse().unwrap()).unwrap();
+            let skewed = vec![
+                hello(&cookie, Some(msg::hello_timestamp() - 3600)),
+                hello(&cookie, Some(msg::hello_timestamp() + 3600)),
+            ];
+            assert_eq!(replies(&probe, server_addr, skewed).await, 2);
+        })
+    }
+
+    #[test]
+    fn session_moves_between_listeners() {
+        smol::block_on(move_between_listeners(false, crypt::REKEY_FRAMES))
+    }
+
+    #[test]
+    fn session_survives_listener_crash() {
+        smol::block_on(move_between_listeners(true, crypt::REKEY_FRAMES))
+    }
+
+    #[test]
+    fn session_moves_between_listeners_after_rekeying() {
+        // several rekeys in each direction before the move
+        smol::block_on(move_between_listeners(false, 50))
+    }
+
+    /// Moves a session from one listener to a fresh one on the same address, after the first either hands its sessions over or crashes. Both ends ratchet their keys every `rekey_frames` frames.
+    async fn move_between_listeners(crash: bool, rekey_frames: u64) {
+        let net = SimNetwork::new(SimConfig::default());
+        let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+        let server_addr = server_addr();
+        let first = Listener::listen_with_rekey_frames(
+            net.bind(server_addr).unwrap(),
+            long_sk.clone(),
+            rekey_frames,
+        );
+        first.set_token_keys(TokenKeys::derive(b"shared secret", 100));
+        let client = connect_paths(
+            server_addr,
+            (&long_sk).into(),
+            sim_backhauls(&net, Default::default()),
+            1,
+            PROTOCOL_VERSION,
+            ClientConfig::default().rekey_frames(rekey_frames),
+        )
+        .await
+        .unwrap();
+        client.send_bytes(Bytes::from_static(b"hello")).await;
+        let echo_first = echo(first.accept_session().await.unwrap());
+        // longer than after the move, so that a ratchet left behind can't catch up by accident
+        let recent = ping_pong(&client, Duration::from_millis(10), Duration::from_secs(4)).await;
+        assert!(recent > 50);
+        // the first listener goes away, and a second one that has since rotated keys takes over its address
+        let server_ip = server_addr.ip();
+        if crash {
+            // without a chance to say goodbye
+            net.set_host_config(
+                server_ip,
+                SimConfig {
+                    loss: 1.0,
+                    ..Default::default()
+                },
+            );
+        } else {
+            first.hand_over();
+        }
+        drop(echo_first);
+        drop(first);
+        let backhaul = loop {
+            // the address frees up once the first listener's tasks wind down
+            match net.bind(server_addr) {
+                Ok(backhaul) => break backhaul,
+                Err(_) => smol::Timer::after(Duration::from_millis(10)).await,
+            };
+        };
+        net.set_host_config(server_ip, SimConfig::default());
+        let second = Listener::listen_with_rekey_frames(backhaul, long_sk, rekey_frames);
+        second.set_token_keys(TokenKeys::derive(b"shared secret", 101));
+        let _echo_second = runtime::spawn(async move {
+            let session = second.accept_session().await?;
+            echo(session).await
+        });
+        let recent = ping_pong(&client, Duration::from_millis(10), Duration::from_secs(3)).await;
+        assert!(recent > 50);
     }
 }
@@ -1,5 +1,90 @@
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
+
+/// Highest handshake protocol version this implementation speaks. Version 3 adds the post-quantum hybrid key exchange, and version 4 timestamps client hellos.
+pub const PROTOCOL_VERSION: u64 = 4;
+
+/// Bits of the feature bitmask negotiated in the handshake. Each byte of the mask is one category, and a session needs at least one feature from every category except extensions.
+pub mod features {
+    /// Category of forward error correction schemes.
+    pub const FEC_MASK: u64 = 0xff;
+    /// Reed-Solomon parity over runs of frames.
+    pub const FEC_REED_SOLOMON: u64 = 1;
+    /// RaptorQ repair symbols over runs of frames, which can be longer than Reed-Solomon runs.
+    pub const FEC_RAPTORQ: u64 = 1 << 1;
+
+    /// Category of ciphers for session frames.
+    pub const CIPHER_MASK: u64 = 0xff << 8;
+    /// The ChaCha12 + truncated BLAKE3 `StdAEAD`.
+    pub const CIPHER_STDAEAD: u64 = 1 << 8;
+    /// Standard ChaCha20-Poly1305.
+    pub const CIPHER_CHACHA20_POLY1305: u64 = 1 << 9;
+
+    /// Category of multiplex framings.
+    pub const MUX_MASK: u64 = 0xff << 16;
+    /// The original mux framing.
+    pub const MUX_V1: u64 = 1 << 16;
+    /// The mux framing that opens streams with a typed `StreamHeader` rather than a bare hostname.
+    pub const MUX_V2: u64 = 1 << 17;
+    /// The typed-header mux framing with 32-bit stream IDs in place of 16-bit ones. Clients open streams under odd IDs and servers under even ones.
+    pub const MUX_V3: u64 = 1 << 18;
+
+    /// Category of optional extensions. Unlike the other categories, any number of these may be used at once, including none.
+    pub const EXT_MASK: u64 = 0xff << 24;
+    /// Ratcheting the session keys forward every so often.
+    pub const EXT_REKEY: u64 = 1 << 24;
+    /// Asking for shards of runs that FEC couldn't recover to be sent again.
+    pub const EXT_NACK: u64 = 1 << 25;
+    /// Numbering and acknowledging frames on each path separately, so that senders can favour healthy paths.
+    pub const EXT_MULTIPATH: u64 = 1 << 26;
+    /// Telling the other end when a session is dropped, so that it can free the session right away rather than wait for it to time out.
+    pub const EXT_CLOSE: u64 = 1 << 27;
+    /// Acknowledging every ClientResume once a data frame after it arrives, so that clients can tell when to stop sending them again.
+    pub const EXT_RESUME_ACK: u64 = 1 << 28;
+    /// Reporting how long loss bursts run, so that senders can size FEC parity for bursty links.
+    pub const EXT_BURST_LEN: u64 = 1 << 29;
+
+    /// Everything this implementation supports.
+    pub const SUPPORTED: u64 = FEC_REED_SOLOMON
+        | FEC_RAPTORQ
+        | CIPHER_STDAEAD
+        | CIPHER_CHACHA20_POLY1305
+        | MUX_V1
+        | MUX_V2
+        | MUX_V3
+        | EXT_REKEY
+        | EXT_NACK
+        | EXT_MULTIPATH
+        | EXT_CLOSE
+        | EXT_RESUME_ACK
+        | EXT_BURST_LEN;
+    /// What version-1 peers implicitly speak.
+    pub const LEGACY: u64 = FEC_REED_SOLOMON | CIPHER_STDAEAD | MUX_V1;
+
+    /// Checks that a feature set has something from every category.
+    pub fn is_usable(features: u64) -> bool {
+        features & FEC_MASK != 0 && features & CIPHER_MASK != 0 && features & MUX_MASK != 0
+    }
+
+    /// Narrows a feature set down to one feature per category, preferring the highest bit since newer features get higher bits. Extensions are kept as they are.
+    pub fn select(features: u64) -> u64 {
+        [FEC_MASK, CIPHER_MASK, MUX_MASK]
+            .iter()
+            .map(|mask| features & mask)
+            .filter(|category| *category != 0)
+            .map(|category| 1 << (63 - category.leading_zeros()))
+            .fold(features & EXT_MASK, |acc, bit| acc | bit)
+    }
+}
+
+/// The current time in seconds since the Unix epoch, as carried in a ClientHelloV4.
+pub fn hello_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("must be after Unix epoch")
+        .as_secs()
+}
+
 /// Frame sent as a session-negotiation message. This is always encrypted with the cookie.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum HandshakeFrame {
@@ -8,6 +93,10 @@ pub enum HandshakeFrame {
         long_pk: x25519_dalek::PublicKey,
         eph_pk: x25519_dalek::PublicKey,
         version: u64,
+        /// Highest version the client speaks, which is more than this hello's if it fell back to it. Older clients leave this out, and it reads as zero from the padding.
+        max_version: u64,
+        /// Every feature the client supports. Zero from older clients.
+        features: u64,
     },
     /// Frame sent from server to client to give a cookie for finally opening a connection.
     ServerHello {
@@ -15,6 +104,8 @@ pub enum HandshakeFrame {
         eph_pk: x25519_dalek::PublicKey,
         /// This value includes all the info required to reconstruct a session, encrypted under a secret key only the server knows.
         resume_token: Bytes,
+        /// Highest version the server speaks, so that a client that fell back to an older hello can tell whether it had to. Older servers leave this out, and it reads as zero from the padding.
+        max_version: u64,
     },
 
     /// Frame sent from client to server to either signal roaming, or complete an initial handshake. This is globally encrypted.
@@ -23,7 +114,80 @@ pub enum HandshakeFrame {
         resume_token: Bytes,
         /// Which shard is this
         shard_id: u8,
+        /// The epoch of the client's sending ratchet, so that a listener that has never seen the session starts where the client is. Older clients leave this out, and it reads as zero from the padding.
+        up_epoch: u64,
+        /// The epoch of the client's receiving ratchet.
+        dn_epoch: u64,
+    },
+
+    /// Frame sent from client to server when opening a connection at protocol version 2 or above. Version-1 servers can't parse this and stay silent, so clients fall back to a plain ClientHello.
+    ClientHelloV2 {
+        long_pk: x25519_dalek::PublicKey,
+        eph_pk: x25519_dalek::PublicKey,
+        /// Highest version the client speaks.
+        version: u64,
+        /// Every feature the client supports.
+        features: u64,
+        /// Highest version the client speaks, which is more than `version` if it fell back to this hello. Zero from older clients.
+        max_version: u64,
+    },
+    /// Frame sent from server to client in reply to a ClientHelloV2.
+    ServerHelloV2 {
+        long_pk: x25519_dalek::PublicKey,
+        eph_pk: x25519_dalek::PublicKey,
+        resume_token: Bytes,
+        /// Highest version both sides speak.
+        version: u64,
+        /// Features the session uses, one from every category.
+        features: u64,
+        /// Highest version the server speaks. Zero from older servers.
+        max_version: u64,
+    },
+
+    /// Frame sent from client to server when opening a connection with a post-quantum hybrid key exchange, at protocol version 3 or above. Older servers stay silent, so clients fall back to a ClientHelloV2.
+    ClientHelloV3 {
+        long_pk: x25519_dalek::PublicKey,
+        eph_pk: x25519_dalek::PublicKey,
+        /// Ephemeral Kyber768 encapsulation key.
+        kem_pk: Bytes,
+        /// Highest version the client speaks.
+        version: u64,
+        /// Every feature the client supports.
+        features: u64,
+        /// Highest version the client speaks, which is more than `version` if it fell back to this hello. Zero from older clients.
+        max_version: u64,
+    },
+    /// Frame sent from server to client in reply to a ClientHelloV3.
+    ServerHelloV3 {
+        long_pk: x25519_dalek::PublicKey,
+        eph_pk: x25519_dalek::PublicKey,
+        /// Kyber768 ciphertext encapsulated to the client's `kem_pk`.
+        kem_ct: Bytes,
+        resume_token: Bytes,
+        /// Highest version both sides speak.
+        version: u64,
+        /// Features the session uses, one from every category.
+        features: u64,
+        /// Highest version the server speaks. Zero from older servers.
+        max_version: u64,
     },
+
+    /// Frame sent from client to server when opening a connection at protocol version 4 or above. This is a ClientHelloV3 stamped with the time it was sent, so that servers can refuse stale hellos. The server replies with a ServerHelloV3.
+    ClientHelloV4 {
+        long_pk: x25519_dalek::PublicKey,
+        eph_pk: x25519_dalek::PublicKey,
+        /// Ephemeral Kyber768 encapsulation key.
+        kem_pk: Bytes,
+        /// Seconds since the Unix epoch when the hello was sent.
+        timestamp: u64,
+        /// Highest version the client speaks.
+        version: u64,
+        /// Every feature the client supports.
+        features: u64,
+    },
+
+    /// Frame sent from server to client on the same shard to acknowledge a ClientResume and the first data frame after it, if the session has `EXT_RESUME_ACK`. This is encrypted with a key derived from the session key.
+    ResumeAck { shard_id: u8 },
 }
 
 /// Frame sent as an per-session message. This is always encrypted with a per-session key.
@@ -46,4 +210,125 @@ pub struct DataFrame {
     pub total_recv_frames: u64,
     /// Body.
     pub body: Bytes,
+    /// Mean length of recent loss bursts in delivered frames, in sixteenths of a frame. Only sent when EXT_BURST_LEN is negotiated, and zero if unknown.
+    pub burst_len: u8,
+    /// Runs that the sender wants shards of again. Only sent when EXT_NACK is negotiated, and only then may a frame have no data shards at all, in which case it carries nothing else.
+    pub nacks: Vec<Nack>,
+    /// Where the frame stands on the path it's sent over. Only sent when EXT_MULTIPATH is negotiated, and filled in by whatever picks the path rather than by the session.
+    pub path: Option<PathHeader>,
+    /// Whether the sender has dropped the session. Only sent when EXT_CLOSE is negotiated, on a frame that carries nothing else, and always the last frame of the session.
+    pub close: bool,
+}
+
+/// Sequence numbers and acknowledgements for one path of a session, which is one shard.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathHeader {
+    /// Strictly incrementing counter of frames sent on this path, starting at one.
+    pub seqno: u64,
+    /// Highest `seqno` received on this path, or zero if none.
+    pub high_recv: u64,
+    /// Total frames received on this path.
+    pub total_recv: u64,
+    /// Milliseconds since the frame numbered `high_recv` arrived.
+    pub ack_delay: u16,
+}
+
+/// A request to send some shards of a run again.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Nack {
+    pub run_no: u64,
+    /// Indices of the missing shards. Empty if the whole run went missing, in which case its data shards are wanted.
+    pub shards: Vec<u8>,
+}
+
+/// The fields of a DataFrame that every peer sends.
+#[derive(Deserialize)]
+struct DataFrameHead {
+    frame_no: u64,
+    run_no: u64,
+    run_idx: u8,
+    data_shards: u8,
+    parity_shards: u8,
+    high_recv_frame_no: u64,
+    total_recv_frames: u64,
+    body: Bytes,
+}
+
+impl DataFrame {
+    /// Parses a decrypted DataFrame. Fields after `body` were added later: older peers don't send them, but they ignore them when we do. Missing trailing fields, or the zero padding that usually stands in for them, read as their defaults.
+    pub fn from_plain(plain: &[u8]) -> Option<Self> {
+        let mut reader = plain;
+        let head: DataFrameHead = bincode::deserialize_from(&mut reader).ok()?;
+        let burst_len = bincode::deserialize_from(&mut reader).unwrap_or_default();
+        let nacks = bincode::deserialize_from(&mut reader).unwrap_or_default();
+        let path = bincode::deserialize_from(&mut reader).unwrap_or_default();
+        let close = bincode::deserialize_from(&mut reader).unwrap_or_default();
+        Some(DataFrame {
+            frame_no: head.frame_no,
+            run_no: head.run_no,
+            run_idx: head.run_idx,
+            data_shards: head.data_shards,
+            parity_shards: head.parity_shards,
+            high_recv_frame_no: head.high_recv_frame_no,
+            total_recv_frames: head.total_recv_frames,
+            body: head.body,
+            burst_len,
+            nacks,
+            path,
+            close,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_frames_parse() {
+        let frame = DataFrame {
+            frame_no: 1,
+            run_no: 2,
+            run_idx: 3,
+            data_shards: 4,
+            parity_shards: 5,
+            high_recv_frame_no: 6,
+            total_recv_frames: 7,
+            body: Bytes::from_static(b"hello"),
+            burst_len: 8,
+            nacks: vec![Nack {
+                run_no: 9,
+                shards: vec![10],
+            }],
+            path: Some(PathHeader {
+                seqno: 11,
+                high_recv: 12,
+                total_recv: 13,
+                ack_delay: 14,
+            }),
+            close: true,
+        };
+        let plain = bincode::serialize(&frame).unwrap();
+        let parsed = DataFrame::from_plain(&plain).unwrap();
+        assert_eq!(parsed.burst_len, 8);
+        assert_eq!(parsed.nacks, frame.nacks);
+        assert_eq!(parsed.path, frame.path);
+        assert!(parsed.close);
+        // an unpadded frame from an older peer ends right after the body
+        let body_end = plain.len()
+            - bincode::serialized_size(&(8u8, &frame.nacks, &frame.path, true)).unwrap() as usize;
+        let legacy = DataFrame::from_plain(&plain[..body_end]).unwrap();
+        assert_eq!(legacy.body, frame.body);
+        assert_eq!(legacy.burst_len, 0);
+        assert!(legacy.nacks.is_empty());
+        assert_eq!(legacy.path, None);
+        assert!(!legacy.close);
+        // padding reads as defaults
+        let mut padded = plain[..body_end].to_vec();
+        padded.extend_from_slice(&[0; 100]);
+        let padded = DataFrame::from_plain(&padded).unwrap();
+        assert!(padded.nacks.is_empty());
+        assert_eq!(padded.path, None);
+        assert!(!padded.close);
+    }
 }
@@ -0,0 +1,303 @@
+use crate::msg::PathHeader;
+use parking_lot::Mutex;
+use rand::prelude::*;
+use rustc_hash::FxHashMap;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How many recent sends each path remembers, to time the acknowledgements that come back.
+const SEND_HISTORY: usize = 1024;
+/// Roughly how many frames' worth of loss samples the smoothed loss of a path covers.
+const LOSS_WINDOW: f64 = 64.0;
+/// Round trip assumed for a path that hasn't been measured yet.
+const DEFAULT_RTT: Duration = Duration::from_millis(100);
+/// Shortest time without acknowledgements after which a path with frames in flight counts as stalled. Longer round trips wait longer.
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+/// Fraction of its usual share a stalled path still gets, so that it's noticed when it comes back.
+const STALLED_SHARE: f64 = 0.01;
+
+/// Statistics of one path of a session, as its sender sees them.
+#[derive(Debug, Clone)]
+pub struct PathStats {
+    /// The shard that the path carries.
+    pub shard_id: u8,
+    /// Frames sent on the path.
+    pub sent: u64,
+    /// Smoothed round trip of frames sent on the path, if measured yet.
+    pub ping: Option<Duration>,
+    /// Smoothed fraction of frames sent on the path that never arrived.
+    pub loss: f64,
+    /// Whether frames sent on the path have gone unacknowledged for a long while.
+    pub stalled: bool,
+}
+
+/// What one end of a session knows about each of its paths. Frames are numbered and acknowledged separately on every path, so that each end learns how lossy and slow every path is for what it sends, and can favour the healthy ones.
+#[derive(Debug, Default)]
+pub(crate) struct PathTable {
+    paths: Mutex<FxHashMap<u8, Path>>,
+}
+
+#[derive(Debug)]
+struct Path {
+    /// Sequence number of the last frame sent.
+    seqno: u64,
+    /// Sequence numbers and times of recent sends, oldest first.
+    send_times: VecDeque<(u64, Instant)>,
+    srtt: Option<Duration>,
+    loss: f64,
+    /// The latest acknowledgement from the other end.
+    acked_high: u64,
+    acked_total: u64,
+    last_ack: Instant,
+    /// What we've received on the path, for acknowledging.
+    high_recv: u64,
+    total_recv: u64,
+    high_recv_time: Instant,
+}
+
+impl Path {
+    fn new(now: Instant) -> Self {
+        Path {
+            seqno: 0,
+            send_times: VecDeque::new(),
+            srtt: None,
+            loss: 0.0,
+            acked_high: 0,
+            acked_total: 0,
+            last_ack: now,
+            high_recv: 0,
+            total_recv: 0,
+            high_recv_time: now,
+        }
+    }
+
+    /// Whether frames have been in flight without any acknowledgement for too long.
+    fn is_stalled(&self, now: Instant) -> bool {
+        let timeout = STALL_TIMEOUT.max(self.srtt.unwrap_or(DEFAULT_RTT) * 4);
+        let oldest_unacked = self
+            .send_times
+            .iter()
+            .find(|(seqno, _)| *seqno > self.acked_high);
+        match oldest_unacked {
+            Some((_, sent)) => {
+                now.saturating_duration_since(*sent) > timeout
+                    && now.saturating_duration_since(self.last_ack) > timeout
+            }
+            None => false,
+        }
+    }
+
+    /// How much traffic the path deserves relative to others: lossy paths deserve a lot less, and slow ones somewhat less. None if the path hasn't been measured yet.
+    fn weight(&self, now: Instant) -> Option<f64> {
+        let srtt = self.srtt?;
+        let mut weight = (1.0 - self.loss).powi(4) / srtt.as_secs_f64().max(0.001);
+        if self.is_stalled(now) {
+            weight *= STALLED_SHARE;
+        }
+        Some(weight)
+    }
+}
+
+impl PathTable {
+    /// Numbers a frame about to be sent on the given shard's path, and acknowledges what has arrived on it.
+    pub fn stamp(&self, shard_id: u8) -> PathHeader {
+        let now = Instant::now();
+        let mut paths = self.paths.lock();
+        let path = paths.entry(shard_id).or_insert_with(|| Path::new(now));
+        path.seqno += 1;
+        path.send_times.push_back((path.seqno, now));
+        if path.send_times.len() > SEND_HISTORY {
+            path.send_times.pop_front();
+        }
+        let ack_delay = now
+            .saturating_duration_since(path.high_recv_time)
+            .as_millis();
+        PathHeader {
+            seqno: path.seqno,
+            high_recv: path.high_recv,
+            total_recv: path.total_recv,
+            ack_delay: ack_delay.min(u16::MAX as u128) as u16,
+        }
+    }
+
+    /// Takes note of a frame that arrived on the given shard's path.
+    pub fn received(&self, shard_id: u8, header: &PathHeader) {
+        let now = Instant::now();
+        let mut paths = self.paths.lock();
+        let path = paths.entry(shard_id).or_insert_with(|| Path::new(now));
+        if header.seqno > 0 {
+            path.total_recv += 1;
+            if header.seqno > path.high_recv {
+                path.high_recv = header.seqno;
+                path.high_recv_time = now;
+            }
+        }
+        // acknowledgements can't run ahead of what we sent, and stale ones tell us nothing new
+        if header.high_recv <= path.acked_high || header.high_recv > path.seqno {
+            return;
+        }
+        let sent = header.high_recv - path.acked_high;
+        let delivered = header.total_recv.saturating_sub(path.acked_total);
+        let loss_sample = 1.0 - (delivered as f64 / sent as f64).min(1.0);
+        path.loss += (loss_sample - path.loss) * (sent as f64 / LOSS_WINDOW).min(1.0);
+        if let Ok(idx) = path
+            .send_times
+            .binary_search_by_key(&header.high_recv, |(seqno, _)| *seqno)
+        {
+            let rtt_sample = now
+                .saturating_duration_since(path.send_times[idx].1)
+                .checked_sub(Duration::from_millis(header.ack_delay as u64))
+                .unwrap_or_default();
+            path.srtt = Some(match path.srtt {
+                Some(srtt) => (srtt * 7 + rtt_sample) / 8,
+                None => rtt_sample,
+            });
+        }
+        path.acked_high = header.high_recv;
+        path.acked_total = header.total_recv;
+        path.last_ack = now;
+    }
+
+    /// Picks which of the given shards to send on next, at random in proportion to how healthy their paths are. Paths that haven't been measured yet are treated like the best measured one, so that they get tried.
+    pub fn pick(&self, shard_ids: &[u8]) -> Option<u8> {
+        let now = Instant::now();
+        let paths = self.paths.lock();
+        let weights: Vec<Option<f64>> = shard_ids
+            .iter()
+            .map(|shard_id| paths.get(shard_id).and_then(|path| path.weight(now)))
+            .collect();
+        let best = weights
+            .iter()
+            .filter_map(|weight| *weight)
+            .fold(None, |best: Option<f64>, weight| {
+                Some(best.map_or(weight, |best| best.max(weight)))
+            })
+            .unwrap_or(1.0);
+        let weights: Vec<f64> = weights
+            .into_iter()
+            .map(|weight| weight.unwrap_or(best))
+            .collect();
+        let total: f64 = weights.iter().sum();
+        let mut point = rand::thread_rng().gen::<f64>() * total;
+        for (shard_id, weight) in shard_ids.iter().zip(weights) {
+            if point < weight {
+                return Some(*shard_id);
+            }
+            point -= weight;
+        }
+        shard_ids.last().copied()
+    }
+
+    /// Statistics of every path, in order of shard.
+    pub fn stats(&self) -> Vec<PathStats> {
+        let now = Instant::now();
+        let paths = self.paths.lock();
+        let mut stats: Vec<PathStats> = paths
+            .iter()
+            .map(|(shard_id, path)| PathStats {
+                shard_id: *shard_id,
+                sent: path.seqno,
+                ping: path.srtt,
+                loss: path.loss,
+                stalled: path.is_stalled(now),
+            })
+            .collect();
+        stats.sort_by_key(|stats| stats.shard_id);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::{client, ClientConfig, Listener, SimConfig, SimNetwork, PROTOCOL_VERSION};
+    use bytes::Bytes;
+
+    /// Sends frames from one table to another over a path that loses every `lose_every`th frame, acknowledging them back.
+    fn exchange(sender: &PathTable, receiver: &PathTable, shard_id: u8, lose_every: u64) {
+        for i in 1..=200 {
+            let header = sender.stamp(shard_id);
+            if i % lose_every != 0 {
+                receiver.received(shard_id, &header);
+            }
+            sender.received(shard_id, &receiver.stamp(shard_id));
+        }
+    }
+
+    #[test]
+    fn paths_favour_healthy_ones() {
+        let (client, server) = (PathTable::default(), PathTable::default());
+        exchange(&server, &client, 0, u64::MAX);
+        exchange(&server, &client, 1, 2);
+        let stats = server.stats();
+        assert!(stats[0].loss < 0.01);
+        assert!((stats[1].loss - 0.5).abs() < 0.1);
+        assert!(stats.iter().all(|stats| stats.ping.is_some()));
+        let lossy_picks = (0..1000)
+            .filter(|_| server.pick(&[0, 1, 2]) == Some(1))
+            .count();
+        // shard 2 hasn't been measured, so it's treated as healthy
+        assert!(lossy_picks < 100);
+    }
+
+    #[test]
+    fn multipath_favours_healthy_paths() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+            let listener =
+                Listener::listen_with_backhaul(net.bind(server_addr()).unwrap(), long_sk.clone());
+            // say, Wi-Fi on 10.0.0.2 and cellular on 10.0.0.3
+            let client = client::connect_paths(
+                server_addr(),
+                (&long_sk).into(),
+                sim_backhauls(&net, Default::default()),
+                2,
+                PROTOCOL_VERSION,
+                ClientConfig::default(),
+            )
+            .await
+            .unwrap();
+            // a burst gets every shard to send its ClientResume before the loss starts
+            for _ in 0..200 {
+                client.send_bytes(Bytes::from_static(b"warmup")).await;
+            }
+            let server = listener.accept_session().await.unwrap();
+            smol::Timer::after(Duration::from_millis(100)).await;
+            net.set_host_config(
+                "10.0.0.3".parse().unwrap(),
+                SimConfig {
+                    loss: 0.5,
+                    ..Default::default()
+                },
+            );
+            let measure = async {
+                // give the server time to learn which path is which
+                ping_pong(&client, Duration::from_millis(5), Duration::from_secs(2)).await;
+                let before = server.get_stats().await.unwrap().paths;
+                let recent =
+                    ping_pong(&client, Duration::from_millis(5), Duration::from_secs(2)).await;
+                let after = server.get_stats().await.unwrap().paths;
+                (before, after, recent)
+            };
+            let (before, after, recent) = with_echoes(&server, measure).await;
+            // shards alternate between the paths, so the odd ones are on the lossy one
+            assert_eq!(after.len(), 4);
+            let (mut lossy_sent, mut total_sent) = (0, 0);
+            for (before, after) in before.iter().zip(after.iter()) {
+                let sent = after.sent - before.sent;
+                total_sent += sent;
+                if after.shard_id % 2 == 1 {
+                    lossy_sent += sent;
+                    assert!(after.loss > 0.2);
+                } else {
+                    assert!(after.loss < 0.1);
+                }
+            }
+            // taking turns would send half of it over the lossy path
+            assert!(lossy_sent * 4 < total_sent);
+            assert!(recent > 150);
+        })
+    }
+}
@@ -2,16 +2,18 @@ use crate::*;
 use bytes::Bytes;
 use smol::channel::{Receiver, Sender};
 use std::sync::Arc;
+mod header;
 mod multiplex_actor;
 mod relconn;
 mod structs;
+pub use header::StreamHeader;
 pub use relconn::RelConn;
 
 /// A multiplex session over a sosistab session, implementing both reliable "streams" and unreliable messages.
 pub struct Multiplex {
     urel_send: Sender<Bytes>,
     urel_recv: Receiver<Bytes>,
-    conn_open: Sender<(Option<String>, Sender<RelConn>)>,
+    conn_open: Sender<(Option<StreamHeader>, Sender<std::io::Result<RelConn>>)>,
     conn_accept: Receiver<RelConn>,
     sess_ref: Arc<Session>,
     _task: smol::Task<()>,
@@ -68,18 +70,16 @@ impl Multiplex {
         &self.sess_ref
     }
 
-    /// Open a reliable conn to the other end.
-    pub async fn open_conn(&self, additional: Option<String>) -> std::io::Result<RelConn> {
+    /// Open a reliable conn to the other end, telling it what the conn is for. Fails if the other end never acknowledges the conn, or if every stream ID is in use.
+    pub async fn open_conn(&self, header: Option<StreamHeader>) -> std::io::Result<RelConn> {
         let (send, recv) = smol::channel::unbounded();
         self.conn_open
-            .send((additional.clone(), send))
+            .send((header, send))
             .await
             .map_err(to_ioerror)?;
-        if let Ok(s) = recv.recv().await {
-            Ok(s)
-        } else {
-            smol::future::pending().await
-        }
+        recv.recv()
+            .await
+            .map_err(|_| to_ioerror("stream could not be opened"))?
     }
 
     /// Accept a reliable conn from the other end.
@@ -87,3 +87,124 @@ impl Multiplex {
         self.conn_accept.recv().await.map_err(to_ioerror)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use smol::prelude::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn streams_carry_headers() {
+        smol::block_on(async {
+            let tcp = mux::StreamHeader::TcpConnect {
+                host: "example.com:443".into(),
+            };
+            // (server version, headers that survive the mux framing)
+            for &(server_version, typed) in &[(PROTOCOL_VERSION, true), (1, false)] {
+                let net = SimNetwork::new(SimConfig::default());
+                let (_listener, client, server) = sim_sessions_versioned(
+                    &net,
+                    Default::default(),
+                    PROTOCOL_VERSION,
+                    server_version,
+                )
+                .await;
+                let client = mux::Multiplex::new(client);
+                let server = mux::Multiplex::new(server);
+                let _conn = client.open_conn(Some(tcp.clone())).await.unwrap();
+                assert_eq!(server.accept_conn().await.unwrap().header(), Some(&tcp));
+                let _conn = client
+                    .open_conn(Some(mux::StreamHeader::Auth))
+                    .await
+                    .unwrap();
+                let expected = if typed {
+                    Some(&mux::StreamHeader::Auth)
+                } else {
+                    None
+                };
+                assert_eq!(server.accept_conn().await.unwrap().header(), expected);
+            }
+        })
+    }
+
+    #[test]
+    fn bulk_stream_doesnt_hold_up_others() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let (_listener, client, server) = sim_sessions(&net, Default::default()).await;
+            // the session can't keep up with the stream, so its data backs up
+            client.set_up_ratelimit(50_000, 10_000);
+            let client = mux::Multiplex::new(client);
+            let server = mux::Multiplex::new(server);
+            let mut bulk = client.open_conn(None).await.unwrap();
+            let _writer = runtime::spawn(async move {
+                bulk.write_all(&vec![0u8; 16 << 20]).await.unwrap();
+            });
+            let mut remote = server.accept_conn().await.unwrap();
+            let _reader = runtime::spawn(async move {
+                let mut buf = vec![0u8; 65536];
+                while remote.read(&mut buf).await.unwrap() > 0 {}
+            });
+            smol::Timer::after(Duration::from_secs(2)).await;
+            // opening another stream only takes control messages, which mustn't wait behind the data
+            let start = Instant::now();
+            let _other = client.open_conn(None).await.unwrap();
+            server.accept_conn().await.unwrap();
+            assert!(
+                start.elapsed() < Duration::from_secs(1),
+                "took {:?} to open",
+                start.elapsed()
+            );
+        })
+    }
+
+    #[test]
+    fn stalled_reader_wastes_nothing() {
+        smol::block_on(async {
+            let link = SimConfig {
+                latency: Duration::from_millis(10),
+                seed: 1,
+                ..Default::default()
+            };
+            let net = SimNetwork::new(link.clone());
+            let (_listener, client, server) = sim_sessions(&net, Default::default()).await;
+            // once the session is up, the link drops the odd packet, so the writer has to retransmit too
+            net.set_config(SimConfig { loss: 0.01, ..link });
+            let before = client.get_stats().await.unwrap().up_bytes;
+            let client = mux::Multiplex::new(client);
+            let server = mux::Multiplex::new(server);
+            let session = client.get_session();
+            let data: Vec<u8> = (0..4 << 20).map(|i: u32| (i % 251) as u8).collect();
+            let mut conn = client.open_conn(None).await.unwrap();
+            let to_write = data.clone();
+            let _writer = runtime::spawn(async move {
+                conn.write_all(&to_write).await.unwrap();
+                smol::future::pending::<()>().await
+            });
+            let mut remote = server.accept_conn().await.unwrap();
+            // nobody reads, so the writer fills the receive window, however long that takes, and then has to wait
+            let filled = timeout(Duration::from_secs(60), async {
+                while session.get_stats().await.unwrap().up_bytes - before
+                    < (relconn::RECV_BUFFER * 3 / 4) as u64
+                {
+                    smol::Timer::after(Duration::from_millis(50)).await;
+                }
+            })
+            .await;
+            assert!(filled.is_some(), "never filled the receive window");
+            smol::Timer::after(Duration::from_secs(2)).await;
+            // however long it waits, it sent no more than the window, a few retransmissions and the odd window probe
+            let sent = session.get_stats().await.unwrap().up_bytes - before;
+            assert!(
+                sent < (relconn::RECV_BUFFER * 5 / 4) as u64,
+                "sent {} bytes while stalled",
+                sent
+            );
+            let mut received = vec![0u8; data.len()];
+            remote.read_exact(&mut received).await.unwrap();
+            assert!(received == data);
+        })
+    }
+}
@@ -0,0 +1,107 @@
+use crate::features;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+/// Version of the typed header encoding, sent as its first byte so that later encodings can be told apart.
+const HEADER_VERSION: u8 = 1;
+
+/// Mux framings that carry typed headers.
+const TYPED_FRAMINGS: u64 = features::MUX_V2 | features::MUX_V3;
+
+/// What a stream is for. The side that opens a stream sends one along, so that the other side can dispatch on it without parsing anything out of the stream itself.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StreamHeader {
+    /// Relays a TCP connection to a `host:port`.
+    TcpConnect { host: String },
+    /// Relays UDP datagrams, like a SOCKS5 UDP associate.
+    UdpAssociate,
+    /// Carries DNS queries and their responses.
+    Dns,
+    /// Sets up a VPN, whose packets then travel as unreliable messages.
+    VpnControl,
+    /// Carries the credentials that authenticate a session.
+    Auth,
+}
+
+/// Encodes a stream header into the payload of a Syn, in the mux framing the session negotiated. The original framing only has room for a `host:port`, so other kinds of streams go without a header there.
+pub(crate) fn encode_header(header: Option<&StreamHeader>, features: u64) -> Bytes {
+    match header {
+        None => Bytes::new(),
+        Some(header) if features & TYPED_FRAMINGS != 0 => {
+            let mut payload = vec![HEADER_VERSION];
+            payload.extend_from_slice(&bincode::serialize(header).unwrap());
+            payload.into()
+        }
+        Some(StreamHeader::TcpConnect { host }) => Bytes::copy_from_slice(host.as_bytes()),
+        Some(_) => Bytes::new(),
+    }
+}
+
+/// A stream header that can't be read, because it comes from a newer encoding or doesn't parse. Streams that carry one are refused rather than taken for streams without a header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct UnknownHeader;
+
+/// Decodes the stream header in the payload of a Syn. Streams opened without a header decode as `None`.
+pub(crate) fn decode_header(
+    payload: &[u8],
+    features: u64,
+) -> Result<Option<StreamHeader>, UnknownHeader> {
+    if payload.is_empty() {
+        return Ok(None);
+    }
+    if features & TYPED_FRAMINGS == 0 {
+        return Ok(Some(StreamHeader::TcpConnect {
+            host: String::from_utf8_lossy(payload).to_string(),
+        }));
+    }
+    if payload[0] != HEADER_VERSION {
+        tracing::debug!("unknown stream header version {}", payload[0]);
+        return Err(UnknownHeader);
+    }
+    bincode::deserialize(&payload[1..])
+        .map(Some)
+        .map_err(|_| UnknownHeader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_survive_both_framings() {
+        let headers = [
+            StreamHeader::TcpConnect {
+                host: "example.com:443".into(),
+            },
+            StreamHeader::UdpAssociate,
+            StreamHeader::Dns,
+            StreamHeader::VpnControl,
+            StreamHeader::Auth,
+        ];
+        for header in headers.iter() {
+            let payload = encode_header(Some(header), features::MUX_V2);
+            assert_eq!(
+                decode_header(&payload, features::MUX_V2),
+                Ok(Some(header.clone()))
+            );
+        }
+        // the original framing carries bare hostnames and nothing else
+        let payload = encode_header(Some(&headers[0]), features::MUX_V1);
+        assert_eq!(&payload[..], b"example.com:443");
+        assert_eq!(
+            decode_header(&payload, features::MUX_V1),
+            Ok(Some(headers[0].clone()))
+        );
+        assert!(encode_header(Some(&StreamHeader::Auth), features::MUX_V1).is_empty());
+        assert_eq!(decode_header(&[], features::MUX_V2), Ok(None));
+        // headers this end can't read are told apart from no header at all
+        assert_eq!(
+            decode_header(&[HEADER_VERSION + 1, 0], features::MUX_V2),
+            Err(UnknownHeader)
+        );
+        assert_eq!(
+            decode_header(&[HEADER_VERSION, 200, 0, 0, 0], features::MUX_V2),
+            Err(UnknownHeader)
+        );
+    }
+}
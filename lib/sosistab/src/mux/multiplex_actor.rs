@@ -1,23 +1,38 @@
 use crate::*;
 use bytes::Bytes;
 use dashmap::DashMap;
+use mux::header::{decode_header, encode_header, UnknownHeader};
 use mux::relconn::{RelConn, RelConnBack, RelConnState};
 use mux::structs::*;
+use mux::StreamHeader;
 use rand::prelude::*;
 use smol::channel::{Receiver, Sender};
 use smol::prelude::*;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+
+/// How long a freed 16-bit stream ID is kept out of use. The other end's copy of the stream may sit in reset for this long, and while it does, it answers a Syn for its ID as if the stream were its own.
+const ID_LINGER: Duration = Duration::from_secs(mux::relconn::MAX_WAIT_SECS);
 
 pub async fn multiplex(
     session: Arc<Session>,
     urel_send_recv: Receiver<Bytes>,
     urel_recv_send: Sender<Bytes>,
-    conn_open_recv: Receiver<(Option<String>, Sender<RelConn>)>,
+    conn_open_recv: Receiver<(Option<StreamHeader>, Sender<std::io::Result<RelConn>>)>,
     conn_accept_send: Sender<RelConn>,
 ) -> anyhow::Result<()> {
-    let conn_tab = Arc::new(ConnTable::default());
+    let features = session.features();
+    let conn_tab = Arc::new(ConnTable::new(stream_ids(features, session.is_client())));
     let (glob_send, glob_recv) = smol::channel::bounded(1000);
+    let (data_send, data_recv) = smol::channel::bounded(1000);
     let (dead_send, dead_recv) = smol::channel::unbounded();
+    // outlives each round of events, since a message waiting for room in the session mustn't be lost
+    let send_loop =
+        send_loop(&session, glob_recv, features).or(data_send_loop(&session, data_recv, features));
+    smol::pin!(send_loop);
     loop {
         // fires on receiving messages
         let recv_evt = async {
@@ -25,8 +40,7 @@ pub async fn multiplex(
                 .recv_bytes()
                 .await
                 .ok_or_else(|| anyhow::anyhow!("underlying session is dead"))?;
-            let msg = bincode::deserialize::<Message>(&msg);
-            if let Ok(msg) = msg {
+            if let Some(msg) = Message::decode(&msg, features) {
                 match msg {
                     // unreliable
                     Message::Urel(bts) => {
@@ -43,33 +57,52 @@ pub async fn multiplex(
                         if conn_tab.get_stream(stream_id).is_some() {
                             tracing::trace!("syn recv {} REACCEPT", stream_id);
                             session
-                                .send_bytes(
-                                    bincode::serialize(&Message::Rel {
+                                .send_prioritized(
+                                    Message::Rel {
                                         kind: RelKind::SynAck,
                                         stream_id,
                                         seqno: 0,
                                         payload: Bytes::new(),
-                                    })
-                                    .unwrap()
-                                    .into(),
+                                    }
+                                    .encode(features),
+                                    Priority::Control,
                                 )
                                 .await;
                         } else {
-                            let dead_send = dead_send.clone();
-                            tracing::trace!("syn recv {} ACCEPT", stream_id);
-                            let lala = String::from_utf8_lossy(&payload).to_string();
-                            let additional_info = if &lala == "" { None } else { Some(lala) };
-                            let (new_conn, new_conn_back) = RelConn::new(
-                                RelConnState::SynReceived { stream_id },
-                                glob_send.clone(),
-                                move || {
-                                    let _ = dead_send.try_send(stream_id);
-                                },
-                                additional_info,
-                            );
-                            // the RelConn itself is responsible for sending the SynAck. Here we just store the connection into the table, accept it, and be done with it.
-                            conn_tab.set_stream(stream_id, new_conn_back);
-                            drop(conn_accept_send.send(new_conn).await);
+                            match decode_header(&payload, features) {
+                                Ok(header) => {
+                                    let dead_send = dead_send.clone();
+                                    tracing::trace!("syn recv {} ACCEPT", stream_id);
+                                    let (new_conn, new_conn_back) = RelConn::new(
+                                        RelConnState::SynReceived { stream_id },
+                                        glob_send.clone(),
+                                        data_send.clone(),
+                                        move || {
+                                            let _ = dead_send.try_send(stream_id);
+                                        },
+                                        header,
+                                    );
+                                    // the RelConn itself is responsible for sending the SynAck. Here we just store the connection into the table, accept it, and be done with it.
+                                    conn_tab.set_stream(stream_id, new_conn_back);
+                                    drop(conn_accept_send.send(new_conn).await);
+                                }
+                                Err(UnknownHeader) => {
+                                    // refuse it outright, instead of handing over a stream that nobody knows what to do with
+                                    tracing::debug!("syn recv {} REFUSE unknown header", stream_id);
+                                    session
+                                        .send_prioritized(
+                                            Message::Rel {
+                                                kind: RelKind::Rst,
+                                                stream_id,
+                                                seqno: 0,
+                                                payload: Bytes::new(),
+                                            }
+                                            .encode(features),
+                                            Priority::Control,
+                                        )
+                                        .await;
+                                }
+                            }
                         }
                     }
                     // associated with existing connection
@@ -83,15 +116,15 @@ pub async fn multiplex(
                             tracing::trace!("discarding {:?} to nonexistent {}", kind, stream_id);
                             if kind != RelKind::Rst {
                                 session
-                                    .send_bytes(
-                                        bincode::serialize(&Message::Rel {
+                                    .send_prioritized(
+                                        Message::Rel {
                                             kind: RelKind::Rst,
                                             stream_id,
                                             seqno: 0,
                                             payload: Bytes::new(),
-                                        })
-                                        .unwrap()
-                                        .into(),
+                                        }
+                                        .encode(features),
+                                        Priority::Control,
                                     )
                                     .await;
                             }
@@ -101,14 +134,6 @@ pub async fn multiplex(
             }
             Ok::<(), anyhow::Error>(())
         };
-        // fires on sending messages
-        let send_evt = async {
-            let to_send = glob_recv.recv().await?;
-            session
-                .send_bytes(bincode::serialize(&to_send).unwrap().into())
-                .await;
-            Ok::<(), anyhow::Error>(())
-        };
         // fires on a new unreliable sending request
         let urel_send_evt = async {
             let to_send = urel_send_recv.recv().await?;
@@ -118,9 +143,11 @@ pub async fn multiplex(
         };
         // fires on a new stream open request
         let conn_open_evt = async {
-            let (additional_data, result_chan) = conn_open_recv.recv().await?;
+            let (header, result_chan) = conn_open_recv.recv().await?;
+            let syn_payload = encode_header(header.as_ref(), features);
             let conn_tab = conn_tab.clone();
             let glob_send = glob_send.clone();
+            let data_send = data_send.clone();
             let dead_send = dead_send.clone();
             runtime::spawn(async move {
                 let stream_id = {
@@ -132,22 +159,26 @@ pub async fn multiplex(
                                 stream_id,
                                 tries: 0,
                                 result: send_sig,
+                                syn_payload: syn_payload.clone(),
                             },
                             glob_send.clone(),
+                            data_send,
                             move || {
                                 let _ = dead_send.try_send(stream_id);
                             },
-                            additional_data.clone(),
+                            header,
                         );
                         runtime::spawn(async move {
                             recv_sig.recv().await.ok()?;
-                            result_chan.send(conn).await.ok()?;
+                            result_chan.send(Ok(conn)).await.ok()?;
                             Some(())
                         })
                         .detach();
                         conn_tab.set_stream(stream_id, conn_back);
                         stream_id
                     } else {
+                        let err = std::io::Error::other("ran out of stream IDs");
+                        drop(result_chan.send(Err(err)).await);
                         return;
                     }
                 };
@@ -158,9 +189,7 @@ pub async fn multiplex(
                             kind: RelKind::Syn,
                             stream_id,
                             seqno: 0,
-                            payload: Bytes::copy_from_slice(
-                                additional_data.clone().unwrap_or_default().as_bytes(),
-                            ),
+                            payload: syn_payload,
                         })
                         .await,
                 );
@@ -177,46 +206,245 @@ pub async fn multiplex(
         };
         // await on them all
         recv_evt
-            .or(urel_send_evt.or(send_evt.or(conn_open_evt.or(dead_evt))))
+            .or(urel_send_evt.or(send_loop.as_mut().or(conn_open_evt.or(dead_evt))))
             .await?;
     }
 }
 
-#[derive(Default)]
+/// Sends messages from RelConns and elsewhere into the session, until something goes wrong. These never wait for room in the session, so a stream stuck behind a full session doesn't hold up the others' control messages and acks.
+async fn send_loop(
+    session: &Session,
+    glob_recv: Receiver<Message>,
+    features: u64,
+) -> anyhow::Result<()> {
+    loop {
+        let msg = glob_recv.recv().await?;
+        let priority = msg.priority();
+        session
+            .send_prioritized(msg.encode(features), priority)
+            .await;
+    }
+}
+
+/// Sends stream data from RelConns into the session, until something goes wrong.
+async fn data_send_loop(
+    session: &Session,
+    data_recv: Receiver<Message>,
+    features: u64,
+) -> anyhow::Result<()> {
+    loop {
+        let msg = data_recv.recv().await?;
+        let priority = msg.priority();
+        // stream data waits for room, so that RelConns slow down rather than seeing losses that never happened
+        session
+            .send_bytes_reliable(msg.encode(features), priority)
+            .await;
+    }
+}
+
+/// The streams of a session, by ID. Messages carry no generation alongside the ID to tell a stream from an earlier one under the same ID: with 32-bit IDs, an ID only comes around again after billions of streams, long after anything left of the old stream is gone, and the 16-bit framings older peers speak have no room for one. Instead, freed 16-bit IDs sit out `ID_LINGER` before they are handed out again.
 struct ConnTable {
     /// Maps IDs to RelConn back handles.
-    sid_to_stream: DashMap<u16, RelConnBack>,
+    sid_to_stream: DashMap<StreamId, RelConnBack>,
+    /// The IDs this end hands out. The table also holds streams the other end opened, under IDs of its own.
+    ids: StreamIds,
+    /// Counts up through the IDs, starting from a random one. A freed ID is only handed out again once the count comes back around to it, so stale packets for a closed stream are unlikely to reach a new one. With 32-bit IDs, that takes billions of streams.
+    next_id: AtomicU64,
+    /// When recently freed IDs were freed, if there are few enough IDs for the count to come back around to them within `ID_LINGER`.
+    freed: Option<DashMap<StreamId, Instant>>,
 }
 
 impl ConnTable {
-    fn get_stream(&self, sid: u16) -> Option<RelConnBack> {
+    fn new(ids: StreamIds) -> Self {
+        ConnTable {
+            sid_to_stream: DashMap::new(),
+            ids,
+            next_id: AtomicU64::new(rand::thread_rng().gen_range(0, ids.count())),
+            freed: if ids.space <= 1 << 16 {
+                Some(DashMap::new())
+            } else {
+                None
+            },
+        }
+    }
+
+    fn get_stream(&self, sid: StreamId) -> Option<RelConnBack> {
         let x = self.sid_to_stream.get(&sid)?;
         Some(x.clone())
     }
 
-    fn set_stream(&self, id: u16, handle: RelConnBack) {
+    fn set_stream(&self, id: StreamId, handle: RelConnBack) {
         self.sid_to_stream.insert(id, handle);
     }
 
-    fn del_stream(&self, id: u16) {
-        self.sid_to_stream.remove(&id);
+    fn del_stream(&self, id: StreamId) {
+        if self.sid_to_stream.remove(&id).is_some() {
+            if let Some(freed) = &self.freed {
+                freed.insert(id, Instant::now());
+            }
+        }
     }
 
-    fn find_id(&self) -> Option<u16> {
-        if self.sid_to_stream.len() >= 65535 {
-            tracing::warn!("ran out of descriptors ({})", self.sid_to_stream.len());
-            return None;
+    /// Whether the ID was freed too recently to hand out again.
+    fn lingering(&self, id: StreamId) -> bool {
+        let freed = match &self.freed {
+            Some(freed) => freed,
+            None => return false,
+        };
+        let lingering = match freed.get(&id) {
+            Some(freed_at) => freed_at.elapsed() < ID_LINGER,
+            None => return false,
+        };
+        if !lingering {
+            freed.remove(&id);
         }
-        loop {
-            let possible_id: u16 = rand::thread_rng().gen();
-            if self.sid_to_stream.get(&possible_id).is_none() {
+        lingering
+    }
+
+    fn find_id(&self) -> Option<StreamId> {
+        let freed_count = self.freed.as_ref().map(|freed| freed.len()).unwrap_or(0);
+        // the other end's streams may be taking up some of our IDs too, if the framing makes us share them
+        for _ in 0..self
+            .ids
+            .count()
+            .min((self.sid_to_stream.len() + freed_count) as u64 + 1)
+        {
+            let possible_id = self.ids.nth(self.next_id.fetch_add(1, Ordering::Relaxed));
+            if self.sid_to_stream.get(&possible_id).is_none() && !self.lingering(possible_id) {
                 tracing::debug!(
                     "found id {} out of {}",
                     possible_id,
                     self.sid_to_stream.len()
                 );
-                break Some(possible_id);
+                return Some(possible_id);
+            }
+        }
+        tracing::warn!("ran out of descriptors ({})", self.sid_to_stream.len());
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_ids_run_out() {
+        smol::block_on(async {
+            let table = ConnTable::new(StreamIds {
+                space: 4,
+                stride: 1,
+                offset: 0,
+            });
+            let (output, _output_recv) = smol::channel::unbounded();
+            let mut conns = Vec::new();
+            for _ in 0..4 {
+                let stream_id = table.find_id().unwrap();
+                let (conn, conn_back) = RelConn::new(
+                    RelConnState::SynReceived { stream_id },
+                    output.clone(),
+                    output.clone(),
+                    || {},
+                    None,
+                );
+                table.set_stream(stream_id, conn_back);
+                conns.push((stream_id, conn));
             }
+            let mut ids: Vec<StreamId> = conns.iter().map(|(id, _)| *id).collect();
+            ids.sort_unstable();
+            assert_eq!(ids, vec![0, 1, 2, 3]);
+            assert_eq!(table.find_id(), None);
+            // a freed ID sits out the other end's reset first
+            table.del_stream(2);
+            assert_eq!(table.find_id(), None);
+            // and then comes back once the count wraps around to it
+            let freed_at = Instant::now() - ID_LINGER;
+            table.freed.as_ref().unwrap().insert(2, freed_at);
+            assert_eq!(table.find_id(), Some(2));
+        })
+    }
+
+    /// Files a stream under the given ID in each of the tables, as the mux at each end of a session does.
+    fn add_stream(tables: &[&ConnTable], stream_id: StreamId, conns: &mut Vec<RelConn>) {
+        let (output, _output_recv) = smol::channel::unbounded();
+        for table in tables {
+            assert!(table.get_stream(stream_id).is_none());
+            let (conn, conn_back) = RelConn::new(
+                RelConnState::SynReceived { stream_id },
+                output.clone(),
+                output.clone(),
+                || {},
+                None,
+            );
+            table.set_stream(stream_id, conn_back);
+            conns.push(conn);
         }
     }
+
+    #[test]
+    fn ends_open_streams_apart() {
+        smol::block_on(async {
+            let client = ConnTable::new(stream_ids(features::MUX_V3, true));
+            let server = ConnTable::new(stream_ids(features::MUX_V3, false));
+            let mut conns = Vec::new();
+            for _ in 0..100 {
+                let stream_id = client.find_id().unwrap();
+                assert_eq!(stream_id % 2, 1);
+                add_stream(&[&client, &server], stream_id, &mut conns);
+                let stream_id = server.find_id().unwrap();
+                assert_eq!(stream_id % 2, 0);
+                add_stream(&[&client, &server], stream_id, &mut conns);
+            }
+        })
+    }
+
+    #[test]
+    fn shared_ids_skip_the_other_ends() {
+        smol::block_on(async {
+            let ids = StreamIds {
+                space: 4,
+                stride: 1,
+                offset: 0,
+            };
+            let (ours, theirs) = (ConnTable::new(ids), ConnTable::new(ids));
+            let mut conns = Vec::new();
+            for _ in 0..2 {
+                let stream_id = theirs.find_id().unwrap();
+                add_stream(&[&ours, &theirs], stream_id, &mut conns);
+            }
+            for _ in 0..2 {
+                let stream_id = ours.find_id().unwrap();
+                add_stream(&[&ours, &theirs], stream_id, &mut conns);
+            }
+            assert_eq!(ours.find_id(), None);
+        })
+    }
+
+    #[test]
+    fn refused_streams_fail_to_open() {
+        smol::block_on(async {
+            let (output, _output_recv) = smol::channel::unbounded();
+            let (result, opened) = smol::channel::bounded(1);
+            let (_conn, conn_back) = RelConn::new(
+                RelConnState::SynSent {
+                    stream_id: 0,
+                    tries: 0,
+                    result,
+                    syn_payload: Bytes::new(),
+                },
+                output.clone(),
+                output,
+                || {},
+                None,
+            );
+            // what the other end answers a Syn with when it can't read the stream header
+            conn_back.process(Message::Rel {
+                kind: RelKind::Rst,
+                stream_id: 0,
+                seqno: 0,
+                payload: Bytes::new(),
+            });
+            assert!(opened.recv().await.is_err());
+        })
+    }
 }
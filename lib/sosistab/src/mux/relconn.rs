@@ -4,7 +4,8 @@ use async_dup::Mutex as DMutex;
 use bipe::{BipeReader, BipeWriter};
 use bytes::{Bytes, BytesMut};
 use connvars::ConnVars;
-use mux::structs::{Message, RelKind, Seqno};
+use mux::structs::{Message, RelKind, Seqno, StreamId};
+use mux::StreamHeader;
 use smol::channel::{Receiver, Sender};
 use smol::prelude::*;
 use std::{
@@ -23,26 +24,32 @@ mod connvars;
 mod inflight;
 
 pub const MSS: usize = 1100;
-const MAX_WAIT_SECS: u64 = 60;
+pub(crate) const MAX_WAIT_SECS: u64 = 60;
+/// Bytes buffered for a reader that falls behind. The receive window never covers more than this.
+pub(crate) const RECV_BUFFER: usize = 1024 * 1024;
+/// How many packets the receive window has to grow by before an update is sent just for it.
+const WINDOW_UPDATE_STEP: Seqno = (RECV_BUFFER / MSS / 4) as Seqno;
+/// How long a sender that has used up the receive window waits before probing for a bigger one, at first. The wait doubles with every probe, up to 16 times this.
+const WINDOW_PROBE_INTERVAL: Duration = Duration::from_millis(250);
 
 #[derive(Clone)]
 pub struct RelConn {
     send_write: DArc<DMutex<BipeWriter>>,
     recv_read: DArc<DMutex<BipeReader>>,
-    additional_info: Option<String>,
+    header: Option<StreamHeader>,
 }
 
 impl RelConn {
     pub(crate) fn new(
         state: RelConnState,
         output: Sender<Message>,
+        data_output: Sender<Message>,
         dropper: impl FnOnce() + Send + 'static,
-        additional_info: Option<String>,
+        header: Option<StreamHeader>,
     ) -> (Self, RelConnBack) {
         let (send_write, recv_write) = bipe::bipe(64 * 1024);
-        let (send_read, recv_read) = bipe::bipe(1024 * 1024);
+        let (send_read, recv_read) = bipe::bipe(RECV_BUFFER);
         let (send_wire_read, recv_wire_read) = smol::channel::bounded(1024);
-        let aic = additional_info.clone();
         let _task = runtime::spawn(async move {
             if let Err(e) = relconn_actor(
                 state,
@@ -50,7 +57,7 @@ impl RelConn {
                 send_read,
                 recv_wire_read,
                 output,
-                aic,
+                data_output,
                 dropper,
             )
             .await
@@ -62,7 +69,7 @@ impl RelConn {
             RelConn {
                 send_write: DArc::new(DMutex::new(send_write)),
                 recv_read: DArc::new(DMutex::new(recv_read)),
-                additional_info,
+                header,
             },
             RelConnBack {
                 send_wire_read,
@@ -71,8 +78,9 @@ impl RelConn {
         )
     }
 
-    pub fn additional_info(&self) -> Option<&str> {
-        self.additional_info.as_deref()
+    /// What the other end said the conn is for, if it said.
+    pub fn header(&self) -> Option<&StreamHeader> {
+        self.header.as_ref()
     }
 
     pub async fn shutdown(&mut self) {
@@ -118,19 +126,20 @@ impl AsyncWrite for RelConn {
 
 pub(crate) enum RelConnState {
     SynReceived {
-        stream_id: u16,
+        stream_id: StreamId,
     },
     SynSent {
-        stream_id: u16,
+        stream_id: StreamId,
         tries: usize,
         result: Sender<()>,
+        syn_payload: Bytes,
     },
     SteadyState {
-        stream_id: u16,
+        stream_id: StreamId,
         conn_vars: Box<ConnVars>,
     },
     Reset {
-        stream_id: u16,
+        stream_id: StreamId,
         death: smol::Timer,
     },
 }
@@ -142,7 +151,7 @@ async fn relconn_actor(
     mut send_read: BipeWriter,
     recv_wire_read: Receiver<Message>,
     send_wire_write: Sender<Message>,
-    additional_info: Option<String>,
+    send_wire_data: Sender<Message>,
     dropper: impl FnOnce(),
 ) -> anyhow::Result<()> {
     let _guard = scopeguard::guard((), |_| dropper());
@@ -151,13 +160,23 @@ async fn relconn_actor(
     enum Evt {
         Rto(Option<(Seqno, bool)>),
         AckTimer,
+        WindowUpdate,
+        WindowProbe,
         NewWrite(Bytes),
         NewPkt(Message),
         Closing,
     }
 
-    let transmit = |msg| async {
-        drop(send_wire_write.send(msg).await);
+    // data goes through a queue of its own, so that waiting for room there never holds up control messages or acks
+    let transmit = |msg: Message| async {
+        let output = match msg {
+            Message::Rel {
+                kind: RelKind::Data,
+                ..
+            } => &send_wire_data,
+            _ => &send_wire_write,
+        };
+        drop(output.send(msg).await);
         smol::future::yield_now().await;
     };
     let mut fragments: VecDeque<Bytes> = VecDeque::new();
@@ -185,6 +204,7 @@ async fn relconn_actor(
                 stream_id,
                 tries,
                 result,
+                syn_payload,
             } => {
                 let wait_interval = 500u64 * 2u64.pow(tries as u32);
                 tracing::debug!("C={} SynSent, tried {} times", stream_id, tries);
@@ -194,6 +214,10 @@ async fn relconn_actor(
                 let synack_evt = async {
                     loop {
                         match recv_wire_read.recv().await? {
+                            // the other end didn't want the stream, so there's no use trying again
+                            Message::Rel {
+                                kind: RelKind::Rst, ..
+                            } => anyhow::bail!("refused"),
                             Message::Rel { .. } => return Ok::<_, anyhow::Error>(true),
                             _ => continue,
                         }
@@ -218,18 +242,14 @@ async fn relconn_actor(
                         kind: RelKind::Syn,
                         stream_id,
                         seqno: 0,
-                        payload: Bytes::copy_from_slice(
-                            additional_info
-                                .as_ref()
-                                .unwrap_or(&"".to_string())
-                                .as_bytes(),
-                        ),
+                        payload: syn_payload.clone(),
                     })
                     .await;
                     SynSent {
                         stream_id,
                         tries: tries + 1,
                         result,
+                        syn_payload,
                     }
                 }
             }
@@ -240,6 +260,7 @@ async fn relconn_actor(
                 let event = {
                     let writeable = conn_vars.inflight.inflight() <= conn_vars.cwnd as usize
                         && conn_vars.inflight.len() < 10000
+                        && conn_vars.peer_has_room()
                         && !conn_vars.closing;
                     let force_ack = conn_vars.ack_seqnos.len() >= 32;
 
@@ -255,6 +276,39 @@ async fn relconn_actor(
                             smol::future::pending().await
                         }
                     };
+                    // fires once the reader has made enough room to be worth advertising
+                    let update_room = (conn_vars.advertised_window + WINDOW_UPDATE_STEP)
+                        .saturating_sub(conn_vars.lowest_unseen)
+                        as usize
+                        * MSS;
+                    let window_update = async {
+                        loop {
+                            let changed = send_read.changed();
+                            if send_read.free_space() >= update_room {
+                                return Ok::<Evt, anyhow::Error>(Evt::WindowUpdate);
+                            }
+                            changed.await;
+                        }
+                    };
+                    // a lost window update would leave a sender that used up the window waiting forever, so it asks again every so often
+                    if !conn_vars.peer_has_room() && conn_vars.inflight.len() == 0 {
+                        let probe_wait =
+                            WINDOW_PROBE_INTERVAL * (1 << conn_vars.window_probes.min(4));
+                        conn_vars
+                            .window_probe_timer
+                            .get_or_insert_with(|| Instant::now() + probe_wait);
+                    } else {
+                        conn_vars.window_probe_timer = None;
+                    }
+                    let probe_timer = conn_vars.window_probe_timer;
+                    let window_probe = async {
+                        if let Some(time) = probe_timer {
+                            smol::Timer::at(time).await;
+                            Ok::<Evt, anyhow::Error>(Evt::WindowProbe)
+                        } else {
+                            smol::future::pending().await
+                        }
+                    };
                     let rto_timer = conn_vars.inflight.wait_first();
                     let rto_timeout = async { Ok::<Evt, anyhow::Error>(Evt::Rto(rto_timer.await)) };
                     let new_write = async {
@@ -299,7 +353,10 @@ async fn relconn_actor(
                     let new_pkt = async {
                         Ok::<Evt, anyhow::Error>(Evt::NewPkt(recv_wire_read.recv().await?))
                     };
-                    new_pkt.or(ack_timer.or(rto_timeout.or(new_write))).await
+                    new_pkt
+                        .or(ack_timer.or(window_update.or(window_probe)))
+                        .or(rto_timeout.or(new_write))
+                        .await
                 };
                 match event {
                     Ok(Evt::Closing) => {
@@ -366,14 +423,33 @@ async fn relconn_actor(
                         ..
                     })) => {
                         tracing::trace!("new ACK pkt with {} seqnos", payload.len() / 2);
-                        for seqno in
-                            bincode::deserialize::<BTreeSet<Seqno>>(&payload).unwrap_or_default()
-                        {
+                        let mut reader = &payload[..];
+                        let acked: BTreeSet<Seqno> =
+                            bincode::deserialize_from(&mut reader).unwrap_or_default();
+                        let window_blocked = !conn_vars.peer_has_room();
+                        // the receive window trails the acks, and older peers don't send it
+                        if let Ok(window) = bincode::deserialize_from::<_, Seqno>(&mut reader) {
+                            if conn_vars
+                                .peer_window
+                                .map(|old| window > old)
+                                .unwrap_or(true)
+                            {
+                                conn_vars.peer_window = Some(window);
+                                conn_vars.window_probes = 0;
+                            }
+                        }
+                        for seqno in acked {
                             if conn_vars.inflight.mark_acked(seqno) {
                                 conn_vars.congestion_ack();
                             }
                         }
                         conn_vars.inflight.mark_acked_lt(seqno);
+                        if window_blocked
+                            && conn_vars.peer_has_room()
+                            && conn_vars.inflight.len() == 0
+                        {
+                            conn_vars.congestion_restart();
+                        }
                         implied_rate.store(conn_vars.pacing_rate() as u32, Ordering::Relaxed);
                         if conn_vars.inflight.len() == 0 && conn_vars.closing {
                             Reset {
@@ -443,9 +519,28 @@ async fn relconn_actor(
                             conn_vars,
                         }
                     }
-                    Ok(Evt::AckTimer) => {
+                    Ok(Evt::WindowProbe) => {
+                        // everything sent so far was acked, so the receiver drops an empty copy of the last packet, but it still acks it with its window
+                        tracing::trace!("C={} probing for a bigger window", stream_id);
+                        conn_vars.window_probes += 1;
+                        conn_vars.window_probe_timer = None;
+                        transmit(Message::Rel {
+                            kind: RelKind::Data,
+                            stream_id,
+                            seqno: conn_vars.next_free_seqno.saturating_sub(1),
+                            payload: Bytes::new(),
+                        })
+                        .await;
+                        SteadyState {
+                            stream_id,
+                            conn_vars,
+                        }
+                    }
+                    Ok(Evt::AckTimer) | Ok(Evt::WindowUpdate) => {
                         // eprintln!("acking {} seqnos", conn_vars.ack_seqnos.len());
-                        let encoded_acks = bincode::serialize(&conn_vars.ack_seqnos).unwrap();
+                        let window = conn_vars.recv_window(send_read.free_space());
+                        let mut encoded_acks = bincode::serialize(&conn_vars.ack_seqnos).unwrap();
+                        encoded_acks.extend_from_slice(&bincode::serialize(&window).unwrap());
                         transmit(Message::Rel {
                             kind: RelKind::DataAck,
                             stream_id,
@@ -453,6 +548,7 @@ async fn relconn_actor(
                             payload: Bytes::copy_from_slice(&encoded_acks),
                         })
                         .await;
+                        conn_vars.advertised_window = window;
                         conn_vars.ack_seqnos.clear();
                         conn_vars.delayed_ack_timer = None;
                         SteadyState {
@@ -38,6 +38,18 @@ impl Drop for BipeWriter {
     }
 }
 
+impl BipeWriter {
+    /// How many more bytes fit before writes have to wait.
+    pub fn free_space(&self) -> usize {
+        self.capacity.saturating_sub(self.queue.lock().1.len())
+    }
+
+    /// Fires the next time either end touches the pipe, such as when the reader takes bytes out.
+    pub fn changed(&self) -> event_listener::EventListener {
+        self.signal.listen()
+    }
+}
+
 fn broken_pipe() -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::ConnectionReset, "broken pipe")
 }
@@ -4,7 +4,7 @@ use bytes::Bytes;
 
 use crate::mux::structs::*;
 
-use super::inflight::Inflight;
+use super::{inflight::Inflight, MSS, RECV_BUFFER};
 
 pub(crate) struct ConnVars {
     pub pre_inflight: VecDeque<Message>,
@@ -17,6 +17,14 @@ pub(crate) struct ConnVars {
 
     pub reorderer: Reorderer<Bytes>,
     pub lowest_unseen: Seqno,
+    /// The receive window last advertised to the other end, as the first seqno it may not send yet.
+    pub advertised_window: Seqno,
+    /// The other end's receive window, if it advertises one.
+    pub peer_window: Option<Seqno>,
+    /// How many times the other end was probed for a bigger receive window since it last sent one.
+    pub window_probes: u32,
+    /// When to probe next, while sending waits on the other end's receive window.
+    pub window_probe_timer: Option<Instant>,
     // read_buffer: VecDeque<Bytes>,
     slow_start: bool,
     ssthresh: f64,
@@ -44,6 +52,10 @@ impl Default for ConnVars {
 
             reorderer: Reorderer::default(),
             lowest_unseen: 0,
+            advertised_window: (RECV_BUFFER / MSS) as Seqno,
+            peer_window: None,
+            window_probes: 0,
+            window_probe_timer: None,
 
             slow_start: true,
             cwnd: 64.0,
@@ -61,6 +73,18 @@ impl Default for ConnVars {
 }
 
 impl ConnVars {
+    /// The receive window to advertise, given how much room is left in the receive buffer. It only ever grows, since every packet delivered takes up at most an MSS of room.
+    pub fn recv_window(&self, free_space: usize) -> Seqno {
+        (self.lowest_unseen + (free_space / MSS) as Seqno).max(self.advertised_window)
+    }
+
+    /// Whether the other end's receive window has room for another packet.
+    pub fn peer_has_room(&self) -> bool {
+        self.peer_window
+            .map(|window| self.next_free_seqno < window)
+            .unwrap_or(true)
+    }
+
     pub fn pacing_rate(&self) -> f64 {
         // calculate implicit rate
         self.cwnd / self.inflight.min_rtt().as_secs_f64()
@@ -81,6 +105,13 @@ impl ConnVars {
         }
     }
 
+    /// Starts the congestion window over after the receive window held up sending until nothing was left in flight, since a window that was never used says little about the path now.
+    pub fn congestion_restart(&mut self) {
+        self.ssthresh = self.ssthresh.max(self.cwnd);
+        self.cwnd = self.cwnd.min(64.0);
+        self.slow_start = true;
+    }
+
     pub fn congestion_loss(&mut self) {
         self.slow_start = false;
         self.loss_rate = self.loss_rate * 0.99 + 0.01;
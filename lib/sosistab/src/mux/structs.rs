@@ -1,3 +1,4 @@
+use crate::{features, Priority};
 use bytes::Bytes;
 use rustc_hash::FxHashMap;
 use serde::{Deserialize, Serialize};
@@ -5,9 +6,24 @@ use serde::{Deserialize, Serialize};
 /// A sequence number.
 pub type Seqno = u64;
 
+/// A stream ID.
+pub type StreamId = u32;
+
 /// A message.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Message {
+    Urel(Bytes),
+    Rel {
+        kind: RelKind,
+        stream_id: StreamId,
+        seqno: Seqno,
+        payload: Bytes,
+    },
+}
+
+/// A message as framed before stream IDs grew to 32 bits.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum LegacyMessage {
     Urel(Bytes),
     Rel {
         kind: RelKind,
@@ -17,6 +33,104 @@ pub enum Message {
     },
 }
 
+/// The stream IDs that one end of a session hands out: every `stride`th ID from `offset`, below `space`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamIds {
+    pub space: u64,
+    pub stride: u64,
+    pub offset: u64,
+}
+
+impl StreamIds {
+    /// How many IDs there are to hand out.
+    pub fn count(&self) -> u64 {
+        (self.space - self.offset).div_ceil(self.stride)
+    }
+
+    /// The `n`th ID to hand out, counting around.
+    pub fn nth(&self, n: u64) -> StreamId {
+        ((n % self.count()) * self.stride + self.offset) as StreamId
+    }
+}
+
+/// Which stream IDs this end of a session hands out. With 32-bit IDs, clients take the odd ones and servers the even ones, so that streams both ends open at once never share an ID. The 16-bit framings have no room to spare, so both ends draw from all of them, as older peers do.
+pub fn stream_ids(features: u64, is_client: bool) -> StreamIds {
+    if features & features::MUX_V3 != 0 {
+        StreamIds {
+            space: 1 << 32,
+            stride: 2,
+            offset: is_client as u64,
+        }
+    } else {
+        StreamIds {
+            space: 1 << 16,
+            stride: 1,
+            offset: 0,
+        }
+    }
+}
+
+/// Unreliable messages up to this long, like DNS queries and bare TCP ACKs, are sent as interactive traffic.
+const INTERACTIVE_URELS: usize = 256;
+
+impl Message {
+    /// Encodes the message in the mux framing the session negotiated. Peers with 16-bit stream IDs are only ever given IDs that fit.
+    pub fn encode(&self, features: u64) -> Bytes {
+        if features & features::MUX_V3 != 0 {
+            return bincode::serialize(self).unwrap().into();
+        }
+        let legacy = match self.clone() {
+            Message::Urel(bts) => LegacyMessage::Urel(bts),
+            Message::Rel {
+                kind,
+                stream_id,
+                seqno,
+                payload,
+            } => LegacyMessage::Rel {
+                kind,
+                stream_id: stream_id as u16,
+                seqno,
+                payload,
+            },
+        };
+        bincode::serialize(&legacy).unwrap().into()
+    }
+
+    /// Decodes a message in the mux framing the session negotiated.
+    pub fn decode(bts: &[u8], features: u64) -> Option<Self> {
+        if features & features::MUX_V3 != 0 {
+            return bincode::deserialize(bts).ok();
+        }
+        Some(match bincode::deserialize(bts).ok()? {
+            LegacyMessage::Urel(bts) => Message::Urel(bts),
+            LegacyMessage::Rel {
+                kind,
+                stream_id,
+                seqno,
+                payload,
+            } => Message::Rel {
+                kind,
+                stream_id: stream_id as StreamId,
+                seqno,
+                payload,
+            },
+        })
+    }
+
+    /// How urgently the message should be sent. Stream setup and teardown must never be lost behind data.
+    pub fn priority(&self) -> Priority {
+        match self {
+            Message::Urel(bts) if bts.len() <= INTERACTIVE_URELS => Priority::Interactive,
+            Message::Urel(_) => Priority::Bulk,
+            Message::Rel { kind, .. } => match kind {
+                RelKind::Data => Priority::Bulk,
+                RelKind::DataAck => Priority::Interactive,
+                _ => Priority::Control,
+            },
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
 pub enum RelKind {
     Syn,
@@ -68,3 +182,30 @@ impl<T: Clone> Reorderer<T> {
         output
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_framing_keeps_short_ids() {
+        let msg = Message::Rel {
+            kind: RelKind::Data,
+            stream_id: 65535,
+            seqno: 7,
+            payload: Bytes::from_static(b"hello"),
+        };
+        let legacy = msg.encode(features::MUX_V1);
+        let current = msg.encode(features::MUX_V3);
+        assert_eq!(legacy.len() + 2, current.len());
+        for (encoded, features) in [(legacy, features::MUX_V1), (current, features::MUX_V3)].iter()
+        {
+            match Message::decode(encoded, *features) {
+                Some(Message::Rel {
+                    stream_id, seqno, ..
+                }) => assert_eq!((stream_id, seqno), (65535, 7)),
+                other => panic!("bad decode {:?}", other),
+            }
+        }
+    }
+}
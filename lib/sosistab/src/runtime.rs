@@ -26,8 +26,8 @@ pub(crate) fn spawn<T: Send + 'static>(
     }
 }
 
-/// Create a new UDP socket that has a largeish buffer and isn't bound to anything.
-pub(crate) async fn new_udp_socket_bind(
+/// Creates a new UDP socket bound to the given address, accepting both IPv4 and IPv6 if bound to an IPv6 address.
+pub async fn new_udp_socket_bind(
     addr: impl AsyncToSocketAddrs,
 ) -> std::io::Result<Async<UdpSocket>> {
     let addr = smol::net::resolve(addr).await?[0];
@@ -45,6 +45,27 @@ pub(crate) async fn new_udp_socket_bind(
     Ok(socket.into_udp_socket().try_into().unwrap())
 }
 
+/// Like [new_udp_socket_bind], but on Linux the kernel coalesces incoming datagrams with GRO. Only ever read such a socket with `Backhaul::recv_from_many`, since other reads can't tell where coalesced datagrams end.
+pub async fn new_udp_socket_bind_gro(
+    addr: impl AsyncToSocketAddrs,
+) -> std::io::Result<Async<UdpSocket>> {
+    let socket = new_udp_socket_bind(addr).await?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::os::unix::io::AsRawFd;
+        crate::backhaul::enable_gro(socket.as_raw_fd());
+    }
+    Ok(socket)
+}
+
+/// Turns UDP GSO on or off for batched sends from every socket. It's on by default wherever the kernel supports it; turn it off to work around network devices that mishandle it, or to compare against plain batching.
+pub fn set_udp_gso(enabled: bool) {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    crate::backhaul::set_gso(enabled);
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    drop(enabled);
+}
+
 // fn anything_socket_addr() -> SocketAddr {
 //     "0.0.0.0:0".parse::<SocketAddr>().unwrap()
 // }
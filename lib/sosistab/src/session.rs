@@ -1,8 +1,11 @@
-use crate::msg::DataFrame;
+use crate::msg::{DataFrame, Nack};
 use crate::runtime;
 use crate::{
-    fec::{FrameDecoder, FrameEncoder},
-    VarRateLimit,
+    features,
+    fec::{self, ErasureCode, FrameDecoder, FrameEncoder},
+    multipath::{PathStats, PathTable},
+    shape::{Shaper, TokenBucket},
+    TrafficShape, VarRateLimit,
 };
 use bytes::Bytes;
 use parking_lot::{Mutex, RwLock};
@@ -14,11 +17,34 @@ use std::{
     time::Instant,
 };
 use std::{
-    sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
     time::SystemTime,
 };
 use std::{sync::Arc, time::Duration};
 
+/// Mean burst length, in sixteenths of a frame, above which parity is interleaved with the next run.
+pub(crate) const INTERLEAVE_BURST_LEN: u8 = 32;
+/// How long interleaved parity waits for the next run before going out on its own.
+const INTERLEAVE_FLUSH: Duration = Duration::from_millis(10);
+/// How often the receiver looks for runs to NACK.
+const NACK_INTERVAL: Duration = Duration::from_millis(10);
+/// How long a run may be incomplete before it's NACKed, to give reordered shards a chance. Further NACKs of the same run also wait a round trip.
+const NACK_GRACE: Duration = Duration::from_millis(10);
+/// Longest round trip that further NACKs wait for, which also covers not knowing the ping yet.
+const NACK_MAX_RTT: Duration = Duration::from_millis(250);
+/// How many times a run is NACKed before giving up on it.
+const NACK_TRIES: u8 = 4;
+/// How many recently sent shards are kept around to answer NACKs.
+const HISTORY_SHARDS: usize = 1024;
+/// Frames per second the send loop paces itself to, so that bursts don't overrun socket buffers.
+const MAX_FRAME_RATE: u32 = 15000;
+/// Time constant of the smoothed rates in [SessionStats].
+const RATE_SMOOTHING: Duration = Duration::from_secs(2);
+/// How many times more bytes interactive packets get than bulk packets when both are queued.
+const INTERACTIVE_WEIGHT: u64 = 4;
+/// How long a dropped session's tasks may keep running to get the close frame out.
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
+
 async fn infal<T, E, F: Future<Output = std::result::Result<T, E>>>(fut: F) -> T {
     match fut.await {
         Ok(res) => res,
@@ -35,60 +61,240 @@ pub(crate) struct SessionConfig {
     pub send_frame: Sender<DataFrame>,
     pub recv_frame: Receiver<DataFrame>,
     pub recv_timeout: Duration,
+    pub reliable: bool,
+    pub version: u64,
+    pub features: u64,
+    /// Whether this end connected to the other, rather than accepting it.
+    pub is_client: bool,
+    pub shaper: Arc<Shaper>,
+    pub traffic: Arc<Traffic>,
+    pub paths: Arc<PathTable>,
+    /// Set once the session is being handed over to another listener, so that dropping it doesn't close it.
+    pub handing_over: Arc<AtomicBool>,
+}
+
+/// Running totals of what a session carries. The session counts application packets, while whatever sends and receives its datagrams counts those.
+#[derive(Debug, Default)]
+pub(crate) struct Traffic {
+    up_packets: AtomicU64,
+    up_bytes: AtomicU64,
+    up_wire_packets: AtomicU64,
+    up_wire_bytes: AtomicU64,
+    down_packets: AtomicU64,
+    down_bytes: AtomicU64,
+    down_wire_packets: AtomicU64,
+    down_wire_bytes: AtomicU64,
+    up_dropped: AtomicU64,
+    down_dropped: AtomicU64,
+}
+
+impl Traffic {
+    /// Counts an encrypted datagram sent for the session.
+    pub fn sent_datagram(&self, len: usize) {
+        self.up_wire_packets.fetch_add(1, Ordering::Relaxed);
+        self.up_wire_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a datagram received for the session.
+    pub fn received_datagram(&self, len: usize) {
+        self.down_wire_packets.fetch_add(1, Ordering::Relaxed);
+        self.down_wire_bytes
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn sent_packet(&self, len: usize) {
+        self.up_packets.fetch_add(1, Ordering::Relaxed);
+        self.up_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn received_packet(&self, len: usize) {
+        self.down_packets.fetch_add(1, Ordering::Relaxed);
+        self.down_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn dropped_outgoing(&self) {
+        self.up_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn dropped_incoming(&self) {
+        self.down_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// How urgently a packet needs to go out. Control packets go out before anything else and are never dropped, so they should be small and rare. Interactive and bulk packets share whatever is left, with interactive packets getting most of it, and are dropped when too many of them are queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Priority {
+    Control,
+    Interactive,
+    Bulk,
+}
+
+/// The sending side's end of the per-priority queues, which hands out packets for the send loop to send.
+#[derive(Debug)]
+struct SendQueues {
+    control: Receiver<Bytes>,
+    interactive: Receiver<Bytes>,
+    bulk: Receiver<Bytes>,
+    /// Next packet from each of the interactive and bulk queues.
+    heads: [Option<Bytes>; 2],
+    /// Bytes sent from each of the interactive and bulk queues, scaled down by their weights.
+    served: [u64; 2],
+}
+
+impl SendQueues {
+    /// Waits for the next packet to send. Interactive and bulk packets are weighted fairly by bytes, and a queue that goes idle doesn't save up a share for later.
+    async fn next(&mut self) -> Bytes {
+        loop {
+            if let Ok(pkt) = self.control.try_recv() {
+                return pkt;
+            }
+            for (head, queue) in self
+                .heads
+                .iter_mut()
+                .zip([&self.interactive, &self.bulk].iter())
+            {
+                if head.is_none() {
+                    *head = queue.try_recv().ok();
+                }
+            }
+            let idx = match (&self.heads[0], &self.heads[1]) {
+                (Some(_), Some(_)) => (self.served[0] > self.served[1]) as usize,
+                (Some(_), None) => 0,
+                (None, Some(_)) => 1,
+                (None, None) => {
+                    let (control, interactive, bulk) =
+                        (&self.control, &self.interactive, &self.bulk);
+                    let (idx, pkt) = async { (None, infal(control.recv()).await) }
+                        .or(async { (Some(0), infal(interactive.recv()).await) })
+                        .or(async { (Some(1), infal(bulk.recv()).await) })
+                        .await;
+                    match idx {
+                        None => return pkt,
+                        Some(idx) => self.heads[idx] = Some(pkt),
+                    }
+                    continue;
+                }
+            };
+            let pkt = self.heads[idx].take().expect("picked an empty queue");
+            let weight = if idx == 0 { 1 } else { INTERACTIVE_WEIGHT };
+            self.served[idx] += pkt.len() as u64 * weight;
+            if self.heads[1 - idx].is_none() {
+                self.served[1 - idx] = self.served[1 - idx].max(self.served[idx]);
+            }
+            return pkt;
+        }
+    }
 }
 
 /// Representation of an isolated session that deals only in DataFrames and abstracts away all I/O concerns. It's the user's responsibility to poll the session. Otherwise, it might not make progress and will drop packets.
 pub struct Session {
-    pub(crate) send_tosend: Sender<Bytes>,
-    rate_limit: Arc<AtomicU32>,
+    send_control: Sender<Bytes>,
+    send_interactive: Sender<Bytes>,
+    send_bulk: Sender<Bytes>,
+    traffic: Arc<Traffic>,
+    up_limit: Arc<TokenBucket>,
+    down_limit: TokenBucket,
     recv_input: Receiver<Bytes>,
     get_stats: Sender<Sender<SessionStats>>,
+    shaper: Arc<Shaper>,
+    version: u64,
+    features: u64,
+    is_client: bool,
+    send_close: Sender<()>,
+    handing_over: Arc<AtomicBool>,
     _dropper: Vec<Box<dyn FnOnce() + Send + Sync + 'static>>,
-    _task: smol::Task<()>,
+    task: Option<smol::Task<()>>,
 }
 
 impl Session {
     /// Creates a tuple of a Session and also a channel with which stuff is fed into the session.
     pub(crate) fn new(cfg: SessionConfig) -> Self {
-        let (send_tosend, recv_tosend) = smol::channel::bounded(50);
+        let (send_control, recv_control) = smol::channel::unbounded();
+        let (send_interactive, recv_interactive) = smol::channel::bounded(50);
+        let (send_bulk, recv_bulk) = smol::channel::bounded(50);
+        let queues = SendQueues {
+            control: recv_control,
+            interactive: recv_interactive,
+            bulk: recv_bulk,
+            heads: [None, None],
+            served: [0, 0],
+        };
         let (send_input, recv_input) = smol::channel::bounded(500);
         let (s, r) = smol::channel::unbounded();
-        let rate_limit = Arc::new(AtomicU32::new(15000));
+        let (send_close, recv_close) = smol::channel::bounded(1);
+        let up_limit = Arc::new(TokenBucket::default());
         let recv_timeout = cfg.recv_timeout;
+        let version = cfg.version;
+        let features = cfg.features;
+        let is_client = cfg.is_client;
+        let shaper = cfg.shaper.clone();
+        let traffic = cfg.traffic.clone();
+        let handing_over = cfg.handing_over.clone();
         let task = runtime::spawn(session_loop(
             cfg,
-            recv_tosend,
+            queues,
             send_input,
-            rate_limit.clone(),
+            up_limit.clone(),
             r,
+            recv_close,
             recv_timeout,
         ));
         Session {
-            send_tosend,
+            send_control,
+            send_interactive,
+            send_bulk,
+            traffic,
             recv_input,
-            rate_limit,
+            up_limit,
+            down_limit: TokenBucket::default(),
             get_stats: s,
+            shaper,
+            version,
+            features,
+            is_client,
+            send_close,
+            handing_over,
             _dropper: Vec::new(),
-            _task: task,
+            task: Some(task),
         }
     }
 
-    /// Adds a closure to be run when the Session is dropped. Use this to manage associated "worker" resources.
+    /// Adds a closure to be run when the Session is dropped, or shortly after if it first has to tell the peer. Use this to manage associated "worker" resources.
     pub fn on_drop<T: FnOnce() + Send + Sync + 'static>(&mut self, thing: T) {
         self._dropper.push(Box::new(thing))
     }
 
-    /// Takes a Bytes to be sent and stuffs it into the session.
+    /// Takes a Bytes to be sent and stuffs it into the session, as bulk traffic.
     pub async fn send_bytes(&self, to_send: Bytes) {
-        if self.send_tosend.try_send(to_send).is_err() {
-            tracing::trace!("overflowed send buffer at session!");
+        self.send_prioritized(to_send, Priority::Bulk).await
+    }
+
+    /// Takes a Bytes to be sent and stuffs it into the session with the given priority. If too much is already queued, the packet is dropped and counted in `SessionStats::up_dropped`.
+    pub async fn send_prioritized(&self, to_send: Bytes, priority: Priority) {
+        if self.send_queue(priority).try_send(to_send).is_err() {
+            tracing::trace!("overflowed {:?} send buffer at session!", priority);
+            self.traffic.dropped_outgoing();
+        }
+    }
+
+    /// Like `send_prioritized`, but waits for room in the queue rather than dropping the packet. Use this to slow down when the session can't keep up, rather than mistaking dropped packets for loss.
+    pub async fn send_bytes_reliable(&self, to_send: Bytes, priority: Priority) {
+        drop(self.send_queue(priority).send(to_send).await)
+    }
+
+    fn send_queue(&self, priority: Priority) -> &Sender<Bytes> {
+        match priority {
+            Priority::Control => &self.send_control,
+            Priority::Interactive => &self.send_interactive,
+            Priority::Bulk => &self.send_bulk,
         }
-        // drop(self.send_tosend.send(to_send).await)
     }
 
     /// Waits until the next application input is decoded by the session.
     pub async fn recv_bytes(&self) -> Option<Bytes> {
-        self.recv_input.recv().await.ok()
+        let pkt = self.recv_input.recv().await.ok()?;
+        self.down_limit.wait(pkt.len()).await;
+        Some(pkt)
     }
 
     /// Obtains current statistics.
@@ -98,12 +304,79 @@ impl Session {
         recv.recv().await.ok()
     }
 
-    /// Sets the rate limit, in packets per second.
-    pub fn set_ratelimit(&self, pps: u32) {
-        self.rate_limit.store(pps, Ordering::Relaxed);
+    /// Returns the protocol version negotiated during the handshake.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns the feature bitmask negotiated during the handshake. See the `features` module.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Returns whether this end connected to the other, rather than accepting it.
+    pub(crate) fn is_client(&self) -> bool {
+        self.is_client
+    }
+
+    /// Sets the traffic-shaping profile for packets this end sends. The other end shapes its own packets independently.
+    pub fn set_traffic_shape(&self, shape: impl TrafficShape) {
+        self.shaper.set_shape(Arc::new(shape));
+    }
+
+    /// Limits how fast this end sends, in bytes per second, letting up to `burst` bytes through at once after a lull. Application bytes count against the limit along with the FEC parity and retransmissions that carry them, but padding and headers don't. A rate of zero lifts the limit, which is the default.
+    pub fn set_up_ratelimit(&self, bytes_per_sec: u32, burst: u32) {
+        self.up_limit.set_limit(bytes_per_sec, burst);
+    }
+
+    /// Limits how fast `recv_bytes` hands out application bytes, like `set_up_ratelimit` except that only application bytes count, since parity never reaches `recv_bytes`. Whatever arrives faster queues up and is eventually dropped, which is how the peer finds out to slow down.
+    pub fn set_down_ratelimit(&self, bytes_per_sec: u32, burst: u32) {
+        self.down_limit.set_limit(bytes_per_sec, burst);
     }
 }
 
+impl Drop for Session {
+    fn drop(&mut self) {
+        let droppers = std::mem::take(&mut self._dropper);
+        let run_droppers = move || {
+            for dropper in droppers {
+                dropper();
+            }
+        };
+        match self.task.take() {
+            // if the peer can understand a close frame, the session gets a moment to send one before whatever carries its frames goes away. Sessions being handed over stay open for whoever takes them over.
+            Some(task)
+                if self.features & features::EXT_CLOSE != 0
+                    && !self.handing_over.load(Ordering::Relaxed)
+                    && self.send_close.try_send(()).is_ok() =>
+            {
+                runtime::spawn(async move {
+                    finish(task).await;
+                    run_droppers();
+                })
+                .detach();
+            }
+            _ => run_droppers(),
+        }
+    }
+}
+
+/// Lets a task belonging to a dropped session run for a little longer, so that the session's close frame gets out, and then cancels it.
+pub(crate) fn linger<T: Send + 'static>(task: smol::Task<T>) {
+    runtime::spawn(finish(task)).detach();
+}
+
+/// Waits for a task to finish, cancelling it if it takes longer than `CLOSE_GRACE`.
+async fn finish<T>(task: smol::Task<T>) {
+    async move {
+        task.await;
+    }
+    .or(async {
+        smol::Timer::after(CLOSE_GRACE).await;
+    })
+    .await
+}
+
 /// Statistics of a single Sosistab session.
 #[derive(Debug)]
 pub struct SessionStats {
@@ -111,24 +384,61 @@ pub struct SessionStats {
     pub down_loss: f64,
     pub down_recovered_loss: f64,
     pub down_redundant: f64,
+    /// Mean length of recent loss bursts in received frames, or zero if nothing was lost.
+    pub down_burst_len: f64,
     pub recent_seqnos: Vec<(Instant, u64)>,
     pub ping: Duration,
+    /// Bytes of traffic-shaping padding sent per byte of frames.
+    pub up_padding: f64,
+    /// Application packets sent.
+    pub up_packets: u64,
+    /// Application bytes sent.
+    pub up_bytes: u64,
+    /// Datagrams sent, including parity, retransmissions and bare NACKs.
+    pub up_wire_packets: u64,
+    /// Bytes of datagrams sent, including parity, headers, padding and encryption.
+    pub up_wire_bytes: u64,
+    /// Smoothed rate of application bytes sent, per second.
+    pub up_rate: f64,
+    /// Application packets dropped before sending because too many were queued.
+    pub up_dropped: u64,
+    /// Application packets received.
+    pub down_packets: u64,
+    /// Application bytes received.
+    pub down_bytes: u64,
+    /// Datagrams received.
+    pub down_wire_packets: u64,
+    /// Bytes of datagrams received, including parity, headers, padding and encryption.
+    pub down_wire_bytes: u64,
+    /// Smoothed rate of application bytes received, per second.
+    pub down_rate: f64,
+    /// Application packets dropped after receiving because `recv_bytes` wasn't called fast enough.
+    pub down_dropped: u64,
+    /// How each path is doing for what this end sends. Empty unless EXT_MULTIPATH is negotiated.
+    pub paths: Vec<PathStats>,
 }
 
 #[tracing::instrument]
 async fn session_loop(
     cfg: SessionConfig,
-    recv_tosend: Receiver<Bytes>,
+    queues: SendQueues,
     send_input: Sender<Bytes>,
-    rate_limit: Arc<AtomicU32>,
+    up_limit: Arc<TokenBucket>,
     recv_statreq: Receiver<Sender<SessionStats>>,
+    recv_close: Receiver<()>,
     recv_timeout: Duration,
 ) {
     let measured_loss = AtomicU8::new(0);
+    let measured_burst = AtomicU8::new(0);
+    let recv_burst = AtomicU8::new(0);
     let high_recv_frame_no = AtomicU64::new(0);
+    let peer_high_frame_no = AtomicU64::new(0);
     let total_recv_frames = AtomicU64::new(0);
-    let shaper = VarRateLimit::new();
+    let pacer = VarRateLimit::new();
     let pinger = Mutex::new(PingCalc::default());
+    // NACKs for the peer, and the peer's NACKs for us
+    let (send_nacks, recv_nacks) = smol::channel::bounded(16);
+    let (send_wanted, recv_wanted) = smol::channel::bounded(16);
 
     // SystemTime is immune to bizarre sleep-induced timer skews on Android. We use this to detect missed timeouts when we try to send a packet.
     let last_send = Mutex::new(SystemTime::now());
@@ -136,12 +446,18 @@ async fn session_loop(
     // sending loop
     let send_task = session_send_loop(
         cfg.clone(),
-        &rate_limit,
-        recv_tosend.clone(),
+        &up_limit,
+        queues,
+        recv_nacks,
+        recv_wanted,
+        recv_close,
         &measured_loss,
+        &measured_burst,
+        &recv_burst,
         &high_recv_frame_no,
+        &peer_high_frame_no,
         &total_recv_frames,
-        shaper,
+        pacer,
         &pinger,
         &last_send,
         recv_timeout,
@@ -149,9 +465,14 @@ async fn session_loop(
     let recv_task = session_recv_loop(
         cfg,
         send_input,
-        recv_statreq,
+        recv_statreq.clone(),
+        send_nacks,
+        send_wanted,
         &measured_loss,
+        &measured_burst,
+        &recv_burst,
         &high_recv_frame_no,
+        &peer_high_frame_no,
         &total_recv_frames,
         &pinger,
         &last_send,
@@ -160,18 +481,27 @@ async fn session_loop(
 
     // we don't spawn new tasks. This ensures that mutexes etc never actually have contention!
     smol::future::race(send_task, recv_task).await;
+    // requests still queued would keep their askers waiting forever
+    recv_statreq.close();
+    while recv_statreq.try_recv().is_ok() {}
 }
 
 #[allow(clippy::too_many_arguments)]
-#[tracing::instrument(skip(shaper))]
+#[tracing::instrument(skip(pacer))]
 async fn session_send_loop(
     cfg: SessionConfig,
-    rate_limit: &AtomicU32,
-    recv_tosend: Receiver<Bytes>,
+    up_limit: &TokenBucket,
+    mut queues: SendQueues,
+    recv_nacks: Receiver<Vec<Nack>>,
+    recv_wanted: Receiver<Vec<Nack>>,
+    recv_close: Receiver<()>,
     measured_loss: &AtomicU8,
+    measured_burst: &AtomicU8,
+    recv_burst: &AtomicU8,
     high_recv_frame_no: &AtomicU64,
+    peer_high_frame_no: &AtomicU64,
     total_recv_frames: &AtomicU64,
-    mut shaper: VarRateLimit,
+    mut pacer: VarRateLimit,
     pinger: &Mutex<PingCalc>,
     last_recv: &Mutex<SystemTime>,
     recv_timeout: Duration,
@@ -190,30 +520,145 @@ async fn session_send_loop(
     let mut frame_no = 0u64;
     let mut run_no = 0u64;
     let mut to_send = Vec::new();
-    let mut abs_timeout = smol::Timer::after(get_timeout(measured_loss.load(Ordering::Relaxed)));
+    let code = fec::erasure_code(cfg.features);
+    let mut encoder = FrameEncoder::new(code.clone(), loss_to_u8(cfg.target_loss));
+    // a reliable backhaul never needs parity, whatever the loss calculator thinks
+    let current_loss = || {
+        if cfg.reliable {
+            0
+        } else {
+            measured_loss.load(Ordering::Relaxed)
+        }
+    };
+    let current_burst = || {
+        if cfg.reliable {
+            0
+        } else {
+            measured_burst.load(Ordering::Relaxed)
+        }
+    };
+    let mut abs_timeout = smol::Timer::after(get_timeout(current_loss()));
+    // with bursty loss, a run's parity goes out after the next run's data, so that one burst is less likely to take out both
+    let mut deferred_parity = Vec::new();
+    let mut outgoing = Vec::new();
+    // recently sent runs, in case the peer NACKs them
+    let keep_history = cfg.features & features::EXT_NACK != 0 && !cfg.reliable;
+    let mut history: VecDeque<(u64, u8, u8, Vec<Bytes>)> = VecDeque::new();
+    let mut history_shards = 0;
+    let report_bursts = cfg.features & features::EXT_BURST_LEN != 0;
+    let burst_report = || {
+        if report_bursts {
+            recv_burst.load(Ordering::Relaxed)
+        } else {
+            0
+        }
+    };
+
+    enum Evt {
+        Data(Bytes),
+        Nacks(Vec<Nack>),
+        Retransmit(Vec<Nack>),
+        Flush,
+        Close,
+    }
 
     loop {
         // obtain a vector of bytes to send
-        let to_send = {
-            to_send.clear();
+        to_send.clear();
+        let mut nacks = Vec::new();
+        // NACKs come first, since they're rare and data may never let up
+        let evt = async {
+            infal(recv_close.recv()).await;
+            Evt::Close
+        }
+        .or(async { Evt::Retransmit(infal(recv_wanted.recv()).await) })
+        .or(async { Evt::Nacks(infal(recv_nacks.recv()).await) })
+        .or(async { Evt::Data(queues.next().await) })
+        .or(async {
+            if deferred_parity.is_empty() {
+                smol::future::pending::<()>().await;
+            }
+            // deferred parity can't wait forever for another run
+            smol::Timer::after(INTERLEAVE_FLUSH).await;
+            Evt::Flush
+        })
+        .await;
+        // a listener that takes over a session starts from scratch, so skip past everything the peer saw from the one before, lest it take our frames for replays
+        let peer_high = peer_high_frame_no.load(Ordering::Relaxed);
+        if peer_high > 0 && peer_high >= frame_no {
+            frame_no = peer_high + 1;
+            run_no = run_no.max(frame_no);
+        }
+        match evt {
+            Evt::Data(first) => to_send.push(first),
+            Evt::Nacks(our_nacks) => nacks = our_nacks,
+            Evt::Retransmit(wanted) => {
+                for nack in wanted {
+                    let idx = match history.binary_search_by_key(&nack.run_no, |run| run.0) {
+                        Ok(idx) => idx,
+                        Err(_) => continue,
+                    };
+                    let (run_no, data_shards, parity_shards, shards) = &history[idx];
+                    let wanted_idxs = if nack.shards.is_empty() {
+                        (0..*data_shards).collect()
+                    } else {
+                        nack.shards
+                    };
+                    for run_idx in wanted_idxs {
+                        if let Some(body) = shards.get(run_idx as usize) {
+                            outgoing.push((
+                                *run_no,
+                                run_idx,
+                                *data_shards,
+                                *parity_shards,
+                                body.clone(),
+                            ));
+                        }
+                    }
+                }
+            }
+            Evt::Flush => {}
+            Evt::Close => {
+                tracing::debug!("sending close frame {}", frame_no);
+                drop(
+                    cfg.send_frame
+                        .send(DataFrame {
+                            frame_no,
+                            run_no,
+                            run_idx: 0,
+                            data_shards: 0,
+                            parity_shards: 0,
+                            high_recv_frame_no: high_recv_frame_no.load(Ordering::Relaxed),
+                            total_recv_frames: total_recv_frames.load(Ordering::Relaxed),
+                            body: Bytes::new(),
+                            burst_len: burst_report(),
+                            nacks: Vec::new(),
+                            path: None,
+                            close: true,
+                        })
+                        .await,
+                );
+                return Some(());
+            }
+        }
+        if !to_send.is_empty() {
             // get as much tosend as possible within the timeout
             // this lets us do it at maximum efficiency
-            to_send.push(infal(recv_tosend.recv()).await);
-            abs_timeout.set_after(get_timeout(measured_loss.load(Ordering::Relaxed)));
+            abs_timeout.set_after(get_timeout(current_loss()));
             loop {
                 let break_now = async {
                     (&mut abs_timeout).await;
                     true
                 }
                 .or(async {
-                    to_send.push(infal(recv_tosend.recv()).await);
+                    to_send.push(queues.next().await);
                     false
                 });
-                if break_now.await || to_send.len() >= 64 {
-                    break &to_send;
+                if break_now.await || to_send.len() >= code.max_run_len() {
+                    break;
                 }
             }
-        };
+        }
         let now = SystemTime::now();
         if let Ok(elapsed) = now.duration_since(*last_recv.lock()) {
             if elapsed > recv_timeout {
@@ -221,10 +666,46 @@ async fn session_send_loop(
                 return None;
             }
         }
-        // encode into raptor
-        let encoded = FrameEncoder::new(loss_to_u8(cfg.target_loss))
-            .encode(measured_loss.load(Ordering::Relaxed), &to_send);
-        for (idx, bts) in encoded.iter().enumerate() {
+        if !to_send.is_empty() {
+            for pkt in to_send.iter() {
+                cfg.traffic.sent_packet(pkt.len());
+            }
+            let encoded = encoder.encode(current_loss(), current_burst(), &to_send);
+            let data_shards = to_send.len() as u8;
+            let parity_shards = (encoded.len() - to_send.len()) as u8;
+            if keep_history {
+                history_shards += encoded.len();
+                history.push_back((run_no, data_shards, parity_shards, encoded.clone()));
+                while history_shards > HISTORY_SHARDS {
+                    if let Some((_, _, _, shards)) = history.pop_front() {
+                        history_shards -= shards.len();
+                    }
+                }
+            }
+            let mut shards = encoded
+                .into_iter()
+                .enumerate()
+                .map(|(idx, body)| (run_no, idx as u8, data_shards, parity_shards, body));
+            outgoing.extend((&mut shards).take(to_send.len()));
+            outgoing.append(&mut deferred_parity);
+            if current_burst() >= INTERLEAVE_BURST_LEN {
+                deferred_parity.extend(shards);
+            } else {
+                outgoing.extend(shards);
+            }
+            run_no += 1;
+        } else {
+            outgoing.append(&mut deferred_parity);
+        }
+        if outgoing.is_empty() && !nacks.is_empty() {
+            // a bare NACK, with no shards
+            outgoing.push((run_no, 0, 0, 0, Bytes::new()));
+        }
+        // parity and retransmissions use up the link as much as data does
+        up_limit
+            .wait(outgoing.iter().map(|(_, _, _, _, body)| body.len()).sum())
+            .await;
+        for (run_no, run_idx, data_shards, parity_shards, body) in outgoing.drain(..) {
             if frame_no % 1000 == 0 {
                 tracing::debug!(
                     "frame {}, measured loss {}",
@@ -237,20 +718,27 @@ async fn session_send_loop(
                     .send(DataFrame {
                         frame_no,
                         run_no,
-                        run_idx: idx as u8,
-                        data_shards: to_send.len() as u8,
-                        parity_shards: (encoded.len() - to_send.len()) as u8,
+                        run_idx,
+                        data_shards,
+                        parity_shards,
                         high_recv_frame_no: high_recv_frame_no.load(Ordering::Relaxed),
                         total_recv_frames: total_recv_frames.load(Ordering::Relaxed),
-                        body: bts.clone(),
+                        body,
+                        burst_len: burst_report(),
+                        nacks: std::mem::take(&mut nacks),
+                        path: None,
+                        close: false,
                     })
                     .await,
             );
-            shaper.wait(rate_limit.load(Ordering::Relaxed)).await;
+            pacer.wait(MAX_FRAME_RATE).await;
+            let gap = cfg.shaper.send_gap();
+            if gap > Duration::from_secs(0) {
+                smol::Timer::after(gap).await;
+            }
             pinger.lock().send(frame_no);
             frame_no += 1;
         }
-        run_no += 1;
     }
 }
 
@@ -260,14 +748,19 @@ async fn session_recv_loop(
     cfg: SessionConfig,
     send_input: Sender<Bytes>,
     recv_statreq: Receiver<Sender<SessionStats>>,
+    send_nacks: Sender<Vec<Nack>>,
+    send_wanted: Sender<Vec<Nack>>,
     measured_loss: &AtomicU8,
+    measured_burst: &AtomicU8,
+    recv_burst: &AtomicU8,
     high_recv_frame_no: &AtomicU64,
+    peer_high_frame_no: &AtomicU64,
     total_recv_frames: &AtomicU64,
     pinger: &Mutex<PingCalc>,
     last_recv: &Mutex<SystemTime>,
     recv_timeout: Duration,
 ) -> Option<()> {
-    let decoder = RwLock::new(RunDecoder::default());
+    let decoder = RwLock::new(RunDecoder::new(fec::erasure_code(cfg.features)));
     let seqnos = RwLock::new(VecDeque::new());
     // receive loop
     let recv_loop = async {
@@ -302,12 +795,33 @@ async fn session_recv_loop(
                 if seqnos.len() > 100000 {
                     seqnos.pop_front();
                 }
+                if total_recv_frames
+                    .load(Ordering::Relaxed)
+                    .is_multiple_of(256)
+                {
+                    recv_burst.store(burst_len(&seqnos), Ordering::Relaxed);
+                }
             }
             loss_calc.update_params(new_frame.high_recv_frame_no, new_frame.total_recv_frames);
             measured_loss.store(loss_to_u8(loss_calc.median), Ordering::Relaxed);
+            // peers without the extension leave the field out, and the padding that stands in for it says nothing
+            if cfg.features & features::EXT_BURST_LEN != 0 {
+                measured_burst.store(new_frame.burst_len, Ordering::Relaxed);
+            }
             high_recv_frame_no.fetch_max(new_frame.frame_no, Ordering::Relaxed);
+            peer_high_frame_no.fetch_max(new_frame.high_recv_frame_no, Ordering::Relaxed);
             total_recv_frames.fetch_add(1, Ordering::Relaxed);
             pinger.lock().ack(new_frame.high_recv_frame_no);
+            if new_frame.close && cfg.features & features::EXT_CLOSE != 0 {
+                tracing::debug!("peer closed the session");
+                return None;
+            }
+            if !new_frame.nacks.is_empty() {
+                let _ = send_wanted.try_send(new_frame.nacks);
+            }
+            if new_frame.data_shards == 0 {
+                continue;
+            }
             if let Some(output) = decoder.write().input(
                 new_frame.run_no,
                 new_frame.run_idx,
@@ -316,16 +830,37 @@ async fn session_recv_loop(
                 &new_frame.body,
             ) {
                 for item in output {
-                    let _ = send_input.try_send(item);
+                    let len = item.len();
+                    if send_input.try_send(item).is_ok() {
+                        cfg.traffic.received_packet(len);
+                    } else {
+                        cfg.traffic.dropped_incoming();
+                    }
                 }
             }
         }
     };
     // stats loop
     let stats_loop = async {
+        let traffic = &cfg.traffic;
+        let (mut last_time, mut last_up, mut last_down) = (Instant::now(), 0, 0);
+        let (mut up_rate, mut down_rate) = (0.0, 0.0);
         loop {
             let req = infal(recv_statreq.recv()).await;
             let ping = pinger.lock().ping();
+            // rates are only sampled when asked for, so the weight of a sample grows with the time it covers
+            let now = Instant::now();
+            let elapsed = now.saturating_duration_since(last_time).as_secs_f64();
+            let up_bytes = traffic.up_bytes.load(Ordering::Relaxed);
+            let down_bytes = traffic.down_bytes.load(Ordering::Relaxed);
+            if elapsed > 0.0 {
+                let weight = 1.0 - (-elapsed / RATE_SMOOTHING.as_secs_f64()).exp();
+                up_rate += weight * ((up_bytes - last_up) as f64 / elapsed - up_rate);
+                down_rate += weight * ((down_bytes - last_down) as f64 / elapsed - down_rate);
+                last_time = now;
+                last_up = up_bytes;
+                last_down = down_bytes;
+            }
             let response = {
                 let decoder = decoder.read();
                 SessionStats {
@@ -338,18 +873,47 @@ async fn session_recv_loop(
                         - (decoder.correct_count as f64 / decoder.total_count as f64).min(1.0),
                     down_redundant: decoder.total_parity_shards as f64
                         / decoder.total_data_shards as f64,
+                    down_burst_len: recv_burst.load(Ordering::Relaxed) as f64 / 16.0,
                     recent_seqnos: seqnos.read().iter().cloned().collect(),
                     ping,
+                    up_padding: cfg.shaper.padding_overhead(),
+                    up_packets: traffic.up_packets.load(Ordering::Relaxed),
+                    up_bytes,
+                    up_wire_packets: traffic.up_wire_packets.load(Ordering::Relaxed),
+                    up_wire_bytes: traffic.up_wire_bytes.load(Ordering::Relaxed),
+                    up_rate,
+                    up_dropped: traffic.up_dropped.load(Ordering::Relaxed),
+                    down_packets: traffic.down_packets.load(Ordering::Relaxed),
+                    down_bytes,
+                    down_wire_packets: traffic.down_wire_packets.load(Ordering::Relaxed),
+                    down_wire_bytes: traffic.down_wire_bytes.load(Ordering::Relaxed),
+                    down_rate,
+                    down_dropped: traffic.down_dropped.load(Ordering::Relaxed),
+                    paths: cfg.paths.stats(),
                 }
             };
             infal(req.send(response)).await;
         }
     };
-    smol::future::race(stats_loop, recv_loop).await
+    // NACK loop
+    let nack_loop = async {
+        if cfg.features & features::EXT_NACK == 0 || cfg.reliable {
+            smol::future::pending::<()>().await;
+        }
+        loop {
+            smol::Timer::after(NACK_INTERVAL).await;
+            let ping = pinger.lock().ping();
+            let nacks = decoder.write().nacks(ping);
+            if !nacks.is_empty() {
+                let _ = send_nacks.try_send(nacks);
+            }
+        }
+    };
+    smol::future::race(stats_loop, recv_loop.or(nack_loop)).await
 }
 /// A reordering-resistant FEC reconstructor
-#[derive(Default)]
 struct RunDecoder {
+    code: Arc<dyn ErasureCode>,
     top_run: u64,
     bottom_run: u64,
     decoders: FxHashMap<u64, FrameDecoder>,
@@ -358,9 +922,26 @@ struct RunDecoder {
 
     total_data_shards: u64,
     total_parity_shards: u64,
+
+    // when each incomplete run was last NACKed or first noticed, and how many times it was NACKed
+    nack_state: FxHashMap<u64, (Instant, u8)>,
 }
 
 impl RunDecoder {
+    fn new(code: Arc<dyn ErasureCode>) -> Self {
+        RunDecoder {
+            code,
+            top_run: 0,
+            bottom_run: 0,
+            decoders: FxHashMap::default(),
+            total_count: 0,
+            correct_count: 0,
+            total_data_shards: 0,
+            total_parity_shards: 0,
+            nack_state: FxHashMap::default(),
+        }
+    }
+
     fn input(
         &mut self,
         run_no: u64,
@@ -383,10 +964,10 @@ impl RunDecoder {
                     self.bottom_run += 1;
                 }
             }
-            let decoder = self
-                .decoders
-                .entry(run_no)
-                .or_insert_with(|| FrameDecoder::new(data_shards as usize, parity_shards as usize));
+            let code = &self.code;
+            let decoder = self.decoders.entry(run_no).or_insert_with(|| {
+                FrameDecoder::new(code.clone(), data_shards as usize, parity_shards as usize)
+            });
             if run_idx < data_shards {
                 self.total_data_shards += 1
             } else {
@@ -401,6 +982,41 @@ impl RunDecoder {
             None
         }
     }
+
+    /// NACKs recent runs that are still missing data shards, after giving them a chance to arrive.
+    fn nacks(&mut self, ping: Duration) -> Vec<Nack> {
+        let now = Instant::now();
+        let bottom_run = self.bottom_run;
+        self.nack_state.retain(|run_no, _| *run_no >= bottom_run);
+        let mut nacks = Vec::new();
+        // only runs that a later run has overtaken are judged
+        for run_no in bottom_run..self.top_run {
+            let shards = match self.decoders.get(&run_no) {
+                Some(decoder) => {
+                    let missing = decoder.missing_data_shards();
+                    if missing.is_empty() {
+                        self.nack_state.remove(&run_no);
+                        continue;
+                    }
+                    missing
+                }
+                None => Vec::new(),
+            };
+            let (since, tries) = self.nack_state.entry(run_no).or_insert((now, 0));
+            let wait = if *tries == 0 {
+                NACK_GRACE
+            } else {
+                ping.min(NACK_MAX_RTT) + NACK_GRACE
+            };
+            if *tries >= NACK_TRIES || now.saturating_duration_since(*since) < wait {
+                continue;
+            }
+            *since = now;
+            *tries += 1;
+            nacks.push(Nack { run_no, shards });
+        }
+        nacks
+    }
 }
 
 /// A filter for replays. Records recently seen seqnos and rejects either repeats or really old seqnos.
@@ -498,6 +1114,24 @@ impl LossCalculator {
     }
 }
 
+/// Estimates the mean length of loss bursts, in sixteenths of a frame, from the frame numbers most recently received. Returns zero if nothing was lost.
+fn burst_len(seqnos: &VecDeque<(Instant, u64)>) -> u8 {
+    let mut recent: Vec<u64> = seqnos
+        .iter()
+        .rev()
+        .take(1000)
+        .map(|(_, seqno)| *seqno)
+        .collect();
+    recent.sort_unstable();
+    recent.dedup();
+    let (bursts, lost) = recent
+        .windows(2)
+        .map(|pair| pair[1] - pair[0] - 1)
+        .filter(|gap| *gap > 0)
+        .fold((0u64, 0u64), |(bursts, lost), gap| (bursts + 1, lost + gap));
+    (lost * 16).checked_div(bursts).unwrap_or(0).min(255) as u8
+}
+
 /// A ping calculator
 #[derive(Debug, Default)]
 struct PingCalc {
@@ -534,3 +1168,280 @@ impl PingCalc {
             .unwrap_or_else(|| Duration::from_secs(1000))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::{SimConfig, SimNetwork};
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn queues_weigh_priorities() {
+        smol::block_on(async {
+            let (send_control, control) = smol::channel::unbounded();
+            let (send_interactive, interactive) = smol::channel::unbounded();
+            let (send_bulk, bulk) = smol::channel::unbounded();
+            let mut queues = SendQueues {
+                control,
+                interactive,
+                bulk,
+                heads: [None, None],
+                served: [0, 0],
+            };
+            for _ in 0..50 {
+                send_bulk.try_send(Bytes::from_static(b"bulk")).unwrap();
+                send_interactive
+                    .try_send(Bytes::from_static(b"intr"))
+                    .unwrap();
+            }
+            assert_eq!(queues.next().await, Bytes::from_static(b"intr"));
+            send_control.try_send(Bytes::from_static(b"ctrl")).unwrap();
+            assert_eq!(queues.next().await, Bytes::from_static(b"ctrl"));
+            let mut interactive_count = 0;
+            for _ in 0..50 {
+                if queues.next().await == Bytes::from_static(b"intr") {
+                    interactive_count += 1;
+                }
+            }
+            assert_eq!(interactive_count, 40);
+            for _ in 0..49 {
+                queues.next().await;
+            }
+            // interactive doesn't get to save up a share while it's idle
+            for _ in 0..10 {
+                send_bulk.try_send(Bytes::from_static(b"bulk")).unwrap();
+                assert_eq!(queues.next().await, Bytes::from_static(b"bulk"));
+            }
+            for _ in 0..5 {
+                send_bulk.try_send(Bytes::from_static(b"bulk")).unwrap();
+                send_interactive
+                    .try_send(Bytes::from_static(b"intr"))
+                    .unwrap();
+            }
+            assert_eq!(queues.next().await, Bytes::from_static(b"intr"));
+            assert_eq!(queues.next().await, Bytes::from_static(b"bulk"));
+        })
+    }
+
+    #[test]
+    fn session_over_sim() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let (_listener, client, server) = sim_sessions(&net, Default::default()).await;
+            for i in 0u32..100 {
+                client.send_bytes(i.to_be_bytes().to_vec().into()).await;
+                smol::Timer::after(Duration::from_millis(1)).await;
+            }
+            // shards race each other, so datagrams may arrive out of order
+            let mut received = Vec::new();
+            for _ in 0u32..100 {
+                received.push(server.recv_bytes().await.unwrap());
+            }
+            received.sort();
+            let expected: Vec<Bytes> = (0u32..100)
+                .map(|i| i.to_be_bytes().to_vec().into())
+                .collect();
+            assert_eq!(received, expected);
+        })
+    }
+
+    #[test]
+    fn session_counts_traffic() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let (_listener, client, server) = sim_sessions(&net, Default::default()).await;
+            for _ in 0..100 {
+                client.send_bytes(Bytes::from(vec![0u8; 500])).await;
+                server.send_bytes(server.recv_bytes().await.unwrap()).await;
+                client.recv_bytes().await.unwrap();
+            }
+            let client_stats = client.get_stats().await.unwrap();
+            let server_stats = server.get_stats().await.unwrap();
+            // the handshake sent a hello first
+            assert_eq!(client_stats.up_packets, 101);
+            assert_eq!(client_stats.up_bytes, 50005);
+            assert_eq!(client_stats.down_packets, 100);
+            assert_eq!(client_stats.down_bytes, 50000);
+            assert_eq!(server_stats.down_bytes, client_stats.up_bytes);
+            assert_eq!(server_stats.up_bytes, client_stats.down_bytes);
+            assert!(client_stats.up_wire_bytes > client_stats.up_bytes);
+            assert_eq!(server_stats.down_wire_bytes, client_stats.up_wire_bytes);
+            assert_eq!(client_stats.down_wire_packets, server_stats.up_wire_packets);
+            assert!(client_stats.up_rate > 0.0);
+        })
+    }
+
+    #[test]
+    fn session_counts_drops() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let (_listener, client, server) = sim_sessions(&net, Default::default()).await;
+            // nothing is lost on the way, but the server reads none of it
+            for _ in 0..1000 {
+                client
+                    .send_bytes_reliable(Bytes::from(vec![0u8; 100]), Priority::Bulk)
+                    .await;
+            }
+            // however slowly the sessions get to run, every packet ends up either queued or dropped
+            let server_stats = timeout(Duration::from_secs(30), async {
+                loop {
+                    let stats = server.get_stats().await.unwrap();
+                    if stats.down_packets + stats.down_dropped == 1001 {
+                        break stats;
+                    }
+                    smol::Timer::after(Duration::from_millis(50)).await;
+                }
+            })
+            .await
+            .expect("packets went missing");
+            let client_stats = client.get_stats().await.unwrap();
+            assert_eq!(client_stats.up_dropped, 0);
+            assert_eq!(client_stats.up_packets, 1001);
+            // the hello, and then as many as fit in the receive queue
+            assert_eq!(server_stats.down_packets, 501);
+            assert_eq!(server_stats.down_dropped, 500);
+            // queueing faster than the session sends drops packets
+            let start = Instant::now();
+            client.set_up_ratelimit(10_000, 1000);
+            for _ in 0..1000 {
+                client.send_bytes(Bytes::from(vec![0u8; 100])).await;
+            }
+            let client_stats = client.get_stats().await.unwrap();
+            // besides the bulk queue, only a run waiting on the limiter, a run being gathered and what the limiter let out meanwhile got in
+            let max_run_len = fec::erasure_code(client.features()).max_run_len() as f64;
+            let let_out = (1000.0 + 10_000.0 * start.elapsed().as_secs_f64()) / 100.0;
+            assert!(
+                (client_stats.up_dropped as f64) >= 1000.0 - 50.0 - 2.0 * max_run_len - let_out,
+                "dropped only {} of 1000",
+                client_stats.up_dropped
+            );
+        })
+    }
+
+    /// Sends 1000-byte packets starting with `tag` as fast as `sender` takes them for `duration`, returning how many bytes per second of them `receiver` got meanwhile. Packets left over from earlier calls don't count, and neither does time spent past `duration`, so a slow machine can only bring the rate down.
+    async fn blast(sender: &Session, receiver: &Session, tag: u8, duration: Duration) -> f64 {
+        let start = Instant::now();
+        let received = AtomicUsize::new(0);
+        let send_loop = async {
+            while start.elapsed() < duration {
+                sender.send_bytes(Bytes::from(vec![tag; 1000])).await;
+                smol::future::yield_now().await;
+            }
+        };
+        let recv_loop = async {
+            loop {
+                let pkt = receiver.recv_bytes().await.unwrap();
+                if pkt[0] == tag {
+                    received.fetch_add(pkt.len(), Ordering::Relaxed);
+                }
+            }
+        };
+        send_loop.or(recv_loop).await;
+        received.load(Ordering::Relaxed) as f64 / start.elapsed().as_secs_f64()
+    }
+
+    #[test]
+    fn ratelimits_hold_throughput() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let (_listener, client, server) = sim_sessions(&net, Default::default()).await;
+            let duration = Duration::from_secs(2);
+            // the burst lets an extra 10 KB through, or 5 KB/s over two seconds, and nothing else gets past the limit however things are scheduled; even a busy machine gets well over half of it through
+            client.set_up_ratelimit(100_000, 10_000);
+            let up = blast(&client, &server, 1, duration).await;
+            assert!(up > 50_000.0 && up <= 105_000.0, "up at {} B/s", up);
+            // limits change on the fly
+            client.set_up_ratelimit(200_000, 10_000);
+            let up = blast(&client, &server, 2, duration).await;
+            assert!(up > 100_000.0 && up <= 205_000.0, "up at {} B/s", up);
+            client.set_down_ratelimit(100_000, 10_000);
+            let down = blast(&server, &client, 3, duration).await;
+            assert!(down > 50_000.0 && down <= 105_000.0, "down at {} B/s", down);
+        })
+    }
+
+    #[test]
+    fn nack_repairs_loss() {
+        smol::block_on(async {
+            let lossy = SimConfig {
+                loss: 0.1,
+                latency: Duration::from_millis(20),
+                seed: 1,
+                ..Default::default()
+            };
+            let net = SimNetwork::new(SimConfig {
+                seed: lossy.seed,
+                ..Default::default()
+            });
+            let (_listener, client, server) = sim_sessions(&net, Default::default()).await;
+            let expected: HashSet<Bytes> = (0u32..200)
+                .map(|i| i.to_be_bytes().to_vec().into())
+                .collect();
+            let received = parking_lot::Mutex::new(HashSet::new());
+            let recv_loop = async {
+                loop {
+                    let pkt = server.recv_bytes().await.unwrap();
+                    let mut received = received.lock();
+                    received.insert(pkt);
+                    if expected.is_subset(&received) {
+                        break;
+                    }
+                }
+            };
+            let send_loop = async {
+                // a burst gets every shard to send its ClientResume before the loss starts, since a shard whose ClientResume is lost swallows everything until it retries
+                for _ in 0..200 {
+                    client.send_bytes(Bytes::from_static(b"warmup")).await;
+                }
+                smol::Timer::after(Duration::from_millis(100)).await;
+                net.set_config(lossy);
+                // the tail can't be NACKed, since nothing overtakes it
+                for i in 0u32..250 {
+                    client.send_bytes(i.to_be_bytes().to_vec().into()).await;
+                    smol::Timer::after(Duration::from_millis(5)).await;
+                }
+                smol::Timer::after(Duration::from_secs(5)).await;
+            };
+            recv_loop.or(send_loop).await;
+            // FEC alone loses a dozen or more of these
+            assert!(expected.difference(&received.lock()).count() <= 1);
+        })
+    }
+
+    #[test]
+    fn close_frees_both_ends() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let binds = Arc::new(AtomicUsize::new(0));
+            // the client leaving ends the server session long before it would time out, however slowly things run
+            let (_listener, client, server) = sim_sessions(&net, binds.clone()).await;
+            assert!(server.features() & features::EXT_CLOSE != 0);
+            drop(client);
+            assert_eq!(
+                timeout(Duration::from_secs(30), server.recv_bytes()).await,
+                Some(None)
+            );
+            assert!(server.get_stats().await.is_none());
+            // the client's sockets go once it's done closing, leaving only the listener's
+            let freed = timeout(Duration::from_secs(30), async {
+                while net.bound_count() > 1 {
+                    smol::Timer::after(Duration::from_millis(50)).await;
+                }
+            })
+            .await;
+            assert!(freed.is_some(), "{} sockets still bound", net.bound_count());
+            assert_eq!(net.bound_count(), 1);
+            // and the other way around
+            let net = SimNetwork::new(SimConfig::default());
+            let (_listener, client, server) = sim_sessions(&net, binds).await;
+            drop(server);
+            assert_eq!(
+                timeout(Duration::from_secs(30), client.recv_bytes()).await,
+                Some(None)
+            );
+            assert!(client.get_stats().await.is_none());
+        })
+    }
+}
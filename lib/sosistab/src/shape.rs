@@ -0,0 +1,252 @@
+use crate::crypt::{Aead, AeadExt};
+use crate::msg::DataFrame;
+use parking_lot::{Mutex, RwLock};
+use rand::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{
+    fmt::Debug,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// A traffic-shaping profile, which decides how big session packets look on the wire and how they are spaced out. Set one with `Session::set_traffic_shape`.
+pub trait TrafficShape: Debug + Send + Sync + 'static {
+    /// Picks the length to pad a packet to, given its unpadded length. Lengths are before encryption, which adds a fixed overhead. Picking a length shorter than the packet leaves it unpadded.
+    fn padded_len(&self, len: usize) -> usize;
+
+    /// Picks how long to wait after sending a packet.
+    fn send_gap(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+}
+
+/// Pads packets to a uniformly random length, and never waits between them. This is the default.
+#[derive(Debug, Clone)]
+pub struct UniformShape {
+    /// Packets are padded to at most this length, unless they're longer to begin with.
+    pub max_len: usize,
+}
+
+impl Default for UniformShape {
+    fn default() -> Self {
+        UniformShape { max_len: 1000 }
+    }
+}
+
+impl TrafficShape for UniformShape {
+    fn padded_len(&self, len: usize) -> usize {
+        let mut rng = rand::thread_rng();
+        let target_len = rng.gen_range(0, self.max_len + 1);
+        if len > target_len {
+            len + rng.gen_range(0, 16)
+        } else {
+            target_len
+        }
+    }
+}
+
+/// Draws packet lengths and gaps from weighted histograms. Each packet is padded to one of the lengths that fit it, chosen by weight; packets longer than every length go out unpadded.
+#[derive(Debug, Clone)]
+pub struct HistogramShape {
+    sizes: Vec<(usize, u32)>,
+    gaps: Vec<(Duration, u32)>,
+}
+
+impl HistogramShape {
+    /// Creates a profile from (length, weight) and (gap, weight) pairs. An empty gap histogram means never waiting.
+    pub fn new(sizes: Vec<(usize, u32)>, gaps: Vec<(Duration, u32)>) -> Self {
+        HistogramShape { sizes, gaps }
+    }
+
+    /// Roughly mimics a WebRTC video call: mostly near-MTU video packets with small audio packets mixed in, sent in bursts a few milliseconds apart. The gaps cap throughput at a few thousand packets per second.
+    pub fn video_call() -> Self {
+        HistogramShape::new(
+            vec![(120, 15), (250, 5), (900, 10), (1150, 40), (1200, 30)],
+            vec![
+                (Duration::from_millis(0), 80),
+                (Duration::from_millis(1), 15),
+                (Duration::from_millis(5), 5),
+            ],
+        )
+    }
+}
+
+/// Picks an item from (item, weight) pairs, or None if every weight is zero.
+fn pick_weighted<T: Copy>(choices: impl Iterator<Item = (T, u32)> + Clone) -> Option<T> {
+    let total: u64 = choices.clone().map(|(_, weight)| weight as u64).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut point = rand::thread_rng().gen_range(0, total);
+    for (item, weight) in choices {
+        if point < weight as u64 {
+            return Some(item);
+        }
+        point -= weight as u64;
+    }
+    unreachable!()
+}
+
+impl TrafficShape for HistogramShape {
+    fn padded_len(&self, len: usize) -> usize {
+        pick_weighted(self.sizes.iter().copied().filter(|(size, _)| *size >= len)).unwrap_or(len)
+    }
+
+    fn send_gap(&self) -> Duration {
+        pick_weighted(self.gaps.iter().copied()).unwrap_or_default()
+    }
+}
+
+/// The traffic shape of one session, shared between the session and whatever encrypts its frames, along with counters of how much padding it has cost.
+#[derive(Debug)]
+pub(crate) struct Shaper {
+    shape: RwLock<Arc<dyn TrafficShape>>,
+    frame_bytes: AtomicU64,
+    padding_bytes: AtomicU64,
+}
+
+impl Default for Shaper {
+    fn default() -> Self {
+        Shaper {
+            shape: RwLock::new(Arc::new(UniformShape::default())),
+            frame_bytes: AtomicU64::new(0),
+            padding_bytes: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Shaper {
+    pub fn set_shape(&self, shape: Arc<dyn TrafficShape>) {
+        *self.shape.write() = shape;
+    }
+
+    pub fn send_gap(&self) -> Duration {
+        self.shape.read().send_gap()
+    }
+
+    /// Pads and encrypts a frame according to the shape.
+    pub fn pad_encrypt(&self, aead: &dyn Aead, frame: &DataFrame) -> bytes::Bytes {
+        let shape = self.shape.read().clone();
+        aead.pad_encrypt_with(frame, |len| {
+            let padded_len = shape.padded_len(len).max(len);
+            self.frame_bytes.fetch_add(len as u64, Ordering::Relaxed);
+            self.padding_bytes
+                .fetch_add((padded_len - len) as u64, Ordering::Relaxed);
+            padded_len
+        })
+    }
+
+    /// Bytes of padding sent per byte of frames.
+    pub fn padding_overhead(&self) -> f64 {
+        self.padding_bytes.load(Ordering::Relaxed) as f64
+            / self.frame_bytes.load(Ordering::Relaxed).max(1) as f64
+    }
+}
+
+/// A byte rate limit that can be changed while it's in use. Tokens accrue at the rate up to the burst, and taking more than there are runs into debt that later takers wait out, so limits hold however big the packets are.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    /// Bytes per second, or zero for no limit.
+    rate: u32,
+    burst: u32,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        TokenBucket {
+            state: Mutex::new(BucketState {
+                rate: 0,
+                burst: 0,
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl TokenBucket {
+    /// Sets the rate, in bytes per second, and the burst, in bytes. A rate of zero lifts the limit.
+    pub fn set_limit(&self, rate: u32, burst: u32) {
+        let mut state = self.state.lock();
+        if state.rate == 0 {
+            state.tokens = burst as f64;
+        }
+        state.rate = rate;
+        state.burst = burst;
+        state.tokens = state.tokens.min(burst as f64);
+        state.last_refill = Instant::now();
+    }
+
+    /// Takes tokens for the given number of bytes, returning how long to wait before sending them.
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock();
+        if state.rate == 0 {
+            return Duration::from_secs(0);
+        }
+        let now = Instant::now();
+        let refill = now
+            .saturating_duration_since(state.last_refill)
+            .as_secs_f64()
+            * state.rate as f64;
+        state.tokens = (state.tokens + refill).min(state.burst as f64) - bytes as f64;
+        state.last_refill = now;
+        if state.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-state.tokens / state.rate as f64)
+        }
+    }
+
+    /// Waits until the given number of bytes may be sent.
+    pub async fn wait(&self, bytes: usize) {
+        let delay = self.take(bytes);
+        if delay > Duration::from_secs(0) {
+            smol::Timer::after(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::{SimConfig, SimNetwork};
+
+    #[test]
+    fn histogram_pads_to_fitting_sizes() {
+        let shape = HistogramShape::video_call();
+        for len in (0..1300).step_by(13) {
+            let padded_len = shape.padded_len(len);
+            if len > 1200 {
+                assert_eq!(padded_len, len);
+            } else {
+                assert!(padded_len >= len);
+                assert!(shape.sizes.iter().any(|(size, _)| *size == padded_len));
+            }
+        }
+    }
+
+    #[test]
+    fn session_with_traffic_shape() {
+        smol::block_on(async {
+            let net = SimNetwork::new(SimConfig::default());
+            let (_listener, client, server) = sim_sessions(&net, Default::default()).await;
+            client.set_traffic_shape(HistogramShape::video_call());
+            server.set_traffic_shape(HistogramShape::video_call());
+            let _echo = echo(server);
+            let recent =
+                ping_pong(&client, Duration::from_millis(10), Duration::from_secs(2)).await;
+            assert!(recent > 50);
+            // small pings get padded up to audio or video packet sizes
+            let stats = client.get_stats().await.unwrap();
+            assert!(stats.up_padding > 0.5);
+        })
+    }
+}
@@ -0,0 +1,143 @@
+use crate::*;
+use bytes::Bytes;
+use smol::future::Boxed;
+use smol::prelude::*;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Where listeners sit on the simulated network.
+pub fn server_addr() -> SocketAddr {
+    "10.0.0.1:19831".parse().unwrap()
+}
+
+/// Makes backhauls for a client on the simulated network, with path `n` at 10.0.0.(2 + n). Every backhaul made is counted in `binds`.
+pub fn sim_backhauls(
+    net: &SimNetwork,
+    binds: Arc<AtomicUsize>,
+) -> impl Fn(u8) -> Boxed<std::io::Result<Arc<dyn Backhaul>>> + Send + Sync + 'static {
+    let net = net.clone();
+    move |path| {
+        binds.fetch_add(1, Ordering::Relaxed);
+        let backhaul = net.bind(SocketAddr::new([10, 0, 0, 2 + path].into(), 0));
+        async move {
+            let backhaul: Arc<dyn Backhaul> = Arc::new(backhaul?);
+            Ok(backhaul)
+        }
+        .boxed()
+    }
+}
+
+/// Connects a client to a listener over the simulated network. Returns the listener too, since dropping it kills the server session.
+pub async fn sim_sessions(
+    net: &SimNetwork,
+    binds: Arc<AtomicUsize>,
+) -> (Listener, Session, Session) {
+    sim_sessions_versioned(net, binds, PROTOCOL_VERSION, PROTOCOL_VERSION).await
+}
+
+/// Like `sim_sessions`, but with the client and the server capped at the given protocol versions.
+pub async fn sim_sessions_versioned(
+    net: &SimNetwork,
+    binds: Arc<AtomicUsize>,
+    client_version: u64,
+    server_version: u64,
+) -> (Listener, Session, Session) {
+    sim_sessions_with(
+        net,
+        binds,
+        client_version,
+        server_version,
+        ClientConfig::default(),
+    )
+    .await
+}
+
+/// Like `sim_sessions_versioned`, but with the client configured as given.
+pub async fn sim_sessions_with(
+    net: &SimNetwork,
+    binds: Arc<AtomicUsize>,
+    client_version: u64,
+    server_version: u64,
+    cfg: ClientConfig,
+) -> (Listener, Session, Session) {
+    let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+    let listener = Listener::listen_with_max_version(
+        net.bind(server_addr()).unwrap(),
+        long_sk.clone(),
+        server_version,
+    );
+    let client = client::connect_paths(
+        server_addr(),
+        (&long_sk).into(),
+        sim_backhauls(net, binds),
+        1,
+        client_version,
+        cfg,
+    )
+    .await
+    .unwrap();
+    // the server only learns about the session once data flows
+    client.send_bytes(Bytes::from_static(b"hello")).await;
+    let server = listener.accept_session().await.unwrap();
+    assert_eq!(
+        server.recv_bytes().await.unwrap(),
+        Bytes::from_static(b"hello")
+    );
+    (listener, client, server)
+}
+
+/// Echoes everything the session receives back to it.
+pub fn echo(session: Session) -> smol::Task<Option<()>> {
+    runtime::spawn(async move {
+        loop {
+            let pkt = session.recv_bytes().await?;
+            session.send_bytes(pkt).await;
+        }
+    })
+}
+
+/// Runs the future while echoing everything the session receives back to it, for when the session is still needed afterwards.
+pub async fn with_echoes<T>(session: &Session, fut: impl Future<Output = T>) -> T {
+    fut.or(async {
+        while let Some(pkt) = session.recv_bytes().await {
+            session.send_bytes(pkt).await;
+        }
+        smol::future::pending().await
+    })
+    .await
+}
+
+/// Sends a numbered packet every `interval` for `duration`, returning how many echoes came back during the last second.
+pub async fn ping_pong(client: &Session, interval: Duration, duration: Duration) -> usize {
+    let start = Instant::now();
+    let recent = AtomicUsize::new(0);
+    let send_loop = async {
+        while start.elapsed() < duration {
+            client.send_bytes(Bytes::from(vec![0u8; 500])).await;
+            smol::Timer::after(interval).await;
+        }
+        smol::Timer::after(Duration::from_millis(500)).await;
+    };
+    let recv_loop = async {
+        loop {
+            client.recv_bytes().await.unwrap();
+            if start.elapsed() + Duration::from_secs(1) > duration {
+                recent.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    };
+    send_loop.or(recv_loop).await;
+    recent.load(Ordering::Relaxed)
+}
+
+/// Waits for the future for up to `duration`, returning None if it takes longer.
+pub async fn timeout<T>(duration: Duration, fut: impl Future<Output = T>) -> Option<T> {
+    async { Some(fut.await) }
+        .or(async {
+            smol::Timer::after(duration).await;
+            None
+        })
+        .await
+}